@@ -12,7 +12,6 @@ extern crate timely;
 extern crate differential_dataflow;
 
 use differential_dataflow::operators::{Count, Distinct, Group, Iterate, JoinUnsigned};
-use differential_dataflow::input::Input;
 use topology_generator::Entity;
 
 // This is the naive implementation of connected components based on the example
@@ -21,9 +20,17 @@ use topology_generator::Entity;
 // https://github.com/frankmcsherry/blog/blob/master/posts/2015-12-24.md
 fn main() {
     strymon_runtime::query::execute(|root, coord| {
-        let (mut edges, mut nodes, probe) = root.dataflow(|scope| {
-            let (edge_input, edges) = scope.new_collection();
-            let (node_input, nodes) = scope.new_collection();
+        let source = root.dataflow::<u64, _, _>(|scope| {
+            // the topology is updated as the generator publishes changes
+            let (topology, source) = coord.subscribe_collection_stream::<_, Entity>(scope, "topology");
+            let edges = topology.flat_map(|entity| match entity {
+                Entity::Connection(c) => Some(c),
+                Entity::Switch(_) => None,
+            });
+            let nodes = topology.flat_map(|entity| match entity {
+                Entity::Switch(s) => Some(s),
+                Entity::Connection(_) => None,
+            });
 
             // (node, label)
             let labels = nodes.map(|x| (x, x));
@@ -44,7 +51,7 @@ fn main() {
 
             // count the number of connected components by counting the number of
             // distinct labels
-            let probe = labels.map(|(_node, label)| label)
+            labels.map(|(_node, label)| label)
                   .distinct_u()
                   .map(|_| ()) // put all labels into a single group for counting
                   .count()
@@ -56,28 +63,15 @@ fn main() {
                             println!("There are now {} disconnected partitions in the graph!", num_partitions);
                         }
                     }
-                  })
-                  .probe();
-
+                  });
 
-            (edge_input, node_input, probe)
+            source
         });
 
-        let topic = coord.subscribe_collection::<(Entity, i32)>("topology")
-            .expect("failed to subscribe to topology generator");
-        for (round, data) in topic.into_iter().enumerate() {
-            for (entity, delta) in data {
-                let diff = delta as isize;
-                match entity {
-                    Entity::Connection(c) => edges.update(c, diff),
-                    Entity::Switch(s)     => nodes.update(s, diff),
-                };
-            }
-            edges.advance_to(round + 1);
-            nodes.advance_to(round + 1);
-            edges.flush();
-            nodes.flush();
-            root.step_while(|| probe.less_than(edges.time()));
+        // the dataflow runs until the topology generator is done
+        while root.step() {}
+        if let Some(err) = source.take_error() {
+            eprintln!("Subscription to the topology failed: {:?}", err);
         }
     }).unwrap();
 }
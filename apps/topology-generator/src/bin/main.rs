@@ -149,7 +149,7 @@ fn main() {
         let (mut input, probe) = root.dataflow::<u64, _, _>(|scope| {
             let (input, stream) = scope.new_input();
 
-            let probe = coord.publish_collection("topology", &stream, Partition::Merge)
+            let probe = coord.publish_timestamped_collection("topology", &stream, Partition::Merge)
                 .expect("failed to publish topology updates").probe();
 
             (input, probe)
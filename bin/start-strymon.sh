@@ -25,7 +25,8 @@ start_coordinator() {
 
   spawn_service "coordinator" "${coord_host}" "${coord_basedir}" \
     "${coord_binary}" --log-level info manage start-coordinator \
-    --external-hostname "${coord_host}" --port "${coord_port}"
+    --external-hostname "${coord_host}" --port "${coord_port}" \
+    --catalog "${coord_basedir}/catalog.journal"
 }
 
 ## Starts an executor process
@@ -40,7 +41,8 @@ start_executor() {
   # TODO(swicki): This currently does not support the port range option
   spawn_service "executor" "${exec_host}" "${exec_basedir}" \
     "${coord_binary}" --log-level info \
-    manage start-executor --external-hostname "${exec_host}"
+    manage start-executor --external-hostname "${exec_host}" \
+    --log-dir "${exec_basedir}/queries"
 }
 
 #
@@ -59,6 +59,11 @@ impl MessageBuf {
         self.buf.is_empty()
     }
 
+    /// Returns the size of the encoded message in bytes.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
     /// Create a new message buffer containing the serialized object.
     pub fn new<S: Serialize>(item: S) -> io::Result<Self> {
         // we start with an empty buffer, because if the serialized element
@@ -7,8 +7,10 @@
 // except according to those terms.
 
 use std::io;
+use std::mem;
+use std::collections::VecDeque;
 use std::net::{TcpListener, TcpStream, Shutdown, ToSocketAddrs};
-use std::sync::{mpsc, Arc};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{self, JoinHandle};
 
 use futures::{Future, Poll, Async};
@@ -43,19 +45,122 @@ fn channel(stream: TcpStream) -> io::Result<(Sender, Receiver)> {
     Ok((sender, receiver))
 }
 
+/// Decides what happens to messages sent while the send queue is full.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Blocks the sending thread until the queue has room again. A publisher
+    /// sending from within a dataflow operator thus stalls the whole timely
+    /// worker thread, including all of its other operators.
+    Block,
+    /// Drops the oldest queued message which is not `Delivery::Reliable`,
+    /// see `Sender::take_dropped`. If all queued messages are reliable, the
+    /// queue grows beyond its capacity instead.
+    DropOldest,
+    /// Discards all queued messages and closes the connection
+    Disconnect,
+}
+
+/// Decides whether `Overflow::DropOldest` may drop a queued message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Never dropped, e.g. the message closing a stream
+    Reliable,
+    /// Dropped if the queue is full, which is counted by `take_dropped`
+    Droppable,
+    /// Dropped if the queue is full without being counted, used for messages
+    /// whose content is repeated by any later message, e.g. a frontier
+    Transient,
+}
+
+struct Queue {
+    messages: VecDeque<(MessageBuf, Delivery)>,
+    /// Maximum number of queued messages, unbounded if not set
+    bound: Option<(usize, Overflow)>,
+    /// Number of messages dropped since the last call to `take_dropped`
+    dropped: usize,
+    closed: bool,
+}
+
+impl Queue {
+    /// Drops the oldest message which is not reliable, returns false if
+    /// there is no such message.
+    fn drop_oldest(&mut self) -> bool {
+        let oldest = self.messages
+            .iter()
+            .position(|&(_, delivery)| delivery != Delivery::Reliable);
+        let pos = match oldest {
+            Some(pos) => pos,
+            None => return false,
+        };
+
+        if let Some((_, Delivery::Droppable)) = self.messages.remove(pos) {
+            self.dropped += 1;
+        }
+        true
+    }
+}
+
+/// The send queue shared between all clones of a `Sender` and its writer
+/// thread.
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Notified whenever a message is queued or dequeued, or the queue is closed
+    changed: Condvar,
+}
+
+impl Shared {
+    fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        queue.messages.clear();
+        self.changed.notify_all();
+    }
+}
+
 #[derive(Clone)]
 pub struct Sender {
-    tx: Option<mpsc::Sender<MessageBuf>>,
+    shared: Arc<Shared>,
     thr: Arc<Option<JoinHandle<()>>>,
 }
 
 impl Sender {
     pub(crate) fn new(mut outstream: TcpStream) -> Self {
-        let (sender_tx, sender_rx) = mpsc::channel::<MessageBuf>();
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                messages: VecDeque::new(),
+                bound: None,
+                dropped: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let writer = shared.clone();
         let thr = thread::spawn(move || {
-            while let Ok(msg) = sender_rx.recv() {
+            loop {
+                let msg = {
+                    let mut queue = writer.queue.lock().unwrap();
+                    loop {
+                        if let Some((msg, _)) = queue.messages.pop_front() {
+                            break Some(msg);
+                        } else if queue.closed {
+                            break None;
+                        }
+                        queue = writer.changed.wait(queue).unwrap();
+                    }
+                };
+
+                // wake up senders waiting for room in the queue
+                writer.changed.notify_all();
+
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => break,
+                };
+
                 if let Err(err) = msg.write(&mut outstream) {
                     info!("unexpected error while writing bytes: {:?}", err);
+                    writer.close();
                     break;
                 }
             }
@@ -64,21 +169,82 @@ impl Sender {
         });
 
         Sender {
-            tx: Some(sender_tx),
+            shared: shared,
             thr: Arc::new(Some(thr)),
         }
     }
 
+    /// Queues a message to be sent. Messages sent after the connection has
+    /// been closed are silently discarded. The message is never dropped by
+    /// `Overflow::DropOldest`, see `send_with`.
     pub fn send<T: Into<MessageBuf>>(&self, msg: T) {
-        drop(self.tx.as_ref().unwrap().send(msg.into()));
+        self.send_with(msg, Delivery::Reliable)
+    }
+
+    /// Queues a message to be sent, `delivery` decides if it may be dropped
+    /// once the queue is full.
+    pub fn send_with<T: Into<MessageBuf>>(&self, msg: T, delivery: Delivery) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if let Some((capacity, overflow)) = queue.bound {
+            while !queue.closed && queue.messages.len() >= capacity {
+                match overflow {
+                    Overflow::Block => {
+                        queue = self.shared.changed.wait(queue).unwrap();
+                    }
+                    Overflow::DropOldest => {
+                        if !queue.drop_oldest() {
+                            break;
+                        }
+                    }
+                    Overflow::Disconnect => {
+                        queue.closed = true;
+                        queue.messages.clear();
+                    }
+                }
+            }
+        }
+
+        if !queue.closed {
+            queue.messages.push_back((msg.into(), delivery));
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// Limits the number of queued messages to `capacity`, `overflow`
+    /// decides what happens to messages sent to a full queue.
+    pub fn bound(&self, capacity: usize, overflow: Overflow) {
+        assert!(capacity > 0, "send queue capacity must be positive");
+        self.shared.queue.lock().unwrap().bound = Some((capacity, overflow));
+    }
+
+    /// Returns the number of messages waiting to be sent.
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().unwrap().messages.len()
+    }
+
+    /// Returns the number of messages dropped due to a full queue since the
+    /// last call of this method.
+    pub fn take_dropped(&self) -> usize {
+        let mut queue = self.shared.queue.lock().unwrap();
+        mem::replace(&mut queue.dropped, 0)
+    }
+
+    /// Returns true if the connection has been closed, either because of an
+    /// error or because the queue overflowed.
+    pub fn is_closed(&self) -> bool {
+        self.shared.queue.lock().unwrap().closed
     }
 }
 
 impl Drop for Sender {
     fn drop(&mut self) {
         // make sure to drain the queue if the other side is still connected
-        drop(self.tx.take());
         if let Some(handle) = Arc::get_mut(&mut self.thr).and_then(Option::take) {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                queue.closed = true;
+                self.shared.changed.notify_all();
+            }
             drop(handle.join());
         }
     }
@@ -212,10 +378,12 @@ fn _assert() {
 #[cfg(test)]
 mod tests {
 
+    use std::collections::VecDeque;
     use futures::stream::Stream;
     use message::MessageBuf;
     use Network;
     use std::io::Result;
+    use super::{Delivery, Queue};
 
     fn assert_io<F: FnOnce() -> Result<()>>(f: F) {
         f().expect("I/O test failed")
@@ -252,4 +420,37 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    fn drop_oldest_keeps_reliable() {
+        let message = |id: u32| {
+            let mut buf = MessageBuf::empty();
+            buf.push(id).unwrap();
+            buf
+        };
+
+        let mut queue = Queue {
+            messages: VecDeque::new(),
+            bound: None,
+            dropped: 0,
+            closed: false,
+        };
+        queue.messages.push_back((message(0), Delivery::Reliable));
+        queue.messages.push_back((message(1), Delivery::Transient));
+        queue.messages.push_back((message(2), Delivery::Droppable));
+        queue.messages.push_back((message(3), Delivery::Reliable));
+
+        // outdated frontiers are dropped without being counted
+        assert!(queue.drop_oldest());
+        assert_eq!(queue.dropped, 0);
+        assert!(queue.drop_oldest());
+        assert_eq!(queue.dropped, 1);
+        assert!(!queue.drop_oldest());
+
+        let remaining: Vec<u32> = queue.messages
+            .iter_mut()
+            .map(|&mut (ref mut msg, _)| msg.pop().unwrap())
+            .collect();
+        assert_eq!(remaining, vec![0, 3]);
+    }
 }
@@ -0,0 +1,112 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use strymon_communication::Network;
+use strymon_runtime::submit::Submitter;
+use strymon_runtime::model::{QueryId, QueryStatus, LogStream};
+
+use errors::*;
+
+/// Delay between two polls when following the output of a query
+const FOLLOW_INTERVAL_MS: u64 = 1000;
+
+pub fn usage<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("logs")
+        .about("Prints the output of a Strymon application")
+        .arg(Arg::with_name("query-id")
+            .required(true)
+            .value_name("QUERY_ID")
+            .help("Id of the query whose output is printed"))
+        .arg(Arg::with_name("follow")
+            .short("f")
+            .long("follow")
+            .help("Keep printing new output until the query exits"))
+        .arg(Arg::with_name("coordinator")
+            .short("c")
+            .long("coordinator")
+            .value_name("ADDR")
+            .help("Address of the coordinator")
+            .takes_value(true))
+}
+
+fn has_exited(submitter: &Submitter, id: QueryId) -> Result<bool> {
+    let queries = submitter.queries()?;
+    let status = queries.iter().find(|q| q.id == id).map(|q| &q.status);
+    Ok(match status {
+        Some(&QueryStatus::Spawning) | Some(&QueryStatus::Running) => false,
+        _ => true,
+    })
+}
+
+pub fn main(args: &ArgMatches) -> Result<()> {
+    let id = args.value_of("query-id").expect("missing query id")
+        .parse::<u64>()
+        .chain_err(|| "Failed to parse query id")?;
+    let id = QueryId(id);
+    let follow = args.is_present("follow");
+
+    let network = Network::init()?;
+    let coord = args.value_of("coordinator").unwrap_or("localhost:9189");
+    let submitter = Submitter::new(&network, &*coord)
+        .chain_err(|| "Unable to connect to coordinator")?;
+
+    let mut offsets: Vec<u64> = Vec::new();
+    // processes whose log has been reported as missing
+    let mut missing = HashSet::new();
+    loop {
+        // check for termination before fetching, so no output is lost
+        let exited = !follow || has_exited(&submitter, id)?;
+
+        let chunks = submitter.logs(id, offsets.clone())
+            .wait_unwrap()
+            .map_err(|e| format!("Failed to fetch logs: {:?}", e))?;
+
+        let mut empty = true;
+        for chunk in chunks {
+            if offsets.len() <= chunk.process {
+                offsets.resize(chunk.process + 1, 0);
+            }
+            offsets[chunk.process] = chunk.offset;
+
+            match chunk.missing {
+                Some(reason) => {
+                    if missing.insert(chunk.process) {
+                        eprintln!("[{}] Log is unavailable: {:?}", chunk.process, reason);
+                    }
+                }
+                None => {
+                    missing.remove(&chunk.process);
+                }
+            }
+
+            for line in chunk.lines {
+                empty = false;
+                let stream = match line.stream {
+                    LogStream::Stdout => "out",
+                    LogStream::Stderr => "err",
+                };
+                println!("[{}:{}] {}", chunk.process, stream, line.line);
+            }
+        }
+
+        if empty {
+            if exited {
+                break;
+            }
+            thread::sleep(Duration::from_millis(FOLLOW_INTERVAL_MS));
+        }
+    }
+
+    Ok(())
+}
@@ -21,6 +21,8 @@ extern crate strymon_communication;
 mod errors;
 mod status;
 mod submit;
+mod terminate;
+mod logs;
 mod manage;
 
 use std::env;
@@ -39,6 +41,8 @@ fn dispatch() -> Result<()> {
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .subcommand(status::usage())
         .subcommand(submit::usage())
+        .subcommand(terminate::usage())
+        .subcommand(logs::usage())
         .subcommand(manage::usage())
         .arg(Arg::with_name("log-level")
             .short("l")
@@ -61,6 +65,8 @@ fn dispatch() -> Result<()> {
     match matches.subcommand() {
         ("status", Some(args)) => status::main(args),
         ("submit", Some(args)) => submit::main(args),
+        ("terminate", Some(args)) => terminate::main(args),
+        ("logs", Some(args)) => logs::main(args),
         ("manage", Some(args)) => manage::main(args),
         _ => unreachable!("invalid subcommand"),
     }
@@ -6,6 +6,9 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::path::PathBuf;
+use std::time::Duration;
+
 use clap::{App, Arg, ArgMatches, SubCommand};
 
 use strymon_runtime::coordinator;
@@ -30,6 +33,36 @@ pub mod start {
                 .value_name("HOST")
                 .help("Externally reachable hostname of the spawned coordinator")
                 .takes_value(true))
+            .arg(Arg::with_name("catalog")
+                .long("catalog")
+                .value_name("FILE")
+                .help("Persist the catalog in FILE and recover it on restart")
+                .takes_value(true))
+            .arg(Arg::with_name("spawn-timeout")
+                .long("spawn-timeout")
+                .value_name("SECS")
+                .help("Abort submissions which have not started within SECS seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("heartbeat-interval")
+                .long("heartbeat-interval")
+                .value_name("SECS")
+                .help("Interval at which executors are checked for liveness")
+                .takes_value(true))
+            .arg(Arg::with_name("max-missed-heartbeats")
+                .long("max-missed-heartbeats")
+                .value_name("NUM")
+                .help("Evict executors which missed NUM consecutive heartbeats")
+                .takes_value(true))
+            .arg(Arg::with_name("query-retention")
+                .long("query-retention")
+                .value_name("NUM")
+                .help("Number of finished or failed queries kept in the catalog")
+                .takes_value(true))
+            .arg(Arg::with_name("recovery-grace")
+                .long("recovery-grace")
+                .value_name("SECS")
+                .help("Terminate recovered queries still running after SECS seconds")
+                .takes_value(true))
     }
 
     pub fn main(args: &ArgMatches) -> Result<()> {
@@ -46,6 +79,47 @@ pub mod start {
             coordinator.host(host.to_owned());
         }
 
+        if let Some(secs) = args.value_of("spawn-timeout") {
+            let parsed = secs.parse::<u64>()
+                .chain_err(|| "unable to parse spawn timeout")?;
+            coordinator.spawn_timeout(Duration::from_secs(parsed));
+        }
+
+        if let Some(secs) = args.value_of("heartbeat-interval") {
+            let parsed = secs.parse::<u64>()
+                .chain_err(|| "unable to parse heartbeat interval")?;
+            if parsed == 0 {
+                bail!("heartbeat interval must be at least one second");
+            }
+            coordinator.heartbeat_interval(Duration::from_secs(parsed));
+        }
+
+        if let Some(num) = args.value_of("max-missed-heartbeats") {
+            let parsed = num.parse::<u32>()
+                .chain_err(|| "unable to parse number of heartbeats")?;
+            if parsed == 0 {
+                bail!("number of missed heartbeats must be at least one");
+            }
+            coordinator.max_missed_heartbeats(parsed);
+        }
+
+        if let Some(num) = args.value_of("query-retention") {
+            let parsed = num.parse::<usize>()
+                .chain_err(|| "unable to parse number of retained queries")?;
+            coordinator.query_retention(parsed);
+        }
+
+        if let Some(secs) = args.value_of("recovery-grace") {
+            let parsed = secs.parse::<u64>()
+                .chain_err(|| "unable to parse recovery grace period")?;
+            coordinator.recovery_grace(Duration::from_secs(parsed));
+        }
+
+        // write-ahead log for crash recovery of the catalog
+        if let Some(path) = args.value_of("catalog") {
+            coordinator.catalog(PathBuf::from(path));
+        }
+
         coordinator.run().chain_err(|| "Failed to run coordinator")
     }
 }
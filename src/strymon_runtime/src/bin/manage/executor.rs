@@ -6,6 +6,9 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::path::PathBuf;
+use std::time::Duration;
+
 use clap::{App, Arg, ArgMatches, SubCommand};
 
 use strymon_runtime::executor;
@@ -36,6 +39,33 @@ pub mod start {
                 .value_name("ADDR")
                 .help("Address of the coordinator")
                 .takes_value(true))
+            .arg(Arg::with_name("cores")
+                .long("cores")
+                .value_name("NUM")
+                .help("Number of cores available for spawned workers")
+                .takes_value(true))
+            .arg(Arg::with_name("memory")
+                .long("memory")
+                .value_name("MB")
+                .help("Amount of memory available for spawned workers")
+                .takes_value(true))
+            .arg(Arg::with_name("label")
+                .long("label")
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .require_delimiter(true)
+                .help("Comma-separated labels used for constraint-based placement")
+                .takes_value(true))
+            .arg(Arg::with_name("log-dir")
+                .long("log-dir")
+                .value_name("DIR")
+                .help("Directory in which the output of spawned queries is stored")
+                .takes_value(true))
+            .arg(Arg::with_name("log-retention")
+                .long("log-retention")
+                .value_name("HOURS")
+                .help("Remove query logs which have not been written to for HOURS")
+                .takes_value(true))
     }
 
     pub fn main(args: &ArgMatches) -> Result<()> {
@@ -63,6 +93,41 @@ pub mod start {
             executor.ports(min.unwrap(), max.unwrap());
         }
 
+        // resources advertised to the coordinator
+        if let Some(cores) = args.value_of("cores") {
+            let cores = cores.parse::<usize>()
+                .chain_err(|| format!("Invalid number of cores: {}", cores))?;
+            executor.cores(cores);
+        }
+
+        if let Some(memory) = args.value_of("memory") {
+            let memory = memory.parse::<u64>()
+                .chain_err(|| format!("Invalid amount of memory: {}", memory))?;
+            executor.memory(memory);
+        }
+
+        if let Some(labels) = args.values_of("label") {
+            for label in labels {
+                let mut split = label.splitn(2, '=');
+                match (split.next(), split.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() => {
+                        executor.label(key.to_owned(), value.to_owned());
+                    }
+                    _ => bail!("Invalid label: {}", label),
+                }
+            }
+        }
+
+        if let Some(dir) = args.value_of("log-dir") {
+            executor.log_dir(PathBuf::from(dir));
+        }
+
+        if let Some(hours) = args.value_of("log-retention") {
+            let hours = hours.parse::<u64>()
+                .chain_err(|| format!("Invalid log retention: {}", hours))?;
+            executor.log_retention(Duration::from_secs(hours * 60 * 60));
+        }
+
         executor.start().chain_err(|| "Failed to start executor")
     }
 }
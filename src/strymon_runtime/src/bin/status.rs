@@ -43,7 +43,20 @@ pub fn main(args: &ArgMatches) -> Result<()> {
     println!("Coordinator: {}", coord);
     for executor in executors {
         let id = executor.id.0;
-        println!(" Executor {}: host={:?}", id, executor.host);
+        println!(" Executor {}: host={:?}, health={:?}, last_seen={}, cores={}, memory={}MB",
+                 id,
+                 executor.host,
+                 executor.health,
+                 executor.last_seen,
+                 executor.capacity.cores,
+                 executor.capacity.memory);
+        if !executor.labels.is_empty() {
+            let labels: Vec<String> = executor.labels
+                .iter()
+                .map(|&(ref k, ref v)| format!("{}={}", k, v))
+                .collect();
+            println!("  Labels: {}", labels.join(","));
+        }
         for query in queries.iter().filter(|q| q.executors.contains(&executor.id)) {
             let id = query.id.0;
             let name = query.name
@@ -51,14 +64,18 @@ pub fn main(args: &ArgMatches) -> Result<()> {
                 .map(|n| format!("{:?}", n))
                 .unwrap_or_else(|| String::from("<unnamed>"));
 
-            println!("  Query {}: name={}, workers={}", id, name, query.workers);
+            println!("  Query {}: name={}, workers={}, status={:?}",
+                     id, name, query.workers, query.status);
 
             for publication in publications.iter().filter(|p| p.0 == query.id) {
                 let topic = &topics[&publication.1];
-                println!("   Publication on Topic {}: name={:?}, schema={}",
+                println!("   Publication on Topic {}: name={:?}, schema={}, compatibility={:?}, \
+                          partitions={}",
                          topic.id.0,
                          topic.name,
-                         topic.schema);
+                         topic.schema,
+                         topic.compatibility,
+                         topic.partitions.len());
             }
 
             for subscription in subscriptions.iter().filter(|p| p.0 == query.id) {
@@ -129,6 +129,13 @@ fn parse_placement(args: &ArgMatches, executors: Vec<Executor>) -> Result<Placem
         1
     };
 
+    // label constraints are only honored by the `constrained` strategy
+    if let Some(strategy) = args.value_of("placement-strategy") {
+        if strategy != "constrained" && args.is_present("constraint") {
+            bail!("The `{}` placement strategy does not support `--constraint`", strategy);
+        }
+    }
+
     // parse placement strategy and its arguments
     match args.value_of("placement-strategy") {
         Some("random") => {
@@ -138,6 +145,19 @@ fn parse_placement(args: &ArgMatches, executors: Vec<Executor>) -> Result<Placem
                 .parse().chain_err(|| parse_err("num-executors"))?;
             Ok(Placement::Random(num_executors, workers))
         },
+        Some(strategy @ "least-loaded") |
+        Some(strategy @ "spread") |
+        Some(strategy @ "pack") => {
+            let num_executors = match args.value_of("num-executors") {
+                Some(num) => num.parse().chain_err(|| parse_err("num-executors"))?,
+                None => 1,
+            };
+            Ok(match strategy {
+                "least-loaded" => Placement::LeastLoaded(num_executors, workers),
+                "spread" => Placement::Spread(num_executors, workers),
+                _ => Placement::Pack(num_executors, workers),
+            })
+        },
         Some("pinned") => {
             let mut pinned = vec![];
             if let Some(ids) = args.values_of("pinned-id") {
@@ -145,7 +165,8 @@ fn parse_placement(args: &ArgMatches, executors: Vec<Executor>) -> Result<Placem
                     let id = num.parse::<u64>().chain_err(|| parse_err("pinned-id"))?;
                     pinned.push(ExecutorId(id));
                 }
-            } if let Some(hosts) = args.values_of("pinned-host") {
+            }
+            if let Some(hosts) = args.values_of("pinned-host") {
                 for name in hosts {
                     let executor = executors.iter().find(|e| e.host == name);
                     if let Some(executor) = executor {
@@ -154,10 +175,30 @@ fn parse_placement(args: &ArgMatches, executors: Vec<Executor>) -> Result<Placem
                         bail!("Unknown executor host '{}'", name);
                     }
                 }
-                Ok(Placement::Fixed(pinned, workers))
-            } else {
+            }
+            if pinned.is_empty() {
                 bail!("Missing executors list for pinning")
             }
+
+            Ok(Placement::Fixed(pinned, workers))
+        },
+        Some("constrained") | None if args.is_present("constraint") => {
+            let mut selectors = vec![];
+            for constraint in args.values_of("constraint").expect("missing constraints") {
+                let mut split = constraint.splitn(2, '=');
+                match (split.next(), split.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() => {
+                        selectors.push((key.to_owned(), value.to_owned()));
+                    }
+                    _ => bail!("Invalid constraint '{}', expected KEY=VALUE", constraint),
+                }
+            }
+
+            let num_executors = match args.value_of("num-executors") {
+                Some(num) => num.parse().chain_err(|| parse_err("num-executors"))?,
+                None => 1,
+            };
+            Ok(Placement::Constraint(selectors, num_executors, workers))
         },
         _ => {
             // by default choose a random executor
@@ -232,6 +273,17 @@ randomly selected executor. This is equivalent to `--placement-strategy random \
 select executors based on their executor id, or use `--pinned-host host1,host2,host3` \
 to specify them by hostname.
 
+The load-aware strategies `least-loaded`, `spread` and `pack` take into account \
+the workers already running on each executor and skip executors whose advertised \
+cores or memory are exhausted, assuming every worker takes 256 MB. `least-loaded` \
+picks the executors with the fewest workers, `spread` distributes the job across \
+as many hosts as possible, and `pack` places it onto as few hosts as possible. \
+All of them accept `--num-executors`.
+
+Executors can be selected by their labels using `--constraint rack=a,ssd=true`, \
+in which case the job is placed on randomly chosen executors matching all \
+of the given labels. This implies `--placement-strategy constrained`.
+
 The number of worker threads per executors (default 1) can set using the \
 `--workers` option. The optional job name is given through the `--description` \
 option.
@@ -320,9 +372,10 @@ pub fn usage<'a, 'b>() -> App<'a, 'b> {
                 .long("placement-strategy")
                 .takes_value(true)
                 .value_name("STRATEGY")
-                .possible_values(&["pinned", "random"])
+                .possible_values(&["pinned", "random", "least-loaded", "spread", "pack", "constrained"])
                 .requires_if("pinned", "pinned-group")
                 .requires_if("random", "random-group")
+                .requires_if("constrained", "constraint")
                 .display_order(402)
                 .help("Job placement strategy"))
         .arg(Arg::with_name("pinned-id")
@@ -348,10 +401,19 @@ pub fn usage<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .value_name("NUM")
                 .display_order(405)
-                .help("Number of executors for the `random` placement strategy"))
+                .help("Number of executors for the `random` and load-aware placement strategies"))
+        .arg(Arg::with_name("constraint")
+                .long("constraint")
+                .takes_value(true)
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .require_delimiter(true)
+                .conflicts_with("pinned-group")
+                .display_order(406)
+                .help("Comma-separated executor labels required by the `constrained` placement strategy"))
         .arg(Arg::with_name("no-upload")
                 .long("no-upload")
-                .display_order(406)
+                .display_order(407)
                 .help("Let the executors read the binary from their local filesystem"))
         // catch-all args after --
         .arg(Arg::with_name("args")
@@ -0,0 +1,49 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use strymon_communication::Network;
+use strymon_runtime::submit::Submitter;
+use strymon_runtime::model::QueryId;
+
+use errors::*;
+
+pub fn usage<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("terminate")
+        .about("Terminates a running Strymon application, or removes a completed one")
+        .arg(Arg::with_name("query-id")
+            .required(true)
+            .value_name("QUERY_ID")
+            .help("Id of the query to terminate"))
+        .arg(Arg::with_name("coordinator")
+            .short("c")
+            .long("coordinator")
+            .value_name("ADDR")
+            .help("Address of the coordinator")
+            .takes_value(true))
+}
+
+pub fn main(args: &ArgMatches) -> Result<()> {
+    let id = args.value_of("query-id").expect("missing query id")
+        .parse::<u64>()
+        .chain_err(|| "Failed to parse query id")?;
+
+    let network = Network::init()?;
+    let coord = args.value_of("coordinator").unwrap_or("localhost:9189");
+    let submitter = Submitter::new(&network, &*coord)
+        .chain_err(|| "Unable to connect to coordinator")?;
+
+    submitter.terminate(QueryId(id))
+        .wait_unwrap()
+        .map_err(|e| format!("Failed to terminate job: {:?}", e))?;
+
+    println!("Successfully terminated job: {}", id);
+
+    Ok(())
+}
@@ -7,25 +7,48 @@
 // except according to those terms.
 
 use std::io;
+use std::mem;
 use std::collections::hash_map::{HashMap, Entry as HashEntry};
 use std::collections::btree_map::{BTreeMap, Values};
 use std::hash::Hash;
+use std::iter::repeat;
+use std::path::Path;
 
 use futures::Future;
 use tokio_core::reactor::Handle;
 use strymon_communication::Network;
 use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
 
 use model::*;
 use coordinator::requests::*;
 
 use pubsub::publisher::collection::{CollectionPublisher, Mutator};
 
+use super::journal::{Journal, Event};
 use super::util::Generator;
 
+/// Ports and number of workers of every worker group of a query, in the
+/// order of its processes. Kept to restore port allocations and to let worker
+/// groups reattach after a restart.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Allocation {
+    pub ports: Vec<(ExecutorId, u16)>,
+    pub threads: Vec<usize>,
+    /// Secret of the token handed out to the worker groups of the query
+    pub auth: u64,
+}
+
 pub struct Catalog {
     generator: Generator<TopicId>,
     directory: HashMap<String, TopicId>,
+    /// Partition secrets of published topics, not visible in `$topics`
+    secrets: HashMap<TopicId, Vec<u64>>,
+    /// ACL of every name published so far, restricting who may publish it
+    /// again after it has been unpublished
+    acls: HashMap<String, Acl>,
+    /// Port allocations of queries whose worker groups are still alive
+    allocations: BTreeMap<QueryId, Allocation>,
 
     topics: MapCollection<TopicId, Topic>,
     executors: MapCollection<ExecutorId, Executor>,
@@ -35,6 +58,8 @@ pub struct Catalog {
     subscriptions: Collection<Subscription>,
 
     keepers: MapCollection<KeeperId, Keeper>,
+
+    journal: Option<Journal>,
 }
 
 impl Catalog {
@@ -76,67 +101,261 @@ impl Catalog {
         Ok(Catalog {
                generator: generator,
                directory: directory,
+               secrets: HashMap::new(),
+               acls: HashMap::new(),
+               allocations: BTreeMap::new(),
                topics: topics,
                executors: executors,
                queries: queries,
                publications: pubs,
                subscriptions: subs,
                keepers: keepers,
+               journal: None,
            })
     }
 
+    /// Creates a new catalog backed by the write-ahead log at `path`. Any
+    /// events already recorded in the log are replayed, so a restarted
+    /// coordinator ends up with the same catalog state it had before.
+    pub fn recover<P: AsRef<Path>>(network: &Network,
+                                   handle: &Handle,
+                                   path: P)
+                                   -> io::Result<Self> {
+        let mut catalog = Catalog::new(network, handle)?;
+        let (mut journal, events) = Journal::open(path)?;
+
+        if !events.is_empty() {
+            info!("recovering catalog from {} journal entries", events.len());
+        }
+
+        // the journal is not attached yet, so replaying will not re-append
+        for event in events {
+            catalog.apply(event);
+        }
+
+        journal.compact(&catalog.snapshot())?;
+        catalog.journal = Some(journal);
+
+        Ok(catalog)
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::AddExecutor(executor) => self.add_executor(executor),
+            Event::RemoveExecutor(id) => self.remove_executor(id),
+            Event::AddQuery(query) => {
+                // queries are updated by re-adding them
+                self.queries.remove(&query.id);
+                self.add_query(query);
+            }
+            Event::RemoveQuery(id) => self.remove_query(id),
+            Event::Allocate(id, allocation) => self.allocate(id, allocation),
+            Event::Release(id) => self.release(id),
+            Event::Publish(query, topic) => {
+                self.generator.advance_past(topic.id.0);
+                self.insert_topic(query, topic);
+            }
+            Event::SetAcl(name, acl) => self.set_acl(name, acl),
+            Event::Unpublish(query, topic) => {
+                let _ = self.unpublish(query, topic);
+            }
+            Event::Subscribe(query, topic) => self.subscribe(query, topic),
+            Event::Unsubscribe(query, topic) => {
+                let _ = self.unsubscribe(query, topic);
+            }
+            Event::AddKeeper(keeper) => {
+                // keepers are updated by removing and re-adding them
+                self.keepers.remove(&keeper.id);
+                self.add_keeper(keeper);
+            }
+            Event::RemoveKeeper(id) => {
+                self.remove_keeper(&id);
+            }
+        }
+    }
+
+    /// Returns the minimal list of events needed to rebuild the current state.
+    fn snapshot(&self) -> Vec<Event> {
+        let mut events = Vec::new();
+        events.extend(self.executors.values().cloned().map(Event::AddExecutor));
+        events.extend(self.queries.values().cloned().map(Event::AddQuery));
+        for (&id, allocation) in self.allocations.iter() {
+            events.push(Event::Allocate(id, allocation.clone()));
+        }
+        for (name, acl) in self.acls.iter() {
+            events.push(Event::SetAcl(name.clone(), acl.clone()));
+        }
+        for &Publication(query, topic) in self.publications.inner.keys() {
+            if let Some(topic) = self.topics.get(&topic) {
+                events.push(Event::Publish(query, self.with_secrets(topic.clone())));
+            }
+        }
+        for (&Subscription(query, topic), &count) in self.subscriptions.inner.iter() {
+            events.extend(repeat(Event::Subscribe(query, topic)).take(count));
+        }
+        events.extend(self.keepers.values().cloned().map(Event::AddKeeper));
+        events
+    }
+
+    fn record(&mut self, event: Event) {
+        if let Some(ref mut journal) = self.journal {
+            if let Err(err) = journal.append(&event) {
+                error!("failed to append to catalog journal: {}", err);
+            }
+        }
+    }
+
     pub fn add_executor(&mut self, executor: Executor) {
         debug!("add_executor: {:?}", executor);
+        self.record(Event::AddExecutor(executor.clone()));
         self.executors.insert(executor.id, executor);
     }
 
     pub fn remove_executor(&mut self, id: ExecutorId) {
         debug!("remove_executor: {:?}", id);
+        self.record(Event::RemoveExecutor(id));
         self.executors.remove(&id);
     }
 
+    /// Updates the transient state of an executor, this is not journaled.
+    /// Subscribers are only notified if the executor actually changed.
+    pub fn update_executor(&mut self, executor: Executor) {
+        if self.executors.get(&executor.id) == Some(&executor) {
+            return;
+        }
+        self.executors.remove(&executor.id);
+        self.executors.insert(executor.id, executor);
+    }
+
+    pub fn executor(&self, id: ExecutorId) -> Option<&Executor> {
+        self.executors.get(&id)
+    }
+
     pub fn executors<'a>(&'a self) -> Executors<'a> {
         Executors { inner: self.executors.values() }
     }
 
+    pub fn query(&self, id: QueryId) -> Option<&Query> {
+        self.queries.get(&id)
+    }
+
+    pub fn queries<'a>(&'a self) -> Queries<'a> {
+        Queries { inner: self.queries.values() }
+    }
+
+    pub fn keepers<'a>(&'a self) -> Keepers<'a> {
+        Keepers { inner: self.keepers.values() }
+    }
+
     pub fn add_query(&mut self, query: Query) {
         debug!("add_query: {:?}", query);
+        self.record(Event::AddQuery(query.clone()));
+        self.queries.insert(query.id, query);
+    }
+
+    pub fn update_query(&mut self, query: Query) {
+        debug!("update_query: {:?}", query);
+        self.record(Event::AddQuery(query.clone()));
+        self.queries.remove(&query.id);
         self.queries.insert(query.id, query);
     }
 
     pub fn remove_query(&mut self, id: QueryId) {
         debug!("remove_query: {:?}", id);
+        self.record(Event::RemoveQuery(id));
         self.queries.remove(&id);
+        self.allocations.remove(&id);
+    }
+
+    pub fn allocate(&mut self, id: QueryId, allocation: Allocation) {
+        debug!("allocate: {:?} {:?}", id, allocation);
+        self.record(Event::Allocate(id, allocation.clone()));
+        self.allocations.insert(id, allocation);
+    }
+
+    /// Forgets the port allocation of a query once its worker groups are gone.
+    pub fn release(&mut self, id: QueryId) {
+        if self.allocations.contains_key(&id) {
+            debug!("release: {:?}", id);
+            self.record(Event::Release(id));
+            self.allocations.remove(&id);
+        }
+    }
+
+    pub fn allocation(&self, id: QueryId) -> Option<&Allocation> {
+        self.allocations.get(&id)
     }
 
     pub fn publish(&mut self,
                    query: QueryId,
                    name: String,
-                   addr: (String, u16),
-                   schema: TopicSchema)
+                   partitions: Vec<(String, u16)>,
+                   secrets: Vec<u64>,
+                   schema: TopicSchema,
+                   compatibility: Compatibility,
+                   acl: Acl)
                    -> Result<Topic, PublishError> {
         // TODO(swicki): Check if query actually exists
-        match self.directory.entry(name.clone()) {
-            HashEntry::Occupied(_) => Err(PublishError::TopicAlreadyExists),
-            HashEntry::Vacant(entry) => {
-                let id = self.generator.generate();
-                let publication = Publication(query, id);
-                let topic = Topic {
-                    id: id,
-                    name: name,
-                    addr: addr,
-                    schema: schema,
-                };
-
-                debug!("publish: {:?}", publication);
-
-                self.topics.insert(id, topic.clone());
-                self.publications.insert(publication);
-                entry.insert(id);
-
-                Ok(topic)
+        if self.directory.contains_key(&name) {
+            return Err(PublishError::TopicAlreadyExists);
+        }
+
+        // the publisher must be granted both by the previous ACL of the name,
+        // and by the new one, so that nobody can lock out the others
+        let (permitted, changed) = {
+            let publisher = self.queries.get(&query);
+            match self.acls.get(&name) {
+                Some(previous) => {
+                    (previous.may_publish(publisher) && acl.may_publish(publisher),
+                     *previous != acl)
+                }
+                None => (acl.may_publish(publisher), true),
             }
+        };
+        if !permitted {
+            return Err(PublishError::PermissionDenied);
+        }
+
+        // subscribers rely on the schema being a well-formed type tree
+        if !schema.is_valid() {
+            return Err(PublishError::InvalidSchema);
+        }
+
+        let topic = Topic {
+            id: self.generator.generate(),
+            name: name,
+            partitions: partitions,
+            schema: schema,
+            compatibility: compatibility,
+            acl: acl,
+            secrets: secrets,
+        };
+
+        if changed {
+            self.set_acl(topic.name.clone(), topic.acl.clone());
         }
+        self.record(Event::Publish(query, topic.clone()));
+        self.insert_topic(query, topic.clone());
+
+        Ok(topic)
+    }
+
+    fn insert_topic(&mut self, query: QueryId, mut topic: Topic) {
+        let publication = Publication(query, topic.id);
+        debug!("publish: {:?}", publication);
+
+        // anyone can read `$topics`, the secrets are only handed out to subscribers
+        let secrets = mem::replace(&mut topic.secrets, Vec::new());
+        self.secrets.insert(topic.id, secrets);
+        self.directory.insert(topic.name.clone(), topic.id);
+        self.publications.insert(publication);
+        self.topics.insert(topic.id, topic);
+    }
+
+    /// Replaces the ACL restricting who may publish `name`.
+    fn set_acl(&mut self, name: String, acl: Acl) {
+        self.record(Event::SetAcl(name.clone(), acl.clone()));
+        self.acls.insert(name, acl);
     }
 
     pub fn unpublish(&mut self,
@@ -145,12 +364,14 @@ impl Catalog {
                      -> Result<(), UnpublishError> {
         let publication = Publication(query_id, topic);
         debug!("unpublish: {:?}", publication);
+        self.record(Event::Unpublish(query_id, topic));
 
         if let Some(name) = self.topics.get(&topic).map(|t| &*t.name) {
             self.directory.remove(name);
         }
 
         self.topics.remove(&topic);
+        self.secrets.remove(&topic);
         self.publications.remove(publication);
         Ok(())
     }
@@ -163,9 +384,53 @@ impl Catalog {
         }
     }
 
+    /// Returns the topic along with its secrets if its ACL grants `query`
+    /// access to it.
+    pub fn authorize(&self, query: QueryId, topic: Topic) -> Option<Topic> {
+        if topic.acl.may_subscribe(self.queries.get(&query)) {
+            Some(self.with_secrets(topic))
+        } else {
+            None
+        }
+    }
+
+    fn with_secrets(&self, mut topic: Topic) -> Topic {
+        if let Some(secrets) = self.secrets.get(&topic.id) {
+            topic.secrets = secrets.clone();
+        }
+        topic
+    }
+
+    /// Removes all publications and subscriptions of a query, for queries
+    /// which cannot withdraw them anymore because their connection is gone.
+    pub fn withdraw_query(&mut self, query_id: QueryId) {
+        let topics: Vec<TopicId> = self.publications
+            .inner
+            .keys()
+            .filter(|p| p.0 == query_id)
+            .map(|p| p.1)
+            .collect();
+        for topic in topics {
+            let _ = self.unpublish(query_id, topic);
+        }
+
+        let subscriptions: Vec<(TopicId, usize)> = self.subscriptions
+            .inner
+            .iter()
+            .filter(|&(s, _)| s.0 == query_id)
+            .map(|(s, &count)| (s.1, count))
+            .collect();
+        for (topic, count) in subscriptions {
+            for _ in 0..count {
+                let _ = self.unsubscribe(query_id, topic);
+            }
+        }
+    }
+
     pub fn subscribe(&mut self, query_id: QueryId, topic: TopicId) {
         let subscription = Subscription(query_id, topic);
         debug!("subscribe: {:?}", subscription);
+        self.record(Event::Subscribe(query_id, topic));
         self.subscriptions.insert(subscription);
     }
 
@@ -175,11 +440,13 @@ impl Catalog {
                        -> Result<(), UnsubscribeError> {
         let subscription = Subscription(query_id, topic);
         debug!("unsubscribe: {:?}", subscription);
+        self.record(Event::Unsubscribe(query_id, topic));
         self.subscriptions.remove(subscription);
         Ok(())
     }
 
     pub fn add_keeper(&mut self, keeper: Keeper) {
+        self.record(Event::AddKeeper(keeper.clone()));
         self.keepers.insert(keeper.id, keeper);
     }
 
@@ -192,11 +459,13 @@ impl Catalog {
             None => return Err("No such Keeper".to_string()),
         };
         keeper.workers.push((worker_num, addr));
+        self.record(Event::AddKeeper(keeper.clone()));
         self.keepers.insert(keeper.id, keeper);
         Ok(())
     }
 
     pub fn remove_keeper(&mut self, id: &KeeperId) -> Option<Keeper> {
+        self.record(Event::RemoveKeeper(*id));
         self.keepers.remove(id)
     }
 }
@@ -206,18 +475,23 @@ struct MapCollection<K, V> {
     mutator: Mutator<V>,
 }
 
-impl<K: Ord, V: Serialize + Eq + Clone + 'static> MapCollection<K, V> {
+impl<K: Ord, V: Serialize + DeserializeOwned + Eq + Hash + Clone + 'static> MapCollection<K, V> {
     fn new(network: &Network,
            handle: &Handle,
            topic_id: TopicId,
            name: &'static str)
            -> io::Result<(Topic, Self)> {
-        let (addr, mutator, publisher) = CollectionPublisher::new(network)?;
+        let (addr, mutator, mut publisher) = CollectionPublisher::new(network)?;
+        // the catalog can be read by anyone
+        publisher.public();
         let topic = Topic {
             id: topic_id,
             name: String::from(name),
-            addr: addr,
-            schema: TopicSchema::Collection(TopicType::of::<V>()),
+            partitions: vec![addr],
+            schema: TopicSchema::Collection(V::schema().expect("catalog type without schema")),
+            compatibility: Compatibility::None,
+            acl: Acl::default(),
+            secrets: Vec::new(),
         };
 
         handle.spawn(publisher.map_err(|err| {
@@ -258,18 +532,23 @@ struct Collection<T> {
     mutator: Mutator<T>,
 }
 
-impl<T: Serialize + Clone + Eq + Hash + 'static> Collection<T> {
+impl<T: Serialize + DeserializeOwned + Clone + Eq + Hash + 'static> Collection<T> {
     fn new(network: &Network,
            handle: &Handle,
            topic_id: TopicId,
            name: &'static str)
            -> io::Result<(Topic, Self)> {
-        let (addr, mutator, publisher) = CollectionPublisher::new(network)?;
+        let (addr, mutator, mut publisher) = CollectionPublisher::new(network)?;
+        // the catalog can be read by anyone
+        publisher.public();
         let topic = Topic {
             id: topic_id,
             name: String::from(name),
-            addr: addr,
-            schema: TopicSchema::Collection(TopicType::of::<T>()),
+            partitions: vec![addr],
+            schema: TopicSchema::Collection(T::schema().expect("catalog type without schema")),
+            compatibility: Compatibility::None,
+            acl: Acl::default(),
+            secrets: Vec::new(),
         };
 
         handle.spawn(publisher.map_err(|err| {
@@ -311,3 +590,169 @@ impl<'a> Iterator for Executors<'a> {
         self.inner.next()
     }
 }
+
+pub struct Queries<'a> {
+    inner: Values<'a, QueryId, Query>,
+}
+
+impl<'a> Iterator for Queries<'a> {
+    type Item = &'a Query;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+pub struct Keepers<'a> {
+    inner: Values<'a, KeeperId, Keeper>,
+}
+
+impl<'a> Iterator for Keepers<'a> {
+    type Item = &'a Keeper;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{BufRead, BufReader};
+    use std::path::{Path, PathBuf};
+
+    use rand;
+    use tokio_core::reactor::Core;
+    use strymon_communication::Network;
+
+    use model::*;
+    use coordinator::requests::PublishError;
+    use super::{Allocation, Catalog};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("strymon_catalog_{}_{}", name, rand::random::<u64>()))
+    }
+
+    fn query(id: u64, name: &str) -> Query {
+        Query {
+            id: QueryId(id),
+            name: Some(name.to_string()),
+            user: None,
+            program: QueryProgram {
+                format: ExecutionFormat::NativeExecutable,
+                source: String::from("query"),
+                args: vec![],
+            },
+            workers: 2,
+            executors: vec![ExecutorId(0), ExecutorId(1)],
+            status: QueryStatus::Running,
+        }
+    }
+
+    fn publish(catalog: &mut Catalog, query: u64, acl: Acl) -> Result<Topic, PublishError> {
+        let schema = TopicSchema::Collection(u64::schema().unwrap());
+        catalog.publish(QueryId(query),
+                        String::from("topic"),
+                        vec![(String::from("localhost"), 2101)],
+                        vec![42],
+                        schema,
+                        Compatibility::None,
+                        acl)
+    }
+
+    fn journal_lines(path: &Path) -> Vec<String> {
+        let file = File::open(path).unwrap();
+        BufReader::new(file).lines().map(|l| l.unwrap()).collect()
+    }
+
+    #[test]
+    fn recover_from_journal() {
+        let core = Core::new().unwrap();
+        let network = Network::init().unwrap();
+        let path = temp_path("recover");
+
+        let allocation = Allocation {
+            ports: vec![(ExecutorId(0), 2101), (ExecutorId(1), 2101)],
+            threads: vec![1, 1],
+            auth: 42,
+        };
+        let acl = Acl {
+            subscribe: None,
+            publish: Some(vec![Principal::Query(String::from("owner"))]),
+        };
+
+        let topic = {
+            let mut catalog = Catalog::recover(&network, &core.handle(), &path).unwrap();
+            catalog.add_query(query(1, "owner"));
+            catalog.allocate(QueryId(1), allocation.clone());
+            catalog.add_query(query(2, "other"));
+            catalog.remove_query(QueryId(2));
+            catalog.add_query(query(3, "other"));
+            publish(&mut catalog, 1, acl.clone()).unwrap()
+        };
+
+        let mut catalog = Catalog::recover(&network, &core.handle(), &path).unwrap();
+        assert!(catalog.query(QueryId(1)).is_some());
+        assert!(catalog.query(QueryId(2)).is_none());
+        assert_eq!(catalog.allocation(QueryId(1)), Some(&allocation));
+        assert_eq!(catalog.lookup("topic").map(|t| t.id), Some(topic.id));
+
+        // secrets are restored, but only handed out to subscribers
+        assert!(catalog.lookup("topic").unwrap().secrets.is_empty());
+        assert_eq!(catalog.authorize(QueryId(3), topic.clone()).unwrap().secrets, vec![42]);
+
+        // the ACL of the name survives its topic
+        catalog.withdraw_query(QueryId(1));
+        assert!(catalog.lookup("topic").is_none());
+        match publish(&mut catalog, 3, Acl::default()) {
+            Err(PublishError::PermissionDenied) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        drop(catalog);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_on_recovery() {
+        let core = Core::new().unwrap();
+        let network = Network::init().unwrap();
+        let path = temp_path("compact");
+
+        {
+            let mut catalog = Catalog::recover(&network, &core.handle(), &path).unwrap();
+            for id in 0..10 {
+                catalog.add_query(query(id, "query"));
+                catalog.remove_query(QueryId(id));
+            }
+            catalog.add_query(query(10, "query"));
+        }
+        assert_eq!(journal_lines(&path).len(), 21);
+
+        // only the surviving query is kept in the journal
+        let catalog = Catalog::recover(&network, &core.handle(), &path).unwrap();
+        assert_eq!(catalog.queries().map(|q| q.id).collect::<Vec<_>>(), vec![QueryId(10)]);
+        assert_eq!(journal_lines(&path).len(), 1);
+
+        drop(catalog);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn first_publisher_must_be_granted() {
+        let core = Core::new().unwrap();
+        let network = Network::init().unwrap();
+        let mut catalog = Catalog::new(&network, &core.handle()).unwrap();
+        catalog.add_query(query(1, "owner"));
+
+        let acl = Acl {
+            subscribe: None,
+            publish: Some(vec![Principal::Query(String::from("somebody else"))]),
+        };
+        match publish(&mut catalog, 1, acl) {
+            Err(PublishError::PermissionDenied) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -9,6 +9,7 @@
 use std::io::{Error, ErrorKind};
 
 use futures::future::Future;
+use futures::unsync::oneshot::Sender;
 use tokio_core::reactor::Handle;
 
 use strymon_communication::rpc::{Outgoing, RequestBuf};
@@ -20,15 +21,19 @@ pub struct Dispatch {
     coord: CoordinatorRef,
     handle: Handle,
     tx: Outgoing,
+    evict: Option<Sender<()>>,
 }
 
 impl Dispatch {
-    pub fn new(coord: CoordinatorRef, handle: Handle, tx: Outgoing) -> Self {
+    /// Creates a new dispatcher, `evict` is used to close the connection
+    /// if an executor registered on it is evicted by the coordinator.
+    pub fn new(coord: CoordinatorRef, handle: Handle, tx: Outgoing, evict: Sender<()>) -> Self {
         debug!("dispatching on new incoming connection");
         Dispatch {
             coord: coord,
             handle: handle,
             tx: tx,
+            evict: Some(evict),
         }
     }
 
@@ -43,22 +48,45 @@ impl Dispatch {
 
                 self.handle.spawn(submission);
             }
+            "TerminateQuery" => {
+                let (TerminateQuery { query }, resp) = req.decode::<TerminateQuery>()?;
+                let terminate = self.coord
+                    .terminate(query)
+                    .then(|res| Ok(resp.respond(res)));
+                self.handle.spawn(terminate);
+            }
+            "FetchLogs" => {
+                let (FetchLogs { query, offsets }, resp) = req.decode::<FetchLogs>()?;
+                let logs = self.coord
+                    .fetch_logs(query, offsets)
+                    .then(|res| Ok(resp.respond(res)));
+                self.handle.spawn(logs);
+            }
+            "ProcessExited" => {
+                let (ProcessExited { query, executor, status }, resp) =
+                    req.decode::<ProcessExited>()?;
+                self.coord.process_exited(query, executor, status);
+                resp.respond(Ok(()));
+            }
             "AddWorkerGroup" => {
-                let (AddWorkerGroup { query, group }, resp) =
+                let (AddWorkerGroup { query, group, reattach }, resp) =
                     req.decode::<AddWorkerGroup>()?;
                 let response = self.coord
-                    .add_worker_group(query, group)
+                    .add_worker_group(query, group, reattach)
                     .then(|res| Ok(resp.respond(res)));
                 self.handle.spawn(response);
             }
             "AddExecutor" => {
                 let (req, resp) = req.decode::<AddExecutor>()?;
-                let id = self.coord.add_executor(req, self.tx.clone());
+                let id = self.coord.add_executor(req, self.tx.clone(), self.evict.take());
                 resp.respond(Ok((id)));
             }
             "Publish" => {
                 let (req, resp) = req.decode::<Publish>()?;
-                resp.respond(self.coord.publish(req));
+                let publish = self.coord
+                    .publish(req)
+                    .then(|res| Ok(resp.respond(res)));
+                self.handle.spawn(publish);
             }
             "Unpublish" => {
                 let (Unpublish { token, topic }, resp) = req.decode::<Unpublish>()?;
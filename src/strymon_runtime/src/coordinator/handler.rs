@@ -6,15 +6,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::collections::btree_map::Entry;
 use std::rc::{Rc, Weak};
 use std::cell::RefCell;
 use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use futures::{self, Future};
+use futures::{self, Future, Stream};
 use futures::unsync::oneshot::{channel, Sender};
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Interval, Timeout};
 
 use rand;
 
@@ -24,21 +25,69 @@ use model::*;
 use executor::requests::*;
 
 use coordinator::requests::*;
-use coordinator::catalog::Catalog;
+use coordinator::catalog::{Allocation, Catalog};
 
 use super::util::Generator;
+use super::placement::{self, Candidate};
+
+/// Tunable parameters of the coordinator.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Time to wait for all worker groups of a query to register
+    pub spawn_timeout: Duration,
+    /// Interval at which executors are probed for liveness
+    pub heartbeat_interval: Duration,
+    /// Number of consecutive unanswered heartbeats before eviction
+    pub max_missed_heartbeats: u32,
+    /// Number of finished or failed queries kept in the catalog
+    pub query_retention: usize,
+    /// Time given to executors and worker groups to reattach after a
+    /// restart, before recovered queries which are not back are terminated
+    pub recovery_grace: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            spawn_timeout: Duration::from_secs(60),
+            heartbeat_interval: Duration::from_secs(5),
+            max_missed_heartbeats: 3,
+            query_retention: 100,
+            recovery_grace: Duration::from_secs(60),
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 
 struct ExecutorState {
     tx: Outgoing,
     ports: VecDeque<u16>,
+    missed: u32,
+    last_seen: u64,
+    /// Closes the connection of the executor when it is evicted
+    evict: Option<Sender<()>>,
+    /// Number of workers and worker groups placed on this executor
+    workers: usize,
+    groups: usize,
 }
 
 impl ExecutorState {
-    fn new(tx: Outgoing, ports: (u16, u16)) -> Self {
+    fn new(tx: Outgoing, ports: (u16, u16), evict: Option<Sender<()>>) -> Self {
         let ports = (ports.0..(ports.1 + 1)).collect();
         ExecutorState {
             tx: tx,
             ports: ports,
+            missed: 0,
+            last_seen: unix_time(),
+            evict: evict,
+            workers: 0,
+            groups: 0,
         }
     }
 
@@ -46,11 +95,22 @@ impl ExecutorState {
         !self.ports.is_empty()
     }
 
-    fn allocate_port(&mut self) -> u16 {
+    fn allocate_port(&mut self, workers: usize) -> u16 {
+        self.workers += workers;
+        self.groups += 1;
         self.ports.pop_front().expect("coordinator has no free ports")
     }
 
-    fn free_port(&mut self, port: u16) {
+    /// Marks a port as taken by a worker group spawned before a restart.
+    fn reserve_port(&mut self, port: u16, workers: usize) {
+        self.workers += workers;
+        self.groups += 1;
+        self.ports.retain(|&p| p != port);
+    }
+
+    fn free_port(&mut self, port: u16, workers: usize) {
+        self.workers -= workers;
+        self.groups -= 1;
         self.ports.push_back(port);
     }
 
@@ -58,6 +118,39 @@ impl ExecutorState {
         debug!("issue spawn request {:?}", req);
         self.tx.request(req)
     }
+
+    fn kill(&self, req: &KillQuery) -> Response<KillQuery> {
+        debug!("issue kill request {:?}", req);
+        self.tx.request(req)
+    }
+
+    fn read_log(&self, req: &ReadLog) -> Response<ReadLog> {
+        self.tx.request(req)
+    }
+
+    fn heartbeat(&self) -> Response<Heartbeat> {
+        self.tx.request(&Heartbeat)
+    }
+}
+
+/// Annotates executors with their current load, dropping the ones which
+/// have no capacity left for `workers` additional workers.
+fn candidates<'a, I>(executors: I,
+                     state: &BTreeMap<ExecutorId, ExecutorState>,
+                     workers: usize)
+                     -> Vec<Candidate<'a>>
+    where I: Iterator<Item = &'a Executor>
+{
+    executors.map(|e| {
+            let state = &state[&e.id];
+            Candidate {
+                executor: e,
+                workers: state.workers,
+                groups: state.groups,
+            }
+        })
+        .filter(|c| c.fits(workers))
+        .collect()
 }
 
 enum QueryState {
@@ -74,6 +167,21 @@ struct WorkerGroup {
     state: QueryState,
     count: usize,
     ports: Vec<(ExecutorId, u16)>,
+    /// Number of workers of each worker group, in the order of `ports`
+    threads: Vec<usize>,
+    /// Secret of the query token, handed out to every worker group
+    auth: u64,
+}
+
+/// A partitioned topic waiting for the remaining partitions to be published.
+struct PartitionedTopic {
+    query: QueryId,
+    schema: TopicSchema,
+    compatibility: Compatibility,
+    acl: Acl,
+    /// Address and secret of every partition
+    addrs: Vec<Option<((String, u16), u64)>>,
+    waiting: Vec<Sender<Result<Topic, PublishError>>>,
 }
 
 struct KeeperState {
@@ -86,40 +194,298 @@ pub struct Coordinator {
     handle: Weak<RefCell<Coordinator>>,
     reactor: Handle,
     catalog: Catalog,
+    config: Config,
 
     queryid: Generator<QueryId>,
     executorid: Generator<ExecutorId>,
     keeperid: Generator<KeeperId>,
 
     executors: BTreeMap<ExecutorId, ExecutorState>,
+    detached: BTreeMap<ExecutorId, u32>,
     queries: BTreeMap<QueryId, WorkerGroup>,
+    exited: BTreeMap<QueryId, usize>,
+    /// Finished and failed queries still in the catalog, oldest first
+    completed: VecDeque<QueryId>,
+    /// Queries spawned before a restart, with the worker groups which have
+    /// reattached so far
+    recovered: HashMap<QueryId, HashSet<usize>>,
     lookups: HashMap<String, Vec<Sender<Result<Topic, SubscribeError>>>>,
+    partitioned: HashMap<String, PartitionedTopic>,
     keepers: BTreeMap<KeeperId, KeeperState>,
     keepers_directory: HashMap<String, KeeperId>,
 }
 
 impl Coordinator {
-    pub fn new(catalog: Catalog, reactor: Handle) -> CoordinatorRef {
-        let coord = Coordinator {
+    pub fn new(catalog: Catalog, reactor: Handle, config: Config) -> CoordinatorRef {
+        let mut coord = Coordinator {
             handle: Weak::new(),
             catalog: catalog,
             queryid: Generator::new(),
             executorid: Generator::new(),
             keeperid: Generator::new(),
             executors: BTreeMap::new(),
+            detached: BTreeMap::new(),
             queries: BTreeMap::new(),
+            exited: BTreeMap::new(),
+            completed: VecDeque::new(),
+            recovered: HashMap::new(),
             lookups: HashMap::new(),
+            partitioned: HashMap::new(),
             keepers: BTreeMap::new(),
             keepers_directory: HashMap::new(),
             reactor: reactor,
+            config: config,
         };
 
+        coord.recover();
+
         // we use weak references to avoid cycles
         let coord = Rc::new(RefCell::new(coord));
         coord.borrow_mut().handle = Rc::downgrade(&coord);
+        coord.borrow().start_heartbeats();
+        coord.borrow().start_recovery_grace();
         CoordinatorRef::from(coord)
     }
 
+    fn start_heartbeats(&self) {
+        let handle = self.handle.clone();
+        let interval = match Interval::new(self.config.heartbeat_interval, &self.reactor) {
+            Ok(interval) => interval,
+            Err(err) => return error!("failed to set up heartbeat timer: {}", err),
+        };
+
+        let heartbeats = interval
+            .map_err(|err| error!("heartbeat timer failed: {}", err))
+            .for_each(move |()| {
+                // stop once the coordinator has been deallocated
+                let coord = handle.upgrade().ok_or(())?;
+                coord.borrow_mut().heartbeat();
+                Ok(())
+            });
+
+        self.reactor.spawn(heartbeats);
+    }
+
+    /// Probes all executors for liveness and evicts the ones which have not
+    /// answered the last `max_missed_heartbeats` heartbeats.
+    fn heartbeat(&mut self) {
+        let max_missed = self.config.max_missed_heartbeats;
+        let mut evicted = vec![];
+
+        // recovered executors which never reattached are treated as silent
+        for (&id, missed) in self.detached.iter_mut() {
+            *missed += 1;
+            if *missed >= max_missed {
+                evicted.push(id);
+            }
+        }
+
+        for (&id, executor) in self.executors.iter_mut() {
+            if executor.missed >= max_missed {
+                evicted.push(id);
+                continue;
+            }
+
+            // an outstanding heartbeat means the previous one was missed
+            if executor.missed > 0 {
+                if let Some(mut entry) = self.catalog.executor(id).cloned() {
+                    entry.last_seen = executor.last_seen;
+                    entry.health = ExecutorHealth::Unresponsive(executor.missed);
+                    self.catalog.update_executor(entry);
+                }
+            }
+
+            executor.missed += 1;
+            let handle = self.handle.clone();
+            let response = executor.heartbeat().then(move |res| {
+                if res.is_ok() {
+                    if let Some(coord) = handle.upgrade() {
+                        coord.borrow_mut().executor_alive(id);
+                    }
+                }
+                Ok(())
+            });
+            self.reactor.spawn(response);
+        }
+
+        for id in evicted {
+            self.evict_executor(id);
+        }
+    }
+
+    fn executor_alive(&mut self, id: ExecutorId) {
+        let last_seen = if let Some(executor) = self.executors.get_mut(&id) {
+            executor.missed = 0;
+            executor.last_seen = unix_time();
+            executor.last_seen
+        } else {
+            // executor has been evicted in the meantime
+            return;
+        };
+
+        // only recovering executors change their catalog entry
+        if let Some(mut entry) = self.catalog.executor(id).cloned() {
+            if entry.health != ExecutorHealth::Healthy {
+                entry.last_seen = last_seen;
+                entry.health = ExecutorHealth::Healthy;
+                self.catalog.update_executor(entry);
+            }
+        }
+    }
+
+    fn evict_executor(&mut self, id: ExecutorId) {
+        warn!("evicting unresponsive executor {:?}", id);
+        self.detached.remove(&id);
+
+        // close the connection, in case the executor ever wakes up again
+        let evict = self.executors.get_mut(&id).and_then(|e| e.evict.take());
+        if let Some(evict) = evict {
+            drop(evict.send(()));
+        }
+
+        self.remove_executor(id);
+
+        // running queries with processes on this executor have failed
+        let failed: Vec<Query> = self.catalog
+            .queries()
+            .filter(|q| q.executors.contains(&id) && q.status == QueryStatus::Running)
+            .cloned()
+            .collect();
+
+        for query in failed {
+            self.complete_query(query, QueryStatus::Failed(None));
+        }
+    }
+
+    /// Restores the coordinator state which can be derived from a recovered
+    /// catalog. Executors found in the catalog stay detached until they
+    /// reconnect and reattach using their previous executor id. Worker groups
+    /// of queries which were alive keep their ports, but are disconnected
+    /// until their processes reattach using the query token.
+    fn recover(&mut self) {
+        for executor in self.catalog.executors() {
+            self.executorid.advance_past(executor.id.0);
+            self.detached.insert(executor.id, 0);
+        }
+
+        for query in self.catalog.queries() {
+            self.queryid.advance_past(query.id.0);
+            if query.status.is_completed() {
+                self.completed.push_back(query.id);
+            }
+        }
+
+        let alive: Vec<(Query, Allocation)> = self.catalog
+            .queries()
+            .filter_map(|q| self.catalog.allocation(q.id).map(|a| (q.clone(), a.clone())))
+            .collect();
+        for (query, allocation) in alive {
+            debug!("recovering worker groups of {:?}", query.id);
+            self.recovered.insert(query.id, HashSet::new());
+            self.queries.insert(query.id, WorkerGroup {
+                state: QueryState::Running,
+                // none of the worker groups are connected yet
+                count: 0,
+                ports: allocation.ports,
+                threads: allocation.threads,
+                auth: allocation.auth,
+            });
+
+            // the submitter is gone, so the submission cannot complete anymore
+            if query.status == QueryStatus::Spawning {
+                self.complete_query(query, QueryStatus::Failed(None));
+            }
+        }
+        self.expire_completed();
+
+        for keeper in self.catalog.keepers() {
+            self.keeperid.advance_past(keeper.id.0);
+            self.keepers_directory.insert(keeper.name.clone(), keeper.id);
+            self.keepers.insert(keeper.id, KeeperState {
+                workers: keeper.workers.clone(),
+                next_worker: 0,
+            });
+        }
+    }
+
+    /// Gives executors time to reattach and report processes of recovered
+    /// queries which exited while the coordinator was down, and the worker
+    /// groups of recovered queries time to reattach. Queries whose worker
+    /// groups did not all come back are terminated afterwards, as they cannot
+    /// be managed anymore.
+    fn start_recovery_grace(&self) {
+        if self.recovered.is_empty() {
+            return;
+        }
+
+        let handle = self.handle.clone();
+        match Timeout::new(self.config.recovery_grace, &self.reactor) {
+            Ok(timeout) => {
+                self.reactor.spawn(timeout.then(move |_| {
+                    if let Some(coord) = handle.upgrade() {
+                        coord.borrow_mut().expire_recovered();
+                    }
+                    Ok(())
+                }));
+            }
+            Err(err) => error!("failed to set up recovery timeout: {}", err),
+        }
+    }
+
+    fn expire_recovered(&mut self) {
+        let recovered: Vec<QueryId> = self.recovered.keys().cloned().collect();
+        for id in recovered {
+            warn!("terminating recovered {:?}, not all worker groups reattached", id);
+            let killed = self.terminate(id).map_err(|err| {
+                warn!("failed to terminate recovered query: {:?}", err)
+            });
+            self.reactor.spawn(killed);
+
+            let query = self.catalog.query(id).cloned();
+            if let Some(query) = query {
+                if !query.status.is_completed() {
+                    self.complete_query(query, QueryStatus::Failed(None));
+                }
+            }
+
+            // reattached worker groups are released once they disconnect
+            let connected = match self.queries.get_mut(&id) {
+                Some(query) => {
+                    query.state = QueryState::Terminating;
+                    query.count
+                }
+                None => 0,
+            };
+
+            if connected > 0 {
+                self.recovered.remove(&id);
+            } else {
+                self.release_recovered(id);
+            }
+        }
+    }
+
+    /// Frees the ports, topics and subscriptions of a recovered query which
+    /// has no worker groups connected. This is otherwise done when its worker
+    /// groups disconnect.
+    fn release_recovered(&mut self, id: QueryId) {
+        let connected = self.queries.get(&id).map_or(0, |q| q.count);
+        if connected > 0 || self.recovered.remove(&id).is_none() {
+            return;
+        }
+
+        debug!("releasing recovered {:?}", id);
+        if let Some(query) = self.queries.remove(&id) {
+            for ((executor, port), threads) in query.ports.into_iter().zip(query.threads) {
+                self.executors.get_mut(&executor).map(|e| e.free_port(port, threads));
+            }
+        }
+
+        self.catalog.release(id);
+        self.catalog.withdraw_query(id);
+        self.expire_completed();
+    }
+
     fn handle(&self) -> Rc<RefCell<Coordinator>> {
         self.handle.upgrade().expect("`self` has been deallocated?!")
     }
@@ -133,24 +499,24 @@ impl Coordinator {
         let queryid = self.queryid.generate();
 
         // step 2: Select suitable executors
-        let (executors, num_executors, num_workers) = {
+        let (executors, threads) = {
             // step 2.1: filter out executors with the wrong format,
             // and the ones with no more free network ports
             let format = &req.query.format;
             let executors = self.catalog
                 .executors()
                 .filter(|e| e.format == *format)
-                .filter(|e| executor_res[&e.id].has_ports());
+                .filter(|e| executor_res.get(&e.id).map_or(false, |e| e.has_ports()));
 
             // step 2.2: select executors according to user placment
-            let (executors, num_executors, num_workers) = match req.placement {
+            let (executors, threads) = match req.placement {
                 Placement::Random(num_executors, num_workers) => {
                     let mut rng = rand::thread_rng();
                     let selected = rand::sample(&mut rng, executors, num_executors);
-                    (selected, num_executors, num_workers)
+                    (selected, vec![num_workers; num_executors])
                 }
                 Placement::Fixed(executor_ids, num_workers) => {
-                    let num_executors = executor_ids.len();
+                    let threads = vec![num_workers; executor_ids.len()];
                     let mut selected = vec![];
 
                     for executor in executors {
@@ -161,24 +527,50 @@ impl Coordinator {
                         }
                     }
 
-                    (selected, num_executors, num_workers)
+                    (selected, threads)
+                }
+                Placement::Constraint(selectors, num_executors, num_workers) => {
+                    let matching = executors.filter(|e| {
+                        selectors.iter().all(|&(ref key, ref value)| {
+                            e.label(key) == Some(&**value)
+                        })
+                    });
+                    let mut rng = rand::thread_rng();
+                    let selected = rand::sample(&mut rng, matching, num_executors);
+                    (selected, vec![num_workers; num_executors])
+                }
+                Placement::LeastLoaded(num_executors, num_workers) => {
+                    let candidates = candidates(executors, executor_res, num_workers);
+                    let selected = placement::least_loaded(candidates, num_executors);
+                    (selected, vec![num_workers; num_executors])
+                }
+                Placement::Spread(num_executors, num_workers) => {
+                    let candidates = candidates(executors, executor_res, num_workers);
+                    let selected = placement::spread(candidates, num_executors);
+                    (selected, vec![num_workers; num_executors])
+                }
+                Placement::Pack(num_executors, num_workers) => {
+                    let candidates = candidates(executors, executor_res, num_workers);
+                    let selected = placement::pack(candidates, num_executors);
+                    (selected, vec![num_workers; num_executors])
                 }
             };
 
             // step 2.3: check if we actually have enough executors
-            if executors.len() != num_executors {
+            if executors.len() != threads.len() {
                 return Box::new(futures::failed(SubmissionError::ExecutorsNotFound));
             }
 
-            (executors, num_executors, num_workers)
+            (executors, threads)
         };
 
         // step 3: create the Timely configuration
         let ports: Vec<(ExecutorId, u16)> = executors.iter()
-            .map(|executor| {
+            .zip(threads.iter())
+            .map(|(executor, &threads)| {
                 let id = executor.id;
                 let executor = executor_res.get_mut(&id).unwrap();
-                (id, executor.allocate_port())
+                (id, executor.allocate_port(threads))
             })
             .collect();
 
@@ -191,20 +583,25 @@ impl Coordinator {
         let query = Query {
             id: queryid,
             name: req.name,
+            user: req.user,
             program: req.query,
-            workers: num_executors * num_workers,
+            workers: threads.iter().sum(),
             executors: executor_ids,
+            status: QueryStatus::Spawning,
         };
-        let spawnquery = SpawnQuery {
+        let mut spawnquery = SpawnQuery {
             query: query.clone(),
             hostlist: hostlist,
+            threads: threads.clone(),
+            process: 0,
         };
 
         // step 4: send requests to the selected coordinators
         debug!("selected executors for {:?}:{:?}", query.id, executors);
-        for executor in &executors {
+        for (process, executor) in executors.iter().enumerate() {
             let handle = handle.clone();
             let executor = &executor_res[&executor.id];
+            spawnquery.process = process;
             let response = executor.spawn(&spawnquery)
                 .map_err(move |err| {
                     let err = match err {
@@ -220,8 +617,26 @@ impl Coordinator {
             self.reactor.spawn(response);
         }
 
-        // TODO(swicki) add a timeout that triggers SpawnFailed here
+        // abort the submission if not all worker groups show up in time
+        match Timeout::new(self.config.spawn_timeout, &self.reactor) {
+            Ok(timeout) => {
+                let handle = handle.clone();
+                self.reactor.spawn(timeout.then(move |_| {
+                    handle.borrow_mut().expire_submission(queryid);
+                    Ok(())
+                }));
+            }
+            Err(err) => error!("failed to set up spawn timeout: {}", err),
+        }
+
         debug!("add pending submission for {:?}", query.id);
+        let auth = rand::random::<u64>();
+        self.catalog.add_query(query.clone());
+        self.catalog.allocate(queryid, Allocation {
+            ports: ports.clone(),
+            threads: threads.clone(),
+            auth: auth,
+        });
         let (tx, rx) = channel();
         let state = QueryState::Spawning {
             query: query,
@@ -233,12 +648,40 @@ impl Coordinator {
             state: state,
             count: executors.len(),
             ports: ports,
+            threads: threads,
+            auth: auth,
         };
         self.queries.insert(queryid, worker_group);
 
         Box::new(rx.then(|res| res.expect("submission canceled?!")))
     }
 
+    /// Called once the spawn deadline of a submission has passed. If the
+    /// query is still spawning, any partially spawned processes are killed
+    /// and the submission fails with `SpawnTimeout`.
+    fn expire_submission(&mut self, id: QueryId) {
+        let executors: Vec<ExecutorId> = match self.queries.get(&id) {
+            Some(&WorkerGroup { state: QueryState::Spawning { .. }, ref ports, .. }) => {
+                ports.iter().map(|&(executor, _)| executor).collect()
+            }
+            _ => return,
+        };
+
+        warn!("spawning {:?} timed out", id);
+        let req = KillQuery { query: id };
+        for executor in executors.iter().filter_map(|id| self.executors.get(id)) {
+            let response = executor.kill(&req).then(|res| {
+                if let Err(Err(err)) = res {
+                    error!("executor request failed: {}", err);
+                }
+                Ok(())
+            });
+            self.reactor.spawn(response);
+        }
+
+        self.cancel_submission(id, SubmissionError::SpawnTimeout);
+    }
+
     fn cancel_submission(&mut self, id: QueryId, err: SubmissionError) {
         debug!("canceling pending submission for {:?}", id);
         if let Some(query) = self.queries.remove(&id) {
@@ -249,15 +692,231 @@ impl Coordinator {
                 }
             }
 
-            for (id, port) in query.ports {
-                self.executors.get_mut(&id).map(|e| e.free_port(port));
+            for ((id, port), threads) in query.ports.into_iter().zip(query.threads) {
+                self.executors.get_mut(&id).map(|e| e.free_port(port, threads));
+            }
+
+            self.catalog.remove_query(id);
+        }
+    }
+
+    fn terminate(&mut self, id: QueryId) -> Box<Future<Item=(), Error=TerminateError>> {
+        let mut executors: Vec<ExecutorId> = match self.queries.get(&id) {
+            Some(query) => query.ports.iter().map(|&(executor, _)| executor).collect(),
+            None => {
+                // terminating a completed query removes it from the catalog
+                let completed = self.catalog.query(id).map(|q| q.status.is_completed());
+                return Box::new(if completed == Some(true) {
+                    self.remove_completed(id);
+                    futures::finished(())
+                } else {
+                    futures::failed(TerminateError::QueryNotFound)
+                });
+            }
+        };
+
+        // executors kill all processes of the query they are hosting
+        executors.sort();
+        executors.dedup();
+
+        // ask every executor hosting a worker group to kill its processes,
+        // the coordinator state is cleaned up once the processes disconnect
+        let req = KillQuery { query: id };
+        let mut responses = vec![];
+        let mut unreachable = false;
+        for executor in executors {
+            let executor = match self.executors.get(&executor) {
+                Some(executor) => executor,
+                None => {
+                    // still kill the processes on the remaining executors
+                    unreachable = true;
+                    continue;
+                }
+            };
+
+            let response = executor.kill(&req).then(|res| match res {
+                // process has already exited by itself
+                Ok(()) | Err(Ok(KillError::NotFound)) => Ok(()),
+                Err(Err(err)) => {
+                    error!("executor request failed: {}", err);
+                    Err(TerminateError::ExecutorUnreachable)
+                }
+            });
+            responses.push(response);
+        }
+
+        let killed = futures::future::join_all(responses).and_then(move |_| {
+            if unreachable {
+                Err(TerminateError::ExecutorUnreachable)
+            } else {
+                Ok(())
             }
+        });
+
+        Box::new(killed)
+    }
+
+    /// Fetches the next chunk of output of every process of a query,
+    /// including processes which have already exited. Logs which cannot be
+    /// read are marked as missing, without failing the whole request.
+    fn fetch_logs(&self,
+                  id: QueryId,
+                  offsets: Vec<u64>)
+                  -> Box<Future<Item = Vec<LogChunk>, Error = FetchLogsError>> {
+        let executors = match self.catalog.query(id) {
+            Some(query) => query.executors.clone(),
+            None => return Box::new(futures::failed(FetchLogsError::QueryNotFound)),
+        };
+
+        let mut responses: Vec<Box<Future<Item = LogChunk, Error = FetchLogsError>>> = vec![];
+        for (process, executor) in executors.into_iter().enumerate() {
+            let offset = offsets.get(process).cloned().unwrap_or(0);
+            let unavailable = move |reason| {
+                LogChunk {
+                    process: process,
+                    lines: vec![],
+                    offset: offset,
+                    missing: Some(reason),
+                }
+            };
+
+            let executor = match self.executors.get(&executor) {
+                Some(executor) => executor,
+                None => {
+                    responses.push(Box::new(futures::finished(
+                        unavailable(LogUnavailable::ExecutorUnreachable))));
+                    continue;
+                }
+            };
+
+            let req = ReadLog {
+                query: id,
+                process: process,
+                offset: offset,
+            };
+            let response = executor.read_log(&req).then(move |res| match res {
+                Ok((lines, offset)) => {
+                    Ok(LogChunk {
+                        process: process,
+                        lines: lines,
+                        offset: offset,
+                        missing: None,
+                    })
+                }
+                // the process has not written anything yet
+                Err(Ok(ReadLogError::NotFound)) => {
+                    Ok(LogChunk {
+                        process: process,
+                        lines: vec![],
+                        offset: offset,
+                        missing: None,
+                    })
+                }
+                Err(Ok(ReadLogError::Io(err))) => Ok(unavailable(LogUnavailable::Io(err))),
+                Err(Err(err)) => {
+                    error!("executor request failed: {}", err);
+                    Ok(unavailable(LogUnavailable::ExecutorUnreachable))
+                }
+            });
+            responses.push(Box::new(response));
         }
+
+        Box::new(futures::future::join_all(responses))
     }
 
-    fn add_worker_group(&mut self, id: QueryId,_group: usize)
+    /// Records the exit status of a single query process. A query is
+    /// finished once all of its processes exited successfully, and failed
+    /// as soon as one of them exits unsuccessfully.
+    fn process_exited(&mut self, id: QueryId, executor: ExecutorId, status: ProcessExit) {
+        debug!("process of {:?} on {:?} exited: {:?}", id, executor, status);
+
+        let spawning = match self.queries.get(&id).map(|q| &q.state) {
+            Some(&QueryState::Spawning { .. }) => true,
+            _ => false,
+        };
+
+        if spawning {
+            return self.cancel_submission(id, SubmissionError::ProcessExited(status));
+        }
+
+        let mut query = match self.catalog.query(id) {
+            Some(query) => query.clone(),
+            None => return warn!("exit status for unknown query {:?}", id),
+        };
+
+        let exited = {
+            let exited = self.exited.entry(id).or_insert(0);
+            *exited += 1;
+            *exited
+        };
+
+        let status = if !status.success() {
+            QueryStatus::Failed(Some(status))
+        } else if exited == query.executors.len() {
+            QueryStatus::Finished
+        } else {
+            query.status
+        };
+
+        let all_exited = exited == query.executors.len();
+        if all_exited {
+            self.exited.remove(&id);
+        }
+
+        // a failed query stays failed, even if other processes succeed
+        if query.status == QueryStatus::Running && status != query.status {
+            self.complete_query(query, status);
+        }
+
+        // worker groups which never reattached cannot release the query
+        if all_exited {
+            self.release_recovered(id);
+        }
+    }
+
+    /// Updates the final status of a query. Only the most recently completed
+    /// queries are kept in the catalog, older ones are removed.
+    fn complete_query(&mut self, mut query: Query, status: QueryStatus) {
+        debug_assert!(status.is_completed());
+        query.status = status;
+        self.completed.push_back(query.id);
+        self.catalog.update_query(query);
+        self.expire_completed();
+    }
+
+    /// Removes the oldest completed queries beyond the retention limit. A
+    /// query which failed while some of its processes are still running keeps
+    /// its allocation until all of its worker groups are gone.
+    fn expire_completed(&mut self) {
+        let excess = self.completed.len().saturating_sub(self.config.query_retention);
+        let expired: Vec<QueryId> = self.completed
+            .iter()
+            .filter(|&&id| !self.queries.contains_key(&id))
+            .take(excess)
+            .cloned()
+            .collect();
+
+        for id in expired {
+            self.remove_completed(id);
+        }
+    }
+
+    /// Removes a completed query and its allocation from the catalog.
+    fn remove_completed(&mut self, id: QueryId) {
+        debug!("removing completed query {:?}", id);
+        self.completed.retain(|&query| query != id);
+        self.exited.remove(&id);
+        self.catalog.remove_query(id);
+    }
+
+    fn add_worker_group(&mut self, id: QueryId, group: usize, reattach: Option<QueryToken>)
         -> Box<Future<Item=QueryToken, Error=WorkerGroupError>>
     {
+        // processes spawned before a restart already own a token
+        if let Some(token) = reattach {
+            return Box::new(futures::done(self.reattach_worker_group(token, group)));
+        }
+
         let query = self.queries.get_mut(&id);
 
         // step 1: check if we actually know about this query
@@ -285,6 +944,7 @@ impl Coordinator {
         if connected < query.count {
             return rx;
         }
+        let auth = query.auth;
 
         // step 3: at this point, all worker groups have registered themselves
         let waiting = mem::replace(&mut query.state, QueryState::Running);
@@ -295,13 +955,15 @@ impl Coordinator {
             _ => unreachable!(),
         };
 
-        // step 4: add query to catalog
-        self.catalog.add_query(query);
+        // step 4: mark query as running in catalog
+        let mut query = query;
+        query.status = QueryStatus::Running;
+        self.catalog.update_query(query);
 
         // step 5: respond to everyone
         let token = QueryToken {
             id: id,
-            auth: rand::random::<u64>(),
+            auth: auth,
         };
         for worker in waiting {
             let _ = worker.send(Ok(token));
@@ -312,6 +974,46 @@ impl Coordinator {
         rx
     }
 
+    /// Reattaches a worker group of a query spawned before a restart. The
+    /// query is managed like any other running query again once all of its
+    /// worker groups are back.
+    fn reattach_worker_group(&mut self, token: QueryToken, group: usize)
+        -> Result<QueryToken, WorkerGroupError>
+    {
+        let id = token.id;
+        let complete = {
+            let (reattached, query) = match (self.recovered.get_mut(&id),
+                                             self.queries.get_mut(&id)) {
+                (Some(reattached), Some(query)) => (reattached, query),
+                _ => return Err(WorkerGroupError::InvalidWorkerGroup),
+            };
+
+            if query.auth != token.auth {
+                return Err(WorkerGroupError::AuthenticationFailure);
+            }
+
+            // groups of terminating queries are not taken back
+            let running = match query.state {
+                QueryState::Running => true,
+                _ => false,
+            };
+
+            if !running || group >= query.ports.len() || !reattached.insert(group) {
+                return Err(WorkerGroupError::InvalidWorkerGroup);
+            }
+
+            query.count += 1;
+            debug!("{:?}: {} of {} have reattached", id, query.count, query.ports.len());
+            query.count == query.ports.len()
+        };
+
+        if complete {
+            self.recovered.remove(&id);
+        }
+
+        Ok(token)
+    }
+
     fn remove_worker_group(&mut self, id: QueryId) {
         let mut query = match self.queries.entry(id) {
             Entry::Occupied(query) => query,
@@ -324,48 +1026,191 @@ impl Coordinator {
         // decrease counter, set to terminating
         let count = {
             let query = query.get_mut();
-            query.state = QueryState::Terminating;
+            let state = mem::replace(&mut query.state, QueryState::Terminating);
+            if let QueryState::Spawning { submitter, waiting, .. } = state {
+                let _ = submitter.send(Err(SubmissionError::WorkerGroupLost));
+                for worker in waiting {
+                    let _ = worker.send(Err(WorkerGroupError::PeerFailed));
+                }
+            }
             query.count -= 1;
 
             query.count
         };
 
         // and we're done
+        // the catalog entry is kept to report the exit status of the query
         if count == 0 {
-            self.catalog.remove_query(id);
             let query = query.remove();
 
-            for (id, port) in query.ports {
-                self.executors.get_mut(&id).map(|e| e.free_port(port));
+            // partitioned topics of this query will never be complete
+            self.partitioned.retain(|_, topic| topic.query != id);
+
+            for ((id, port), threads) in query.ports.into_iter().zip(query.threads) {
+                self.executors.get_mut(&id).map(|e| e.free_port(port, threads));
             }
+
+            // topics of reattached queries, published before the restart,
+            // are not tracked by any connection
+            self.recovered.remove(&id);
+            self.catalog.release(id);
+            self.catalog.withdraw_query(id);
+
+            // completed queries are only expired once their processes are gone
+            self.expire_completed();
         }
     }
 
-    fn add_executor(&mut self, req: AddExecutor, tx: Outgoing) -> ExecutorId {
-        let id = self.executorid.generate();
-        debug!("adding executor {:?} to pool", id);
+    fn add_executor(&mut self,
+                    req: AddExecutor,
+                    tx: Outgoing,
+                    evict: Option<Sender<()>>)
+                    -> ExecutorId {
+        // executors which were known before a coordinator restart keep their id
+        let reattach = req.reattach.and_then(|id| self.detached.remove(&id).map(|_| id));
 
-        let state = ExecutorState::new(tx, req.ports);
+        let mut state = ExecutorState::new(tx, req.ports, evict);
         let executor = Executor {
-            id: id,
+            id: reattach.unwrap_or_else(|| self.executorid.generate()),
             host: req.host,
             format: req.format,
+            last_seen: unix_time(),
+            health: ExecutorHealth::Healthy,
+            capacity: req.capacity,
+            labels: req.labels,
         };
+        let id = executor.id;
+
+        if reattach.is_some() {
+            debug!("reattaching executor {:?} to pool", id);
+            // ports of worker groups spawned before the restart are still taken
+            for group in self.queries.values() {
+                for (&(executor, port), &threads) in group.ports.iter().zip(&group.threads) {
+                    if executor == id {
+                        state.reserve_port(port, threads);
+                    }
+                }
+            }
+            self.catalog.remove_executor(id);
+        } else {
+            debug!("adding executor {:?} to pool", id);
+        }
 
         self.executors.insert(id, state);
         self.catalog.add_executor(executor);
         id
     }
 
+    /// Called when a worker group disconnects before its query finished
+    /// spawning. This aborts the whole submission, as the remaining groups
+    /// will never be able to form a complete Timely cluster.
+    fn abort_worker_group(&mut self, id: QueryId) {
+        let spawning = match self.queries.get(&id).map(|q| &q.state) {
+            Some(&QueryState::Spawning { .. }) => true,
+            _ => false,
+        };
+
+        if spawning {
+            self.cancel_submission(id, SubmissionError::WorkerGroupLost);
+        }
+    }
+
     fn remove_executor(&mut self, id: ExecutorId) {
+        // evicted executors are removed again once their connection closes
+        if self.executors.remove(&id).is_none() && self.catalog.executor(id).is_none() {
+            return;
+        }
+
         debug!("removing executor {:?} from pool", id);
-        self.executors.remove(&id);
         self.catalog.remove_executor(id);
+
+        // abort pending submissions which have been placed on this executor
+        let pending: Vec<QueryId> = self.queries
+            .iter()
+            .filter(|&(_, query)| match query.state {
+                QueryState::Spawning { .. } => true,
+                _ => false,
+            })
+            .filter(|&(_, query)| query.ports.iter().any(|&(executor, _)| executor == id))
+            .map(|(&query, _)| query)
+            .collect();
+
+        for query in pending {
+            self.cancel_submission(query, SubmissionError::ExecutorUnreachable);
+        }
     }
 
-    fn publish(&mut self, req: Publish) -> Result<Topic, PublishError> {
+    fn publish(&mut self, req: Publish) -> Box<Future<Item = Topic, Error = PublishError>> {
         let query = req.token.id;
-        let result = self.catalog.publish(query, req.name, req.addr, req.schema);
+        let (index, count) = req.partition;
+        if count <= 1 {
+            let result = self.register(query,
+                                       req.name,
+                                       vec![(req.addr, req.secret)],
+                                       req.schema,
+                                       req.compatibility,
+                                       req.acl);
+            return Box::new(futures::done(result));
+        }
+
+        if index >= count {
+            return Box::new(futures::failed(PublishError::PartitionMismatch));
+        } else if self.catalog.lookup(&req.name).is_some() {
+            return Box::new(futures::failed(PublishError::TopicAlreadyExists));
+        }
+
+        // partitioned topics are registered once all partitions are known
+        let (tx, rx) = channel();
+        let complete = {
+            let pending = self.partitioned.entry(req.name.clone()).or_insert_with(|| {
+                PartitionedTopic {
+                    query: query,
+                    schema: req.schema.clone(),
+                    compatibility: req.compatibility,
+                    acl: req.acl.clone(),
+                    addrs: vec![None; count],
+                    waiting: Vec::new(),
+                }
+            });
+
+            if pending.query != query || pending.schema != req.schema ||
+               pending.compatibility != req.compatibility || pending.acl != req.acl ||
+               pending.addrs.len() != count || pending.addrs[index].is_some() {
+                return Box::new(futures::failed(PublishError::PartitionMismatch));
+            }
+
+            pending.addrs[index] = Some((req.addr, req.secret));
+            pending.waiting.push(tx);
+            pending.addrs.iter().all(Option::is_some)
+        };
+
+        if complete {
+            let pending = self.partitioned.remove(&req.name).unwrap();
+            let addrs = pending.addrs.into_iter().map(Option::unwrap).collect();
+            let result = self.register(pending.query,
+                                       req.name,
+                                       addrs,
+                                       pending.schema,
+                                       pending.compatibility,
+                                       pending.acl);
+            for tx in pending.waiting {
+                let _ = tx.send(result.clone());
+            }
+        }
+
+        Box::new(rx.then(|res| res.unwrap_or(Err(PublishError::PartitionLost))))
+    }
+
+    fn register(&mut self,
+                query: QueryId,
+                name: String,
+                partitions: Vec<((String, u16), u64)>,
+                schema: TopicSchema,
+                compatibility: Compatibility,
+                acl: Acl)
+                -> Result<Topic, PublishError> {
+        let (addrs, secrets) = partitions.into_iter().unzip();
+        let result = self.catalog.publish(query, name, addrs, secrets, schema, compatibility, acl);
         if let Ok(ref topic) = result {
             debug!("resolving lookup for topic: {:?}", &topic.name);
             if let Some(pending) = self.lookups.remove(&topic.name) {
@@ -388,8 +1233,21 @@ impl Coordinator {
                  req: Subscribe)
                  -> Box<Future<Item = Topic, Error = SubscribeError>> {
         let query = req.token.id;
+        let schema = req.schema;
+
+        // the subscriber must be able to decode the published data
+        let compatible = move |topic: &Topic| {
+            topic.schema.projection(&schema, topic.compatibility).is_some()
+        };
 
         if let Some(topic) = self.catalog.lookup(&req.name) {
+            if !compatible(&topic) {
+                return Box::new(futures::failed(SubscribeError::IncompatibleSchema));
+            }
+            let topic = match self.catalog.authorize(query, topic) {
+                Some(topic) => topic,
+                None => return Box::new(futures::failed(SubscribeError::PermissionDenied)),
+            };
             self.catalog.subscribe(query, topic.id);
             return Box::new(futures::finished(topic));
         } else if req.blocking {
@@ -403,7 +1261,14 @@ impl Coordinator {
                     res.unwrap_or(Err(SubscribeError::TopicNotFound))
                 })
                 .and_then(move |topic: Topic| {
-                    handle.borrow_mut().catalog.subscribe(query, topic.id);
+                    if !compatible(&topic) {
+                        return Err(SubscribeError::IncompatibleSchema);
+                    }
+                    let mut coord = handle.borrow_mut();
+                    let topic = coord.catalog
+                        .authorize(query, topic)
+                        .ok_or(SubscribeError::PermissionDenied)?;
+                    coord.catalog.subscribe(query, topic.id);
                     Ok(topic)
                 });
 
@@ -531,8 +1396,10 @@ impl Coordinator {
     }
 }
 
+/// Resources owned by a single client connection, released on disconnect.
 struct State {
     query: Vec<QueryToken>,
+    pending: Vec<QueryId>,
     executor: Vec<ExecutorId>,
     publication: Vec<(QueryId, TopicId)>,
     subscription: Vec<(QueryId, TopicId)>,
@@ -542,6 +1409,7 @@ impl State {
     fn empty() -> Self {
         State {
             query: Vec::new(),
+            pending: Vec::new(),
             executor: Vec::new(),
             publication: Vec::new(),
             subscription: Vec::new(),
@@ -572,42 +1440,76 @@ impl CoordinatorRef {
         self.coord.borrow_mut().submission(req)
     }
 
-    pub fn add_executor(&mut self, req: AddExecutor, tx: Outgoing) -> ExecutorId {
-        let id = self.coord.borrow_mut().add_executor(req, tx);
+    pub fn process_exited(&self, id: QueryId, executor: ExecutorId, status: ProcessExit) {
+        self.coord.borrow_mut().process_exited(id, executor, status)
+    }
+
+    pub fn terminate(&self, id: QueryId) -> Box<Future<Item = (), Error = TerminateError>> {
+        self.coord.borrow_mut().terminate(id)
+    }
+
+    pub fn fetch_logs(&self,
+                      id: QueryId,
+                      offsets: Vec<u64>)
+                      -> Box<Future<Item = Vec<LogChunk>, Error = FetchLogsError>> {
+        self.coord.borrow().fetch_logs(id, offsets)
+    }
+
+    pub fn add_executor(&mut self,
+                        req: AddExecutor,
+                        tx: Outgoing,
+                        evict: Option<Sender<()>>)
+                        -> ExecutorId {
+        let id = self.coord.borrow_mut().add_executor(req, tx, evict);
         self.state.borrow_mut().executor.push(id);
         id
     }
 
-    pub fn add_worker_group(&mut self, id: QueryId, group: usize)
+    pub fn add_worker_group(&mut self,
+                            id: QueryId,
+                            group: usize,
+                            reattach: Option<QueryToken>)
          -> Box<Future<Item = QueryToken, Error = WorkerGroupError>>
     {
+        // track the worker group while it waits for its peers
+        self.state.borrow_mut().pending.push(id);
+
         let state = self.state.clone();
         let future = self.coord
             .borrow_mut()
-            .add_worker_group(id, group)
-            .and_then(move |token| {
-                state.borrow_mut().query.push(token);
-                Ok(token)
+            .add_worker_group(id, group, reattach)
+            .then(move |result| {
+                let mut state = state.borrow_mut();
+                state.pending.retain(|&pending| pending != id);
+                if let Ok(token) = result {
+                    state.query.push(token);
+                }
+                result
             });
 
         Box::new(future)
     }
 
-    pub fn publish(&mut self, req: Publish) -> Result<Topic, PublishError> {
+    pub fn publish(&mut self,
+                   req: Publish)
+                   -> Box<Future<Item = Topic, Error = PublishError>> {
         let query = req.token;
         if !self.state.borrow().authenticate(&query) {
-            return Err(PublishError::AuthenticationFailure);
+            return Box::new(futures::failed(PublishError::AuthenticationFailure));
         }
 
-        self.coord
+        let state = self.state.clone();
+        let future = self.coord
             .borrow_mut()
             .publish(req)
-            .and_then(|topic| {
+            .and_then(move |topic| {
                 let topic_id = topic.id;
                 let query_id = query.id;
-                self.state.borrow_mut().publication.push((query_id, topic_id));
+                state.borrow_mut().publication.push((query_id, topic_id));
                 Ok(topic)
-            })
+            });
+
+        Box::new(future)
     }
 
     pub fn unpublish(&mut self,
@@ -711,7 +1613,16 @@ impl Clone for CoordinatorRef {
 
 impl Drop for CoordinatorRef {
     fn drop(&mut self) {
-        // here we clean up any state that we might own
+        self.disconnect();
+    }
+}
+
+impl CoordinatorRef {
+    /// Releases everything owned by the connection of this handle: Its
+    /// subscriptions and publications are removed from the catalog, its
+    /// worker groups are removed (freeing their executor ports) and any
+    /// executor registered through it is dropped from the pool.
+    fn disconnect(&mut self) {
         let mut state = self.state.borrow_mut();
         let mut coord = self.coord.borrow_mut();
 
@@ -731,6 +1642,10 @@ impl Drop for CoordinatorRef {
             coord.remove_worker_group(query.id);
         }
 
+        for query in state.pending.drain(..) {
+            coord.abort_worker_group(query);
+        }
+
         for executor in state.executor.drain(..) {
             coord.remove_executor(executor);
         }
@@ -0,0 +1,198 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A write-ahead log for the coordinator catalog.
+//!
+//! Every mutation of the catalog is appended as a single JSON encoded line
+//! to the journal file. When a coordinator is restarted on the same journal,
+//! the recorded events are replayed to rebuild the catalog, after which the
+//! journal is compacted to only contain the surviving entries.
+
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use model::*;
+
+use super::catalog::Allocation;
+
+/// A single mutation of the catalog.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Event {
+    AddExecutor(Executor),
+    RemoveExecutor(ExecutorId),
+    AddQuery(Query),
+    RemoveQuery(QueryId),
+    /// Ports held by the worker groups of a query
+    Allocate(QueryId, Allocation),
+    Release(QueryId),
+    Publish(QueryId, Topic),
+    /// ACL of a topic name, which outlives the topic itself
+    SetAcl(String, Acl),
+    Unpublish(QueryId, TopicId),
+    Subscribe(QueryId, TopicId),
+    Unsubscribe(QueryId, TopicId),
+    AddKeeper(Keeper),
+    RemoveKeeper(KeeperId),
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it does not exist yet.
+    /// Returns the journal handle together with all previously recorded events.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Vec<Event>)> {
+        let path = path.as_ref().to_owned();
+        let events = match File::open(&path) {
+            Ok(file) => Journal::read(file)?,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let journal = Journal {
+            path: path,
+            file: BufWriter::new(file),
+        };
+
+        Ok((journal, events))
+    }
+
+    fn read(file: File) -> io::Result<Vec<Event>> {
+        let mut events = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(event) => events.push(event),
+                Err(err) => {
+                    // a crash during `append` can leave a truncated last line
+                    warn!("ignoring corrupted journal entry: {}", err);
+                    break;
+                }
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Durably appends a new event to the journal.
+    pub fn append(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.file, event)
+            .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+
+    /// Atomically replaces the journal contents with the given events.
+    pub fn compact(&mut self, events: &[Event]) -> io::Result<()> {
+        let tmp = self.path.with_extension("compact");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            for event in events {
+                serde_json::to_writer(&mut writer, event)
+                    .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        self.file = BufWriter::new(file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use rand;
+
+    use model::*;
+    use super::{Event, Journal};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("strymon_journal_{}_{}", name, rand::random::<u64>()))
+    }
+
+    fn removed(events: &[Event]) -> Vec<u64> {
+        events.iter()
+            .map(|event| match *event {
+                Event::RemoveQuery(QueryId(id)) => id,
+                ref other => panic!("unexpected event: {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_appended() {
+        let path = temp_path("replay");
+        {
+            let (mut journal, events) = Journal::open(&path).unwrap();
+            assert!(events.is_empty());
+            journal.append(&Event::RemoveQuery(QueryId(1))).unwrap();
+            journal.append(&Event::RemoveQuery(QueryId(2))).unwrap();
+        }
+
+        let (_, events) = Journal::open(&path).unwrap();
+        assert_eq!(removed(&events), vec![1, 2]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ignore_truncated_entry() {
+        let path = temp_path("truncated");
+        {
+            let (mut journal, _) = Journal::open(&path).unwrap();
+            journal.append(&Event::RemoveQuery(QueryId(1))).unwrap();
+        }
+
+        // simulate a crash in the middle of an append
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"RemoveQue").unwrap();
+        drop(file);
+
+        let (_, events) = Journal::open(&path).unwrap();
+        assert_eq!(removed(&events), vec![1]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_replaces_entries() {
+        let path = temp_path("compact");
+        {
+            let (mut journal, _) = Journal::open(&path).unwrap();
+            for id in 0..10 {
+                journal.append(&Event::RemoveQuery(QueryId(id))).unwrap();
+            }
+            journal.compact(&[Event::RemoveQuery(QueryId(3))]).unwrap();
+            // appending continues after the compacted entries
+            journal.append(&Event::RemoveQuery(QueryId(11))).unwrap();
+        }
+
+        let (_, events) = Journal::open(&path).unwrap();
+        assert_eq!(removed(&events), vec![3, 11]);
+        assert!(!path.with_extension("compact").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -8,15 +8,18 @@
 
 use std::io::Result;
 use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use futures;
 use futures::future::Future;
 use futures::stream::Stream;
+use futures::unsync::oneshot;
 use tokio_core::reactor::Core;
 
 use strymon_communication::Network;
 
-use self::handler::Coordinator;
+use self::handler::{Config, Coordinator};
 use self::dispatch::Dispatch;
 use self::catalog::Catalog;
 
@@ -27,9 +30,13 @@ pub mod catalog;
 pub mod dispatch;
 
 mod util;
+mod placement;
+mod journal;
 
 pub struct Builder {
     port: u16,
+    catalog: Option<PathBuf>,
+    config: Config,
 }
 
 impl Builder {
@@ -40,34 +47,83 @@ impl Builder {
     pub fn port(&mut self, port: u16) {
         self.port = port;
     }
+
+    /// Persist the catalog in a write-ahead log at the given path, recovering
+    /// any state left behind by a previous coordinator instance.
+    pub fn catalog(&mut self, path: PathBuf) {
+        self.catalog = Some(path);
+    }
+
+    /// Time to wait for all worker groups of a submitted query to register
+    /// before the submission is aborted.
+    pub fn spawn_timeout(&mut self, timeout: Duration) {
+        self.config.spawn_timeout = timeout;
+    }
+
+    /// Interval at which the liveness of executors is checked.
+    pub fn heartbeat_interval(&mut self, interval: Duration) {
+        self.config.heartbeat_interval = interval;
+    }
+
+    /// Number of missed heartbeats after which an executor is evicted.
+    pub fn max_missed_heartbeats(&mut self, max: u32) {
+        self.config.max_missed_heartbeats = max;
+    }
+
+    /// Number of finished or failed queries kept in the catalog. Older
+    /// queries are removed once this limit is exceeded.
+    pub fn query_retention(&mut self, num: usize) {
+        self.config.query_retention = num;
+    }
+
+    /// Time given to executors to reattach after the coordinator recovered
+    /// its catalog. Recovered queries still running afterwards are terminated.
+    pub fn recovery_grace(&mut self, grace: Duration) {
+        self.config.recovery_grace = grace;
+    }
 }
 
 impl Default for Builder {
     fn default() -> Self {
-        Builder { port: 9189 }
+        Builder {
+            port: 9189,
+            catalog: None,
+            config: Config::default(),
+        }
     }
 }
 
 impl Builder {
     pub fn run(self) -> Result<()> {
+        let Builder { port, catalog, config } = self;
         let network = Network::init()?;
-        let server = network.server(self.port)?;
+        let server = network.server(port)?;
 
         let mut core = Core::new()?;
         let handle = core.handle();
-        let coordinate = futures::lazy(move || {
-            // TODO(swicki) we should return an I/O error instead
-            let catalog = Catalog::new(&network, &handle).expect("failed to create catalog");
-            let coord = Coordinator::new(catalog, handle.clone());
+        let reactor = handle.clone();
+        let catalog = futures::lazy(move || match catalog {
+            Some(path) => Catalog::recover(&network, &reactor, path),
+            None => Catalog::new(&network, &reactor),
+        });
+
+        let coordinate = catalog.and_then(move |catalog| {
+            let coord = Coordinator::new(catalog, handle.clone(), config);
 
             server.for_each(move |(tx, rx)| {
                 // every connection gets its own handle
-                let mut disp = Dispatch::new(coord.clone(), handle.clone(), tx);
+                let (evict, evicted) = oneshot::channel();
+                let mut disp = Dispatch::new(coord.clone(), handle.clone(), tx, evict);
                 let client = rx.for_each(move |req| disp.dispatch(req))
                     .map_err(|err| {
                         error!("failed to dispatch client: {:?}", err);
                     });
 
+                // the connection is closed early if its executor is evicted,
+                // a dropped sender just means that there is nothing to evict
+                let evicted = evicted.or_else(|_| futures::empty::<(), ()>());
+                let client = client.select(evicted).then(|_| Ok(()));
+
                 // handle client asynchronously
                 handle.spawn(client);
                 Ok(())
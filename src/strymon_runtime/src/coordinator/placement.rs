@@ -0,0 +1,234 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Load-aware strategies for selecting the executors of a submitted query.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+use model::Executor;
+
+/// Memory in megabytes committed for every placed worker, as queries do not
+/// declare how much memory they need.
+pub const WORKER_MEMORY: u64 = 256;
+
+/// An executor eligible for placement, together with its current load.
+pub struct Candidate<'a> {
+    pub executor: &'a Executor,
+    /// Number of workers currently placed on this executor
+    pub workers: usize,
+    /// Number of worker groups currently placed on this executor
+    pub groups: usize,
+}
+
+impl<'a> Candidate<'a> {
+    /// Orders executors by placed workers, preferring executors which have
+    /// more memory per worker among equally loaded ones.
+    fn load(&self) -> (usize, Reverse<u64>, usize) {
+        (self.workers, Reverse(self.memory_per_worker()), self.groups)
+    }
+
+    /// Advertised memory divided among the placed workers and one more.
+    fn memory_per_worker(&self) -> u64 {
+        self.executor.capacity.memory / (self.workers as u64 + 1)
+    }
+
+    /// Advertised memory which is not yet committed to placed workers.
+    fn free_memory(&self) -> u64 {
+        self.executor.capacity.memory.saturating_sub(self.workers as u64 * WORKER_MEMORY)
+    }
+
+    /// Checks if `workers` additional workers fit into the advertised
+    /// capacity, both in cores and in memory. Resources which were not
+    /// advertised do not limit the placement.
+    pub fn fits(&self, workers: usize) -> bool {
+        let cores = self.executor.capacity.cores;
+        let memory = self.executor.capacity.memory;
+        let placed = self.workers + workers;
+        (cores == 0 || placed <= cores) && (memory == 0 || placed as u64 * WORKER_MEMORY <= memory)
+    }
+}
+
+/// Selects the `num` executors with the fewest placed workers, breaking
+/// ties by the amount of memory available per worker.
+pub fn least_loaded<'a>(mut candidates: Vec<Candidate<'a>>, num: usize) -> Vec<&'a Executor> {
+    candidates.sort_by_key(|c| c.load());
+    candidates.into_iter().take(num).map(|c| c.executor).collect()
+}
+
+/// Distributes the selected executors as evenly as possible across hosts,
+/// preferring the least loaded executors on each host.
+pub fn spread<'a>(mut candidates: Vec<Candidate<'a>>, num: usize) -> Vec<&'a Executor> {
+    candidates.sort_by_key(|c| c.load());
+
+    // rank each executor by the number of less loaded executors on the same
+    // host, so that hosts are visited in a round-robin fashion
+    let mut per_host: HashMap<&'a str, usize> = HashMap::new();
+    let mut ranked: Vec<(usize, Candidate<'a>)> = candidates.into_iter()
+        .map(|c| {
+            let executor = c.executor;
+            let rank = per_host.entry(&*executor.host).or_insert(0);
+            *rank += 1;
+            (*rank, c)
+        })
+        .collect();
+
+    // stable sort keeps the load order within each rank
+    ranked.sort_by_key(|&(rank, _)| rank);
+    ranked.into_iter().take(num).map(|(_, c)| c.executor).collect()
+}
+
+/// Places the query onto as few hosts as possible, preferring hosts with
+/// many eligible executors, then hosts with more free memory and then hosts
+/// with fewer placed workers. Executors without room for another worker are
+/// skipped, hosts with no such executors left are therefore never chosen.
+pub fn pack<'a>(candidates: Vec<Candidate<'a>>, num: usize) -> Vec<&'a Executor> {
+    let mut hosts: BTreeMap<&'a str, Vec<Candidate<'a>>> = BTreeMap::new();
+    for c in candidates.into_iter().filter(|c| c.fits(1)) {
+        let executor = c.executor;
+        hosts.entry(&*executor.host).or_insert_with(Vec::new).push(c);
+    }
+
+    let mut hosts: Vec<Vec<Candidate<'a>>> = hosts.into_iter().map(|(_, h)| h).collect();
+    for host in &mut hosts {
+        host.sort_by_key(|c| c.load());
+    }
+    hosts.sort_by_key(|h| {
+        let memory: u64 = h.iter().map(|c| c.free_memory()).sum();
+        let workers: usize = h.iter().map(|c| c.workers).sum();
+        (Reverse((h.len(), memory)), workers)
+    });
+
+    hosts.into_iter()
+        .flat_map(|h| h.into_iter())
+        .take(num)
+        .map(|c| c.executor)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use model::{Capacity, ExecutionFormat, Executor, ExecutorHealth, ExecutorId};
+    use super::{least_loaded, pack, spread, Candidate, WORKER_MEMORY};
+
+    fn executor(id: u64, host: &str, cores: usize, memory: u64) -> Executor {
+        Executor {
+            id: ExecutorId(id),
+            host: host.to_string(),
+            format: ExecutionFormat::NativeExecutable,
+            last_seen: 0,
+            health: ExecutorHealth::Healthy,
+            capacity: Capacity {
+                cores: cores,
+                memory: memory,
+            },
+            labels: vec![],
+        }
+    }
+
+    fn candidate(executor: &Executor, workers: usize) -> Candidate {
+        Candidate {
+            executor: executor,
+            workers: workers,
+            groups: workers,
+        }
+    }
+
+    fn ids(selected: Vec<&Executor>) -> Vec<u64> {
+        selected.into_iter().map(|e| e.id.0).collect()
+    }
+
+    #[test]
+    fn fits_capacity() {
+        let limited = executor(0, "a", 4, 0);
+        assert!(candidate(&limited, 2).fits(2));
+        assert!(!candidate(&limited, 2).fits(3));
+
+        let unknown = executor(1, "a", 0, 0);
+        assert!(candidate(&unknown, 100).fits(100));
+    }
+
+    #[test]
+    fn fits_memory() {
+        let limited = executor(0, "a", 0, 4 * WORKER_MEMORY);
+        assert!(candidate(&limited, 2).fits(2));
+        assert!(!candidate(&limited, 2).fits(3));
+
+        // all memory is committed, even though the cores are not exhausted
+        let committed = executor(1, "a", 8, 4 * WORKER_MEMORY);
+        assert!(!candidate(&committed, 4).fits(1));
+    }
+
+    #[test]
+    fn least_loaded_prefers_idle_and_memory() {
+        let executors = vec![executor(0, "a", 0, 1024),
+                             executor(1, "a", 0, 1024),
+                             executor(2, "b", 0, 4096)];
+        let candidates = vec![candidate(&executors[0], 2),
+                              candidate(&executors[1], 0),
+                              candidate(&executors[2], 0)];
+
+        assert_eq!(ids(least_loaded(candidates, 2)), vec![2, 1]);
+    }
+
+    #[test]
+    fn spread_across_hosts() {
+        let executors = vec![executor(0, "a", 0, 0),
+                             executor(1, "a", 0, 0),
+                             executor(2, "b", 0, 0),
+                             executor(3, "b", 0, 0)];
+        let candidates = executors.iter().map(|e| candidate(e, 0)).collect();
+
+        let mut hosts: Vec<_> = spread(candidates, 2).into_iter().map(|e| &*e.host).collect();
+        hosts.sort();
+        assert_eq!(hosts, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn pack_onto_few_hosts() {
+        let executors = vec![executor(0, "a", 0, 1024),
+                             executor(1, "b", 0, 1024),
+                             executor(2, "b", 0, 1024),
+                             executor(3, "c", 0, 8192),
+                             executor(4, "c", 0, 8192)];
+        let candidates = executors.iter().map(|e| candidate(e, 0)).collect();
+
+        // both two-executor hosts fit, the one with more memory wins
+        assert_eq!(ids(pack(candidates, 2)), vec![3, 4]);
+    }
+
+    #[test]
+    fn pack_by_load() {
+        let executors = vec![executor(0, "a", 4, 0),
+                             executor(1, "a", 4, 0),
+                             executor(2, "b", 4, 4 * WORKER_MEMORY),
+                             executor(3, "b", 4, 4 * WORKER_MEMORY),
+                             executor(4, "c", 4, 4 * WORKER_MEMORY),
+                             executor(5, "c", 4, 4 * WORKER_MEMORY)];
+
+        // host a is full, b has less free memory left than c
+        let candidates = vec![candidate(&executors[0], 4),
+                              candidate(&executors[1], 4),
+                              candidate(&executors[2], 3),
+                              candidate(&executors[3], 3),
+                              candidate(&executors[4], 1),
+                              candidate(&executors[5], 1)];
+        assert_eq!(ids(pack(candidates, 2)), vec![4, 5]);
+
+        // full hosts are skipped even if no other host has enough executors
+        let candidates = vec![candidate(&executors[0], 4),
+                              candidate(&executors[1], 4),
+                              candidate(&executors[2], 0)];
+        assert_eq!(ids(pack(candidates, 2)), vec![2]);
+
+        // equally sized hosts without advertised memory prefer fewer workers
+        let executors = vec![executor(0, "a", 0, 0), executor(1, "b", 0, 0)];
+        let candidates = vec![candidate(&executors[0], 3), candidate(&executors[1], 1)];
+        assert_eq!(ids(pack(candidates, 1)), vec![1]);
+    }
+}
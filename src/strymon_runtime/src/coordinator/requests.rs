@@ -13,12 +13,17 @@ use strymon_communication::rpc::Request;
 pub enum Placement {
     Random(usize, usize), // (num executors, num workers)
     Fixed(Vec<ExecutorId>, usize), // (executors, num workers)
+    LeastLoaded(usize, usize), // (num executors, num workers)
+    Spread(usize, usize), // (num executors, num workers)
+    Pack(usize, usize), // (num executors, num workers)
+    Constraint(Vec<(String, String)>, usize, usize), // (label selectors, num executors, num workers)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Submission {
     pub query: QueryProgram,
     pub name: Option<String>,
+    pub user: Option<String>,
     pub placement: Placement,
 }
 
@@ -26,6 +31,9 @@ pub struct Submission {
 pub enum SubmissionError {
     ExecutorsNotFound,
     ExecutorUnreachable,
+    WorkerGroupLost,
+    SpawnTimeout,
+    ProcessExited(ProcessExit),
     SpawnError(::executor::requests::SpawnError),
 }
 
@@ -36,11 +44,87 @@ impl Request for Submission {
     const NAME: &'static str = "Submission";
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TerminateQuery {
+    pub query: QueryId,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TerminateError {
+    QueryNotFound,
+    ExecutorUnreachable,
+}
+
+impl Request for TerminateQuery {
+    type Success = ();
+    type Error = TerminateError;
+
+    const NAME: &'static str = "TerminateQuery";
+}
+
+/// Fetches the output of all processes of a query.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FetchLogs {
+    pub query: QueryId,
+    /// Offset to start reading from, indexed by process. Missing entries
+    /// start at the beginning of the log.
+    pub offsets: Vec<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogChunk {
+    pub process: usize,
+    pub lines: Vec<LogLine>,
+    /// Offset to be used for the next request
+    pub offset: u64,
+    /// Set if the log of this process could not be read
+    pub missing: Option<LogUnavailable>,
+}
+
+/// Reason why the log of a single process is missing from a response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LogUnavailable {
+    ExecutorUnreachable,
+    /// The executor failed to read the log file
+    Io(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum FetchLogsError {
+    QueryNotFound,
+}
+
+impl Request for FetchLogs {
+    type Success = Vec<LogChunk>;
+    type Error = FetchLogsError;
+
+    const NAME: &'static str = "FetchLogs";
+}
+
+/// Sent by an executor once a spawned query process has exited.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProcessExited {
+    pub query: QueryId,
+    pub executor: ExecutorId,
+    pub status: ProcessExit,
+}
+
+impl Request for ProcessExited {
+    type Success = ();
+    type Error = ();
+
+    const NAME: &'static str = "ProcessExited";
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AddExecutor {
     pub host: String,
     pub ports: (u16, u16),
     pub format: ExecutionFormat,
+    pub capacity: Capacity,
+    pub labels: Vec<(String, String)>,
+    /// Executor id assigned by a previous coordinator instance
+    pub reattach: Option<ExecutorId>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -63,6 +147,8 @@ pub struct QueryToken {
 pub struct AddWorkerGroup {
     pub query: QueryId,
     pub group: usize,
+    /// Token assigned by a previous coordinator instance
+    pub reattach: Option<QueryToken>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -70,6 +156,7 @@ pub enum WorkerGroupError {
     InvalidWorkerGroup,
     SpawningAborted,
     PeerFailed,
+    AuthenticationFailure,
 }
 
 impl Request for AddWorkerGroup {
@@ -84,12 +171,18 @@ pub struct Subscribe {
     pub name: String,
     pub blocking: bool,
     pub token: QueryToken,
+    /// Schema of the data expected by the subscriber
+    pub schema: TopicSchema,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum SubscribeError {
     TopicNotFound,
     AuthenticationFailure,
+    /// The expected schema cannot be decoded from the published data
+    IncompatibleSchema,
+    /// The ACL of the topic does not grant access to the query
+    PermissionDenied,
 }
 
 impl Request for Subscribe {
@@ -122,7 +215,13 @@ impl Request for Unsubscribe {
 pub struct Publish {
     pub name: String,
     pub addr: (String, u16),
+    /// Partition hosted at `addr` and the total number of partitions
+    pub partition: (usize, usize),
     pub schema: TopicSchema,
+    pub compatibility: Compatibility,
+    pub acl: Acl,
+    /// Secret subscribers have to present to the publisher of this partition
+    pub secret: u64,
     pub token: QueryToken,
 }
 
@@ -130,6 +229,15 @@ pub struct Publish {
 pub enum PublishError {
     TopicAlreadyExists,
     AuthenticationFailure,
+    InvalidSchema,
+    /// The partition does not match the ones registered before
+    PartitionMismatch,
+    /// The publisher of another partition went away before the topic
+    /// was complete
+    PartitionLost,
+    /// The ACL of a previous topic with the same name does not grant
+    /// access to the query
+    PermissionDenied,
 }
 
 impl Request for Publish {
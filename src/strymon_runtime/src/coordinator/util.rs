@@ -25,4 +25,11 @@ impl<T: From<u64>> Generator<T> {
     pub fn generate(&mut self) -> T {
         From::from(self.generator.next().unwrap())
     }
+
+    /// Ensures that `id` will never be generated again by this generator.
+    pub fn advance_past(&mut self, id: u64) {
+        if self.generator.start <= id {
+            self.generator = (id + 1)..;
+        }
+    }
 }
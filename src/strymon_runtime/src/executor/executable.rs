@@ -11,15 +11,19 @@ use std::num;
 use std::process::{Command, Stdio};
 use std::ffi::OsStr;
 use std::fmt::{Write, Display};
-use std::io::{BufReader};
+use std::io::{self, BufReader};
+use std::process::ExitStatus;
 
-use futures::{Future, Stream};
+use futures::{self, Future, Stream};
+use futures::future::Either;
+use futures::unsync::oneshot;
 use tokio_io;
 use tokio_core::reactor::Handle;
 use tokio_process::CommandExt;
 
-use model::QueryId;
+use model::{QueryId, ProcessExit, LogStream};
 use executor::requests::SpawnError;
+use executor::logs::LogWriter;
 
 pub const QUERY_ID: &'static str = "TIMELY_EXEC_CONF_QUERY_ID";
 pub const THREADS: &'static str = "TIMELY_EXEC_CONF_THREADS";
@@ -80,6 +84,8 @@ pub struct Builder {
     // strymon config
     coord: Option<String>,
     hostname: Option<String>,
+    // output capture
+    log: Option<LogWriter>,
 }
 
 impl Builder {
@@ -96,6 +102,7 @@ impl Builder {
             hostlist: None,
             coord: None,
             hostname: None,
+            log: None,
         }
     }
 
@@ -137,12 +144,20 @@ impl Builder {
         self
     }
 
-    /// Spawns the given command on the given event
-    pub fn spawn(mut self, id: QueryId, handle: &Handle) -> Result<(), SpawnError> {
+    /// Capture the output of the child in the given log (default: none)
+    pub fn log(&mut self, log: LogWriter) -> &mut Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Spawns the given command on the given event loop. Returns a handle
+    /// which can be used to terminate the spawned process, and a future
+    /// which resolves to the exit status of the process.
+    pub fn spawn(mut self, id: QueryId, handle: &Handle) -> Result<(ProcessHandle, Exited), SpawnError> {
         let mut child = self.cmd
             .env(QUERY_ID, id.0.to_string())
             .env(THREADS, self.threads.unwrap_or(1).to_string())
-            .env(PROCESS, self.threads.unwrap_or(0).to_string())
+            .env(PROCESS, self.process.unwrap_or(0).to_string())
             .env(HOSTLIST, self.hostlist.expect("missing hostname"))
             .env(COORD, self.coord.expect("missing coordinator"))
             .env(HOST, self.hostname.expect("missing external hostname"))
@@ -159,8 +174,12 @@ impl Builder {
             error!("failed to read stdout: {}", err)
         });
 
+        let log = self.log.clone();
         handle.spawn(lines.for_each(move |line| {
-            Ok(info!("{:?} | {}", id, line))
+            info!("{:?} | {}", id, line);
+            Ok(if let Some(ref log) = log {
+                log.write(LogStream::Stdout, line)
+            })
         }));
 
         let stderr = child.stderr().take().unwrap();
@@ -169,19 +188,76 @@ impl Builder {
             error!("failed to read stderr: {}", err)
         });
 
+        let log = self.log;
         handle.spawn(lines.for_each(move |line| {
-            Ok(warn!("{:?} | {}", id, line))
+            warn!("{:?} | {}", id, line);
+            Ok(if let Some(ref log) = log {
+                log.write(LogStream::Stderr, line)
+            })
         }));
 
-        // wait for child to finish
-        handle.spawn(child.then(|result| {
+        // wait for child to finish, or kill it if requested
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let child = child.select2(kill_rx).then(|result| -> Box<Future<Item=ExitStatus, Error=io::Error>> {
             match result {
-                Ok(code) if code.success() => Ok(()),
-                Ok(code) => Ok(warn!("child exited with non-zero code: {:?}", code.code())),
-                Err(err) => Err(error!("failed to wait for child: {}", err)),
+                Ok(Either::A((status, _))) => Box::new(futures::finished(status)),
+                Err(Either::A((err, _))) => Box::new(futures::failed(err)),
+                Ok(Either::B(((), mut child))) => {
+                    info!("killing child process of {:?}", id);
+                    if let Err(err) = child.kill() {
+                        error!("failed to kill child: {}", err);
+                    }
+                    Box::new(child)
+                }
+                // the process handle was dropped, just keep waiting
+                Err(Either::B((_, child))) => Box::new(child),
             }
-        }));
+        });
+
+        let exited = child.map(|status| {
+            if !status.success() {
+                warn!("child exited with non-zero code: {:?}", status.code());
+            }
+            exit_status(status)
+        });
+
+        Ok((ProcessHandle { kill: kill_tx }, Box::new(exited)))
+    }
+}
+
+/// Resolves once the spawned process has exited.
+pub type Exited = Box<Future<Item=ProcessExit, Error=io::Error>>;
+
+#[cfg(unix)]
+fn exit_status(status: ExitStatus) -> ProcessExit {
+    use std::os::unix::process::ExitStatusExt;
+    match (status.code(), status.signal()) {
+        (Some(code), _) => ProcessExit::Code(code),
+        (None, Some(signal)) => ProcessExit::Signal(signal),
+        // one of them is always set on Unix
+        (None, None) => unreachable!("exit status without code or signal"),
+    }
+}
+
+#[cfg(not(unix))]
+fn exit_status(status: ExitStatus) -> ProcessExit {
+    ProcessExit::Code(status.code().expect("missing exit code"))
+}
+
+/// A handle to a spawned child process.
+#[derive(Debug)]
+pub struct ProcessHandle {
+    kill: oneshot::Sender<()>,
+}
+
+impl ProcessHandle {
+    /// Returns `true` if the child process has already exited.
+    pub fn has_exited(&self) -> bool {
+        self.kill.is_canceled()
+    }
 
-        Ok(())
+    /// Kills the child process, returns `false` if it has already exited.
+    pub fn kill(self) -> bool {
+        self.kill.send(()).is_ok()
     }
 }
@@ -0,0 +1,125 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Per-process log files of spawned queries. Each line of a log file is
+//! a JSON encoded `LogLine`, so that the stream name can be recovered when
+//! the log is served back to clients.
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use serde_json;
+
+use model::{LogLine, LogStream, QueryId};
+
+/// Maximum number of bytes returned by a single call to `read`.
+const MAX_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Returns the path of the log file for the given process of a query.
+pub fn path(dir: &Path, query: QueryId, process: usize) -> PathBuf {
+    dir.join(format!("query_{}_{}.log", query.0, process))
+}
+
+/// Removes all query logs in `dir` which have not been modified for longer
+/// than `max_age`, except for the ones listed in `keep`. Returns the number
+/// of removed files.
+pub fn prune(dir: &Path, max_age: Duration, keep: &HashSet<PathBuf>) -> io::Result<usize> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_log = path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with("query_") && name.ends_with(".log"))
+            .unwrap_or(false);
+        if !is_log || keep.contains(&path) {
+            continue;
+        }
+
+        let modified = fs::metadata(&path)?.modified()?;
+        let expired = match now.duration_since(modified) {
+            Ok(age) => age > max_age,
+            // modified in the future, probably due to clock adjustments
+            Err(_) => false,
+        };
+        if expired {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// A shared handle for appending lines to a log file.
+#[derive(Clone)]
+pub struct LogWriter {
+    file: Rc<RefCell<File>>,
+}
+
+impl LogWriter {
+    /// Creates a new log file at `path`. An existing log at the same path,
+    /// e.g. left behind by an earlier query with the same id, is truncated.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
+        Ok(LogWriter { file: Rc::new(RefCell::new(file)) })
+    }
+
+    pub fn write(&self, stream: LogStream, line: String) {
+        let line = LogLine {
+            stream: stream,
+            line: line,
+        };
+
+        let mut file = self.file.borrow_mut();
+        let res = serde_json::to_writer(&mut *file, &line)
+            .map_err(|err| io::Error::new(ErrorKind::Other, err))
+            .and_then(|_| file.write_all(b"\n"));
+
+        if let Err(err) = res {
+            error!("failed to write to query log: {}", err);
+        }
+    }
+}
+
+/// Reads complete lines from the log file starting at byte `offset`.
+/// Returns the lines together with the offset of the next unread line.
+pub fn read(path: &Path, offset: u64) -> io::Result<(Vec<LogLine>, u64)> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut reader = BufReader::new(file);
+    let mut lines = Vec::new();
+    let mut read = 0;
+    let mut buf = String::new();
+    while read < MAX_CHUNK_SIZE {
+        buf.clear();
+        let len = reader.read_line(&mut buf)?;
+        // stop at the end of the file or at a partially written line
+        if len == 0 || !buf.ends_with('\n') {
+            break;
+        }
+
+        read += len as u64;
+        match serde_json::from_str(buf.trim_right()) {
+            Ok(line) => lines.push(line),
+            Err(err) => warn!("skipping malformed log line: {}", err),
+        }
+    }
+
+    Ok((lines, offset + read))
+}
@@ -7,24 +7,74 @@
 // except according to those terms.
 
 use std::io::{Error, ErrorKind};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
 
 use futures;
-use futures::future::Future;
+use futures::future::{Either, Future};
 use futures::stream::Stream;
-use tokio_core::reactor::{Core, Handle};
+use tokio_core::reactor::{Core, Handle, Timeout};
 
 use strymon_communication::Network;
-use strymon_communication::rpc::RequestBuf;
+use strymon_communication::rpc::{Outgoing, RequestBuf};
 
 use model::*;
 
 use coordinator::requests::*;
 use executor::requests::*;
+use executor::executable::ProcessHandle;
+use executor::logs::LogWriter;
 
 pub mod requests;
 pub mod executable;
+pub mod logs;
+
+/// Spawned processes, which outlive a single connection to the coordinator.
+/// Exit statuses are reported over the current connection, or once the
+/// executor has reconnected.
+#[derive(Default)]
+pub struct Processes {
+    /// Spawned processes by query and process index
+    running: HashMap<(QueryId, usize), ProcessHandle>,
+    coordinator: Option<Outgoing>,
+    /// Exit statuses of processes which exited while disconnected
+    unreported: Vec<ProcessExited>,
+}
+
+impl Processes {
+    fn attach(&mut self, tx: Outgoing, handle: &Handle) {
+        for exited in self.unreported.drain(..) {
+            report_exit(&tx, exited, handle);
+        }
+        self.coordinator = Some(tx);
+    }
+
+    fn detach(&mut self) {
+        self.coordinator = None;
+    }
+
+    fn exited(&mut self, exited: ProcessExited, handle: &Handle) {
+        match self.coordinator {
+            Some(ref tx) => report_exit(tx, exited, handle),
+            None => self.unreported.push(exited),
+        }
+    }
+}
+
+fn report_exit(tx: &Outgoing, exited: ProcessExited, handle: &Handle) {
+    let query = exited.query;
+    let response = tx.request(&exited).then(move |res| {
+        if res.is_err() {
+            warn!("failed to report exit status of {:?}", query);
+        }
+        Ok(())
+    });
+    handle.spawn(response);
+}
 
 pub struct ExecutorService {
     id: ExecutorId,
@@ -32,16 +82,29 @@ pub struct ExecutorService {
     host: String,
     network: Network,
     handle: Handle,
+    processes: Rc<RefCell<Processes>>,
+    log_dir: PathBuf,
+    log_retention: Duration,
 }
 
 impl ExecutorService {
-    pub fn new(id: ExecutorId, coord: String, network: Network, handle: Handle) -> Self {
+    pub fn new(id: ExecutorId,
+               coord: String,
+               network: Network,
+               handle: Handle,
+               processes: Rc<RefCell<Processes>>,
+               log_dir: PathBuf,
+               log_retention: Duration)
+               -> Self {
         ExecutorService {
             id: id,
             coord: coord,
             host: network.hostname(),
             network: network,
             handle: handle,
+            processes: processes,
+            log_dir: log_dir,
+            log_retention: log_retention,
         }
     }
 
@@ -64,33 +127,154 @@ impl ExecutorService {
         }
     }
 
-    fn spawn(&mut self, query: Query, hostlist: Vec<String>) -> Result<(), SpawnError> {
-        let process = query.executors
-            .iter()
-            .position(|&id| self.id == id)
-            .ok_or(SpawnError::InvalidRequest)?;
-        let threads = query.workers / hostlist.len();
+    fn spawn(&mut self,
+             query: Query,
+             hostlist: Vec<String>,
+             threads: Vec<usize>,
+             process: usize)
+             -> Result<(), SpawnError> {
+        if query.executors.get(process) != Some(&self.id) {
+            return Err(SpawnError::InvalidRequest);
+        }
+        if threads.len() != hostlist.len() || process >= threads.len() {
+            return Err(SpawnError::InvalidRequest);
+        }
+
+        // forget about processes which have exited in the meantime
+        {
+            let mut processes = self.processes.borrow_mut();
+            let running = &mut processes.running;
+            running.retain(|_, child| !child.has_exited());
+            // a running process would otherwise share its log with the new one
+            if running.contains_key(&(query.id, process)) {
+                return Err(SpawnError::InvalidRequest);
+            }
+        }
+
         let executable = self.fetch(&query.program.source)?;
         let args = &*query.program.args;
         let id = query.id;
 
         let mut exec = executable::Builder::new(&executable, args);
 
-        exec.threads(threads)
+        exec.threads(threads[process])
             .process(process)
             .hostlist(&hostlist)
             .hostname(&self.host)
             .coord(&self.coord);
 
-        exec.spawn(id, &self.handle)
+        self.prune_logs();
+
+        // a missing log is not a reason to refuse spawning the query
+        match LogWriter::create(&logs::path(&self.log_dir, id, process)) {
+            Ok(log) => {
+                exec.log(log);
+            }
+            Err(err) => warn!("failed to create log file for {:?}: {}", id, err),
+        }
+
+        let (child, exited) = exec.spawn(id, &self.handle)?;
+
+        // report the exit status back to the coordinator
+        let processes = self.processes.clone();
+        let handle = self.handle.clone();
+        let executor = self.id;
+        let report = exited
+            .map_err(|err| error!("failed to wait for child: {}", err))
+            .map(move |status| {
+                let exited = ProcessExited {
+                    query: id,
+                    executor: executor,
+                    status: status,
+                };
+                processes.borrow_mut().exited(exited, &handle);
+            });
+        self.handle.spawn(report);
+        self.processes.borrow_mut().running.insert((id, process), child);
+
+        Ok(())
+    }
+
+    /// Kills all processes of a query hosted by this executor.
+    fn kill(&mut self, query: QueryId) -> Result<(), KillError> {
+        let mut processes = self.processes.borrow_mut();
+        let running = &mut processes.running;
+        let keys: Vec<(QueryId, usize)> = running.keys()
+            .filter(|&&(id, _)| id == query)
+            .cloned()
+            .collect();
+
+        let mut killed = false;
+        for key in keys {
+            if let Some(process) = running.remove(&key) {
+                killed |= process.kill();
+            }
+        }
+
+        if killed {
+            Ok(())
+        } else {
+            Err(KillError::NotFound)
+        }
+    }
+
+    /// Removes expired logs, keeping the ones of processes still running.
+    fn prune_logs(&self) {
+        let running: HashSet<PathBuf> = self.processes
+            .borrow()
+            .running
+            .keys()
+            .map(|&(query, process)| logs::path(&self.log_dir, query, process))
+            .collect();
+
+        match logs::prune(&self.log_dir, self.log_retention, &running) {
+            Ok(0) => (),
+            Ok(removed) => debug!("removed {} expired query logs", removed),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => warn!("failed to remove expired query logs: {}", err),
+        }
+    }
+
+    fn read_log(&self,
+                query: QueryId,
+                process: usize,
+                offset: u64)
+                -> Result<(Vec<LogLine>, u64), ReadLogError> {
+        match logs::read(&logs::path(&self.log_dir, query, process), offset) {
+            Ok(chunk) => Ok(chunk),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                Err(ReadLogError::NotFound)
+            }
+            Err(err) => {
+                error!("failed to read log of {:?}: {}", query, err);
+                Err(ReadLogError::Io(err.to_string()))
+            }
+        }
     }
 
     pub fn dispatch(&mut self, req: RequestBuf) -> Result<(), Error> {
         match req.name() {
             "SpawnQuery" => {
-                let (SpawnQuery { query, hostlist }, resp) = req.decode::<SpawnQuery>()?;
+                let (SpawnQuery { query, hostlist, threads, process }, resp) =
+                    req.decode::<SpawnQuery>()?;
                 debug!("got spawn request for {:?}", query);
-                resp.respond(self.spawn(query, hostlist));
+                resp.respond(self.spawn(query, hostlist, threads, process));
+                Ok(())
+            }
+            "Heartbeat" => {
+                let (Heartbeat, resp) = req.decode::<Heartbeat>()?;
+                resp.respond(Ok(()));
+                Ok(())
+            }
+            "KillQuery" => {
+                let (KillQuery { query }, resp) = req.decode::<KillQuery>()?;
+                debug!("got kill request for {:?}", query);
+                resp.respond(self.kill(query));
+                Ok(())
+            }
+            "ReadLog" => {
+                let (ReadLog { query, process, offset }, resp) = req.decode::<ReadLog>()?;
+                resp.respond(self.read_log(query, process, offset));
                 Ok(())
             }
             _ => {
@@ -104,6 +288,10 @@ impl ExecutorService {
 pub struct Builder {
     coord: String,
     ports: (u16, u16),
+    log_dir: PathBuf,
+    log_retention: Duration,
+    capacity: Capacity,
+    labels: Vec<(String, String)>,
 }
 
 impl Builder {
@@ -118,6 +306,33 @@ impl Builder {
     pub fn ports(&mut self, min: u16, max: u16) {
         self.ports = (min, max);
     }
+
+    /// Directory in which the output of spawned queries is stored.
+    pub fn log_dir(&mut self, dir: PathBuf) {
+        self.log_dir = dir;
+    }
+
+    /// Logs of finished queries are removed once they have not been
+    /// written to for longer than `retention`.
+    pub fn log_retention(&mut self, retention: Duration) {
+        self.log_retention = retention;
+    }
+
+    /// Number of cores advertised to the coordinator for worker placement.
+    pub fn cores(&mut self, cores: usize) {
+        self.capacity.cores = cores;
+    }
+
+    /// Amount of memory in megabytes advertised to the coordinator.
+    pub fn memory(&mut self, memory: u64) {
+        self.capacity.memory = memory;
+    }
+
+    /// Attaches a label to this executor, used by constraint-based placement.
+    pub fn label(&mut self, key: String, value: String) {
+        self.labels.retain(|&(ref k, _)| *k != key);
+        self.labels.push((key, value));
+    }
 }
 
 impl Default for Builder {
@@ -125,6 +340,10 @@ impl Default for Builder {
         Builder {
             coord: String::from("localhost:9189"),
             ports: (2101, 4101),
+            log_dir: env::temp_dir().join("strymon_logs"),
+            log_retention: Duration::from_secs(DEFAULT_LOG_RETENTION_SECS),
+            capacity: Capacity::default(),
+            labels: Vec::new(),
         }
     }
 }
@@ -146,40 +365,110 @@ fn setup_termination_handler(handle: &Handle) -> Box<Future<Item=(), Error=Error
     Box::new(future::empty())
 }
 
+/// Delay between two attempts to reconnect to a lost coordinator
+const RECONNECT_INTERVAL_SECS: u64 = 5;
+
+/// Logs of finished queries are kept for a week by default
+const DEFAULT_LOG_RETENTION_SECS: u64 = 7 * 24 * 60 * 60;
+
 impl Builder {
     pub fn start(self) -> Result<(), Error> {
-        let Builder { ports, coord } = self;
+        let Builder { ports, coord, log_dir, log_retention, capacity, labels } = self;
         let network = Network::init()?;
         let host = network.hostname();
-        let (tx, rx) = network.client(&*coord)?;
 
         let mut core = Core::new()?;
         let handle = core.handle();
 
         // define a signal handler for clean shutdown
-        let sigterm = setup_termination_handler(&handle);
-
-        // define main executor loop
-        let service = futures::lazy(move || {
-            // announce ourselves at the coordinator
-            let id = tx.request(&AddExecutor {
-                    host: host,
-                    ports: ports,
-                    format: ExecutionFormat::NativeExecutable,
-                })
-                .map_err(|e| e.unwrap_err());
-
-            // once we get results, start the actual executor service
-            id.and_then(move |id| {
-                let mut executor = ExecutorService::new(id, coord, network, handle);
-                rx.for_each(move |req| executor.dispatch(req))
-            })
-        });
-
-        // terminate on whatever comes first: sigterm or service exits
-        core.run(service.select2(sigterm).then(|result| match result {
-            Ok(t) => Ok(t.split().0),
-            Err(e) => Err(e.split().0),
-        }))
+        let mut sigterm = setup_termination_handler(&handle);
+
+        // the id assigned by the coordinator, used to reattach after a restart
+        let assigned: Rc<Cell<Option<ExecutorId>>> = Rc::new(Cell::new(None));
+        let processes = Rc::new(RefCell::new(Processes::default()));
+
+        loop {
+            let connected = network.client(&*coord);
+            if let Ok((tx, rx)) = connected {
+                let (host, coord) = (host.clone(), coord.clone());
+                let (network, handle) = (network.clone(), handle.clone());
+                let registered = assigned.clone();
+                let processes = processes.clone();
+                let log_dir = log_dir.clone();
+                let labels = labels.clone();
+
+                // define main executor loop
+                let service = futures::lazy(move || {
+                    let coordinator = tx.clone();
+
+                    // announce ourselves at the coordinator
+                    let id = tx.request(&AddExecutor {
+                            host: host,
+                            ports: ports,
+                            format: ExecutionFormat::NativeExecutable,
+                            capacity: capacity,
+                            labels: labels,
+                            reattach: registered.get(),
+                        })
+                        .map_err(|e| e.unwrap_err());
+
+                    // once we get results, start the actual executor service
+                    id.and_then(move |id| {
+                        registered.set(Some(id));
+                        processes.borrow_mut().attach(coordinator, &handle);
+                        let mut executor = ExecutorService::new(id,
+                                                                coord,
+                                                                network,
+                                                                handle,
+                                                                processes,
+                                                                log_dir,
+                                                                log_retention);
+                        rx.for_each(move |req| executor.dispatch(req))
+                    })
+                });
+
+                // terminate on sigterm, reconnect if the coordinator went away
+                let registered = assigned.clone();
+                let terminated = core.run(service.select2(&mut sigterm).then(|result| {
+                    match result {
+                        Ok(Either::A(_)) => Ok(false),
+                        Err(Either::A((err, _))) => {
+                            if registered.get().is_none() {
+                                return Err(err);
+                            }
+                            warn!("lost connection to coordinator: {}", err);
+                            Ok(false)
+                        }
+                        Ok(Either::B(_)) => Ok(true),
+                        Err(Either::B((err, _))) => Err(err),
+                    }
+                }))?;
+
+                // exit statuses are kept until we have reconnected
+                processes.borrow_mut().detach();
+
+                if terminated {
+                    return Ok(());
+                }
+            } else if assigned.get().is_none() {
+                return connected.map(|_| ());
+            }
+
+            // wait a bit before trying to reattach to the coordinator
+            info!("reconnecting to coordinator at {:?}", coord);
+            let interval = Duration::from_secs(RECONNECT_INTERVAL_SECS);
+            let timeout = Timeout::new(interval, &handle)?;
+            let terminated = core.run(timeout.select2(&mut sigterm).then(|result| {
+                match result {
+                    Ok(Either::A(_)) => Ok(false),
+                    Ok(Either::B(_)) => Ok(true),
+                    Err(Either::A((err, _))) | Err(Either::B((err, _))) => Err(err),
+                }
+            }))?;
+
+            if terminated {
+                return Ok(());
+            }
+        }
     }
 }
@@ -13,6 +13,11 @@ use strymon_communication::rpc::Request;
 pub struct SpawnQuery {
     pub query: Query,
     pub hostlist: Vec<String>,
+    /// Number of workers of each process, in the order of `hostlist`
+    pub threads: Vec<usize>,
+    /// Index of the process to spawn, an executor can be selected for
+    /// several processes of the same query
+    pub process: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,3 +33,54 @@ impl Request for SpawnQuery {
 
     const NAME: &'static str = "SpawnQuery";
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KillQuery {
+    pub query: QueryId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KillError {
+    NotFound,
+}
+
+impl Request for KillQuery {
+    type Success = ();
+    type Error = KillError;
+
+    const NAME: &'static str = "KillQuery";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadLog {
+    pub query: QueryId,
+    /// Index of the query process whose log is read
+    pub process: usize,
+    /// Byte offset of the first line to read
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReadLogError {
+    NotFound,
+    /// The log exists, but could not be read
+    Io(String),
+}
+
+impl Request for ReadLog {
+    /// The read lines and the offset of the next unread line
+    type Success = (Vec<LogLine>, u64);
+    type Error = ReadLogError;
+
+    const NAME: &'static str = "ReadLog";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat;
+
+impl Request for Heartbeat {
+    type Success = ();
+    type Error = ();
+
+    const NAME: &'static str = "Heartbeat";
+}
@@ -6,8 +6,6 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-#![feature(core_intrinsics)]
-
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -19,12 +17,14 @@ extern crate tokio_process;
 
 extern crate timely;
 extern crate timely_communication;
+extern crate differential_dataflow;
 
 extern crate rand;
 
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 
 extern crate abomonation;
 #[macro_use]
@@ -7,7 +7,11 @@
 // except according to those terms.
 
 use std::fmt;
-use std::intrinsics::type_name;
+
+pub use self::schema::{Compatibility, Schema, TopicType};
+use self::schema::Projection;
+
+pub mod schema;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Abomonation)]
 pub struct TopicId(pub u64);
@@ -18,26 +22,13 @@ impl From<u64> for TopicId {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
-pub struct TopicType {
-    pub name: String,
-}
-
-impl TopicType {
-    pub fn of<T>() -> Self {
-        // TODO(swicki): This currently required unstable Rust, we really
-        // should either use NamedType instead or figure out if we can derive
-        // a schema from serde instead
-        TopicType {
-            name: unsafe { type_name::<T>() }.to_string(),
-        }
-    }
-}
-
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
 pub enum TopicSchema {
     Collection(TopicType),
     Stream(TopicType, TopicType),
+    /// A collection whose updates carry timestamps and frontier progress,
+    /// consisting of the item, timestamp and difference type
+    TimestampedCollection(TopicType, TopicType, TopicType),
 }
 
 impl TopicSchema {
@@ -54,25 +45,153 @@ impl TopicSchema {
             _ => false,
         }
     }
+
+    /// Checks if subscribers expecting `other` can decode this topic.
+    pub fn is_compatible(&self, other: &TopicSchema) -> bool {
+        match (self, other) {
+            (&TopicSchema::Collection(ref a), &TopicSchema::Collection(ref b)) => {
+                a.is_compatible(b)
+            }
+            (&TopicSchema::Stream(ref a, ref t), &TopicSchema::Stream(ref b, ref u)) => {
+                a.is_compatible(b) && t.is_compatible(u)
+            }
+            (&TopicSchema::TimestampedCollection(ref a, ref t, ref r),
+             &TopicSchema::TimestampedCollection(ref b, ref u, ref s)) => {
+                a.is_compatible(b) && t.is_compatible(u) && r.is_compatible(s)
+            }
+            _ => false,
+        }
+    }
+
+    /// Derives how published data is decoded by a subscriber expecting the
+    /// schema `expected`, following the given compatibility rules.
+    /// Timestamps and differences are never converted.
+    pub fn projection(&self, expected: &TopicSchema, mode: Compatibility) -> Option<Projection> {
+        match (self, expected) {
+            (&TopicSchema::Collection(ref w), &TopicSchema::Collection(ref r)) => {
+                Projection::new(r, w, mode)
+            }
+            (&TopicSchema::Stream(ref w, ref t), &TopicSchema::Stream(ref r, ref u)) => {
+                if u.is_compatible(t) {
+                    Projection::new(r, w, mode)
+                } else {
+                    None
+                }
+            }
+            (&TopicSchema::TimestampedCollection(ref w, ref t, ref d),
+             &TopicSchema::TimestampedCollection(ref r, ref u, ref e)) => {
+                if u.is_compatible(t) && e.is_compatible(d) {
+                    Projection::new(r, w, mode)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            TopicSchema::Collection(ref d) => d.is_valid(),
+            TopicSchema::Stream(ref d, ref t) => d.is_valid() && t.is_valid(),
+            TopicSchema::TimestampedCollection(ref d, ref t, ref r) => {
+                d.is_valid() && t.is_valid() && r.is_valid()
+            }
+        }
+    }
 }
 
 impl fmt::Display for TopicSchema {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            TopicSchema::Collection(ref d) => write!(f, "Collection(item={:?})", d.name),
+            TopicSchema::Collection(ref d) => write!(f, "Collection(item={})", d),
             TopicSchema::Stream(ref d, ref t) => {
-                write!(f, "Stream(timestamp={:?}, data={:?})", t.name, d.name)
+                write!(f, "Stream(timestamp={}, data={})", t, d)
+            }
+            TopicSchema::TimestampedCollection(ref d, ref t, ref r) => {
+                write!(f, "TimestampedCollection(timestamp={}, item={}, diff={})", t, d, r)
             }
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Abomonation)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
 pub struct Topic {
     pub id: TopicId,
     pub name: String,
-    pub addr: (String, u16),
+    /// Address of every partition, most topics consist of a single one
+    pub partitions: Vec<(String, u16)>,
     pub schema: TopicSchema,
+    pub compatibility: Compatibility,
+    pub acl: Acl,
+    /// Secret of every partition, presented by subscribers when connecting
+    /// to its publisher. Only sent to accepted subscribers, empty otherwise.
+    pub secrets: Vec<u64>,
+}
+
+impl Topic {
+    /// Returns the partitions read by subscriber worker `index` out of
+    /// `peers` workers. Topics with a single partition are read by every
+    /// worker.
+    pub fn assigned(&self, index: usize, peers: usize) -> Vec<usize> {
+        if self.partitions.len() == 1 {
+            vec![0]
+        } else {
+            (0..self.partitions.len()).filter(|p| p % peers == index).collect()
+        }
+    }
+}
+
+/// A query name or a user granted access to a topic. Both are chosen by
+/// the submitter of a query and are not verified by the coordinator.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub enum Principal {
+    Query(String),
+    /// All queries submitted by the given user
+    User(String),
+}
+
+impl Principal {
+    fn matches(&self, query: &Query) -> bool {
+        match *self {
+            Principal::Query(ref name) => query.name.as_ref() == Some(name),
+            Principal::User(ref user) => query.user.as_ref() == Some(user),
+        }
+    }
+}
+
+/// Restricts which queries may subscribe to a topic, and which queries may
+/// publish a topic under the same name once it has been unpublished. A list
+/// of `None` grants access to all queries.
+///
+/// ACLs are advisory only: they protect against mistakes of cooperating
+/// users, not against malicious ones. Query names and users are asserted by
+/// the submitting client, so anyone able to submit a query can impersonate
+/// any principal.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub struct Acl {
+    pub subscribe: Option<Vec<Principal>>,
+    pub publish: Option<Vec<Principal>>,
+}
+
+impl Acl {
+    /// Checks if `query` may subscribe, unknown queries may only subscribe
+    /// to unrestricted topics.
+    pub fn may_subscribe(&self, query: Option<&Query>) -> bool {
+        grants(&self.subscribe, query)
+    }
+
+    pub fn may_publish(&self, query: Option<&Query>) -> bool {
+        grants(&self.publish, query)
+    }
+}
+
+fn grants(list: &Option<Vec<Principal>>, query: Option<&Query>) -> bool {
+    match (list.as_ref(), query) {
+        (None, _) => true,
+        (Some(list), Some(query)) => list.iter().any(|p| p.matches(query)),
+        (Some(_), None) => false,
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Abomonation)]
@@ -84,23 +203,62 @@ impl From<u64> for QueryId {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Abomonation)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
 pub struct Query {
     pub id: QueryId,
     pub name: Option<String>,
+    /// User who submitted the query, as claimed by the submitting client
+    pub user: Option<String>,
     pub program: QueryProgram,
     pub workers: usize, // in total
     pub executors: Vec<ExecutorId>,
+    pub status: QueryStatus,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Abomonation)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub enum QueryStatus {
+    /// Waiting for all worker groups to register
+    Spawning,
+    Running,
+    /// All processes exited successfully
+    Finished,
+    /// A process exited unsuccessfully, or the exit status is unknown
+    /// because its executor was lost
+    Failed(Option<ProcessExit>),
+}
+
+impl QueryStatus {
+    /// Returns true if all processes of the query have exited, or the query
+    /// failed.
+    pub fn is_completed(&self) -> bool {
+        match *self {
+            QueryStatus::Finished | QueryStatus::Failed(_) => true,
+            QueryStatus::Spawning | QueryStatus::Running => false,
+        }
+    }
+}
+
+/// Exit status of a spawned query process.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub enum ProcessExit {
+    Code(i32),
+    Signal(i32),
+}
+
+impl ProcessExit {
+    pub fn success(&self) -> bool {
+        *self == ProcessExit::Code(0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
 pub struct QueryProgram {
     pub format: ExecutionFormat,
     pub source: String, // TODO(swicki) use Url crate for this?
     pub args: Vec<String>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Abomonation)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
 pub enum ExecutionFormat {
     NativeExecutable,
     Other,
@@ -115,11 +273,40 @@ impl From<u64> for ExecutorId {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Abomonation)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
 pub struct Executor {
     pub id: ExecutorId,
     pub host: String,
     pub format: ExecutionFormat,
+    /// Time of the last answered heartbeat, in seconds since the Unix epoch,
+    /// only refreshed when the health of the executor changes
+    pub last_seen: u64,
+    pub health: ExecutorHealth,
+    pub capacity: Capacity,
+    /// Key/value pairs describing the executor, e.g. `("rack", "a")`
+    pub labels: Vec<(String, String)>,
+}
+
+impl Executor {
+    /// Returns the value of the given label, if set.
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| &**v)
+    }
+}
+
+/// Resources advertised by an executor, zero if unknown.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub struct Capacity {
+    pub cores: usize,
+    /// Main memory in megabytes
+    pub memory: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub enum ExecutorHealth {
+    Healthy,
+    /// The executor has not answered the given number of heartbeats
+    Unresponsive(u32),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Abomonation)]
@@ -131,7 +318,7 @@ pub struct Subscription(pub QueryId, pub TopicId);
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Abomonation)]
 pub struct KeeperId(pub u64);
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Abomonation)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
 pub struct Keeper {
     pub id: KeeperId,
     pub name: String,
@@ -139,8 +326,77 @@ pub struct Keeper {
     pub workers: Vec<(usize, (String, u16))>,
 }
 
+/// A single line of output of a query process.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Abomonation)]
+pub struct LogLine {
+    pub stream: LogStream,
+    pub line: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Abomonation)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
 impl From<u64> for KeeperId {
     fn from(id: u64) -> KeeperId {
         KeeperId(id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, user: &str) -> Query {
+        Query {
+            id: QueryId(0),
+            name: Some(name.to_string()),
+            user: Some(user.to_string()),
+            program: QueryProgram {
+                format: ExecutionFormat::NativeExecutable,
+                source: String::from("query"),
+                args: vec![],
+            },
+            workers: 1,
+            executors: vec![],
+            status: QueryStatus::Running,
+        }
+    }
+
+    #[test]
+    fn unrestricted_acl() {
+        let acl = Acl::default();
+        assert!(acl.may_subscribe(None));
+        assert!(acl.may_publish(Some(&query("q", "alice"))));
+    }
+
+    #[test]
+    fn restricted_acl() {
+        let acl = Acl {
+            subscribe: Some(vec![Principal::User("alice".into())]),
+            publish: Some(vec![Principal::Query("q".into())]),
+        };
+
+        assert!(acl.may_subscribe(Some(&query("other", "alice"))));
+        assert!(!acl.may_subscribe(Some(&query("q", "bob"))));
+        assert!(acl.may_publish(Some(&query("q", "bob"))));
+        assert!(!acl.may_publish(Some(&query("other", "alice"))));
+
+        // unknown queries are only granted unrestricted access
+        assert!(!acl.may_subscribe(None));
+        assert!(!acl.may_publish(None));
+    }
+
+    #[test]
+    fn empty_acl_denies_all() {
+        let acl = Acl {
+            subscribe: Some(vec![]),
+            publish: Some(vec![]),
+        };
+
+        assert!(!acl.may_subscribe(Some(&query("q", "alice"))));
+        assert!(!acl.may_publish(Some(&query("q", "alice"))));
+    }
+}
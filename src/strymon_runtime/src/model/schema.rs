@@ -0,0 +1,1240 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Structural descriptions of the data exchanged over topics.
+//!
+//! Schemas are derived from serde's data model: the `Deserialize`
+//! implementation of a type is driven by a tracing deserializer, which
+//! records the shape of every value it is asked to produce. Enums are
+//! traced repeatedly until each of their variants has been observed.
+//!
+//! Types which require a self-describing format (i.e. they call
+//! `deserialize_any`) and recursive types are not supported.
+//!
+//! Depending on the `Compatibility` mode of a topic, subscribers may expect
+//! a different schema than the one of the publisher. The `Projection`
+//! between the two schemas describes how published values are rearranged
+//! for such subscribers.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess,
+                IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
+
+/// Upper bound on the number of passes needed to observe all enum variants.
+const MAX_TRACES: usize = 1024;
+
+/// A single node of a structural type description. Nested types are
+/// referred to by their index in `TopicType::nodes`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub enum Shape {
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
+    Char,
+    Str,
+    Bytes,
+    Unit,
+    Option(usize),
+    Seq(usize),
+    Map(usize, usize),
+    Tuple(Vec<usize>),
+    UnitStruct(String),
+    NewtypeStruct(String, usize),
+    TupleStruct(String, Vec<usize>),
+    Struct(String, Vec<(String, usize)>),
+    Enum(String, Vec<(String, Variant)>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub enum Variant {
+    Unit,
+    Newtype(usize),
+    Tuple(Vec<usize>),
+    Struct(Vec<(String, usize)>),
+}
+
+impl Shape {
+    /// Returns a copy of this shape with all references replaced by `f`.
+    fn map<F: FnMut(usize) -> usize>(&self, f: &mut F) -> Shape {
+        match *self {
+            Shape::Option(x) => Shape::Option(f(x)),
+            Shape::Seq(x) => Shape::Seq(f(x)),
+            Shape::Map(k, v) => {
+                let k = f(k);
+                Shape::Map(k, f(v))
+            }
+            Shape::Tuple(ref xs) => Shape::Tuple(xs.iter().map(|&x| f(x)).collect()),
+            Shape::NewtypeStruct(ref n, x) => Shape::NewtypeStruct(n.clone(), f(x)),
+            Shape::TupleStruct(ref n, ref xs) => {
+                Shape::TupleStruct(n.clone(), xs.iter().map(|&x| f(x)).collect())
+            }
+            Shape::Struct(ref n, ref fields) => {
+                Shape::Struct(n.clone(),
+                              fields.iter().map(|&(ref name, x)| (name.clone(), f(x))).collect())
+            }
+            Shape::Enum(ref n, ref variants) => {
+                Shape::Enum(n.clone(),
+                            variants.iter().map(|&(ref name, ref v)| (name.clone(), v.map(&mut *f))).collect())
+            }
+            ref shape => shape.clone(),
+        }
+    }
+
+    /// Returns all nodes referred to by this shape.
+    fn children(&self) -> Vec<usize> {
+        let mut children = Vec::new();
+        self.map(&mut |x| {
+            children.push(x);
+            x
+        });
+        children
+    }
+}
+
+impl Variant {
+    fn map<F: FnMut(usize) -> usize>(&self, f: &mut F) -> Variant {
+        match *self {
+            Variant::Unit => Variant::Unit,
+            Variant::Newtype(x) => Variant::Newtype(f(x)),
+            Variant::Tuple(ref xs) => Variant::Tuple(xs.iter().map(|&x| f(x)).collect()),
+            Variant::Struct(ref fields) => {
+                Variant::Struct(fields.iter().map(|&(ref name, x)| (name.clone(), f(x))).collect())
+            }
+        }
+    }
+}
+
+/// The structural description of a type, as a tree of shapes rooted at the
+/// first node.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub struct TopicType {
+    pub nodes: Vec<Shape>,
+}
+
+impl TopicType {
+    /// Checks if the encoding of `other` matches the encoding of `self`.
+    /// Only the structure is compared, names of structs and enums are
+    /// ignored, while field and variant names must match.
+    pub fn is_compatible(&self, other: &TopicType) -> bool {
+        self.is_valid() && other.is_valid() && self.compare(0, other, 0)
+    }
+
+    /// Checks that the description forms a tree, i.e. every node only refers
+    /// to nodes which come after it.
+    pub fn is_valid(&self) -> bool {
+        !self.nodes.is_empty() &&
+        self.nodes
+            .iter()
+            .enumerate()
+            .all(|(i, shape)| shape.children().iter().all(|&c| c > i && c < self.nodes.len()))
+    }
+
+    /// Returns the description of the tuple element at `index`, if this
+    /// describes a tuple.
+    pub fn element(&self, index: usize) -> Option<TopicType> {
+        match self.nodes.first() {
+            Some(&Shape::Tuple(ref xs)) => {
+                xs.get(index).map(|&x| {
+                    let mut nodes = Vec::new();
+                    self.copy(x, &mut nodes);
+                    TopicType { nodes: nodes }
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Copies the subtree rooted at `node` into `out`.
+    fn copy(&self, node: usize, out: &mut Vec<Shape>) -> usize {
+        let pos = out.len();
+        out.push(Shape::Unit);
+        let shape = self.nodes[node].map(&mut |x| self.copy(x, out));
+        out[pos] = shape;
+        pos
+    }
+
+    fn compare_all(&self, xs: &[usize], other: &TopicType, ys: &[usize]) -> bool {
+        xs.len() == ys.len() && xs.iter().zip(ys).all(|(&x, &y)| self.compare(x, other, y))
+    }
+
+    fn compare_fields(&self,
+                      xs: &[(String, usize)],
+                      other: &TopicType,
+                      ys: &[(String, usize)])
+                      -> bool {
+        xs.len() == ys.len() &&
+        xs.iter().zip(ys).all(|(&(ref n, x), &(ref m, y))| n == m && self.compare(x, other, y))
+    }
+
+    fn compare_variants(&self, x: &Variant, other: &TopicType, y: &Variant) -> bool {
+        match (x, y) {
+            (&Variant::Unit, &Variant::Unit) => true,
+            (&Variant::Newtype(x), &Variant::Newtype(y)) => self.compare(x, other, y),
+            (&Variant::Tuple(ref xs), &Variant::Tuple(ref ys)) => self.compare_all(xs, other, ys),
+            (&Variant::Struct(ref xs), &Variant::Struct(ref ys)) => {
+                self.compare_fields(xs, other, ys)
+            }
+            _ => false,
+        }
+    }
+
+    fn compare(&self, a: usize, other: &TopicType, b: usize) -> bool {
+        match (&self.nodes[a], &other.nodes[b]) {
+            (&Shape::Option(x), &Shape::Option(y)) |
+            (&Shape::Seq(x), &Shape::Seq(y)) |
+            (&Shape::NewtypeStruct(_, x), &Shape::NewtypeStruct(_, y)) => self.compare(x, other, y),
+            (&Shape::Map(k1, v1), &Shape::Map(k2, v2)) => {
+                self.compare(k1, other, k2) && self.compare(v1, other, v2)
+            }
+            (&Shape::Tuple(ref xs), &Shape::Tuple(ref ys)) |
+            (&Shape::TupleStruct(_, ref xs), &Shape::TupleStruct(_, ref ys)) => {
+                self.compare_all(xs, other, ys)
+            }
+            (&Shape::Struct(_, ref xs), &Shape::Struct(_, ref ys)) => {
+                self.compare_fields(xs, other, ys)
+            }
+            (&Shape::UnitStruct(_), &Shape::UnitStruct(_)) => true,
+            (&Shape::Enum(_, ref xs), &Shape::Enum(_, ref ys)) => {
+                xs.len() == ys.len() &&
+                xs.iter().zip(ys).all(|(&(ref n, ref x), &(ref m, ref y))| {
+                    n == m && self.compare_variants(x, other, y)
+                })
+            }
+            (x, y) => x == y,
+        }
+    }
+
+    fn write_list(&self, f: &mut fmt::Formatter, xs: &[usize]) -> fmt::Result {
+        for (i, &x) in xs.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            self.write(f, x)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, node: usize) -> fmt::Result {
+        let shape = match self.nodes.get(node) {
+            Some(shape) => shape,
+            None => return write!(f, "?"),
+        };
+
+        match *shape {
+            Shape::Bool => write!(f, "bool"),
+            Shape::I8 => write!(f, "i8"),
+            Shape::I16 => write!(f, "i16"),
+            Shape::I32 => write!(f, "i32"),
+            Shape::I64 => write!(f, "i64"),
+            Shape::U8 => write!(f, "u8"),
+            Shape::U16 => write!(f, "u16"),
+            Shape::U32 => write!(f, "u32"),
+            Shape::U64 => write!(f, "u64"),
+            Shape::F32 => write!(f, "f32"),
+            Shape::F64 => write!(f, "f64"),
+            Shape::Char => write!(f, "char"),
+            Shape::Str => write!(f, "String"),
+            Shape::Bytes => write!(f, "Bytes"),
+            Shape::Unit => write!(f, "()"),
+            Shape::Option(x) => {
+                write!(f, "Option<")?;
+                self.write(f, x)?;
+                write!(f, ">")
+            }
+            Shape::Seq(x) => {
+                write!(f, "Vec<")?;
+                self.write(f, x)?;
+                write!(f, ">")
+            }
+            Shape::Map(k, v) => {
+                write!(f, "Map<")?;
+                self.write(f, k)?;
+                write!(f, ", ")?;
+                self.write(f, v)?;
+                write!(f, ">")
+            }
+            Shape::Tuple(ref xs) => {
+                write!(f, "(")?;
+                self.write_list(f, xs)?;
+                write!(f, ")")
+            }
+            Shape::UnitStruct(ref name) |
+            Shape::Enum(ref name, _) => write!(f, "{}", name),
+            Shape::NewtypeStruct(ref name, x) => {
+                write!(f, "{}(", name)?;
+                self.write(f, x)?;
+                write!(f, ")")
+            }
+            Shape::TupleStruct(ref name, ref xs) => {
+                write!(f, "{}(", name)?;
+                self.write_list(f, xs)?;
+                write!(f, ")")
+            }
+            Shape::Struct(ref name, ref fields) => {
+                write!(f, "{} {{ ", name)?;
+                for (i, &(ref field, x)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: ", field)?;
+                    self.write(f, x)?;
+                }
+                write!(f, " }}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for TopicType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SchemaError {
+    /// The type cannot be described without looking at its values
+    Unsupported(String),
+    /// The type contains itself
+    Recursive(String),
+    /// The `Deserialize` implementation rejected a traced value
+    Custom(String),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemaError::Unsupported(ref what) => write!(f, "unsupported type: {}", what),
+            SchemaError::Recursive(ref name) => write!(f, "recursive type: {}", name),
+            SchemaError::Custom(ref msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for SchemaError {
+    fn description(&self) -> &str {
+        "failed to derive schema"
+    }
+}
+
+impl de::Error for SchemaError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SchemaError::Custom(msg.to_string())
+    }
+}
+
+/// Types with a structural description, which is used to check that
+/// publishers and subscribers of a topic agree on the encoded data.
+pub trait Schema {
+    fn schema() -> Result<TopicType, SchemaError>;
+}
+
+impl<T: DeserializeOwned> Schema for T {
+    fn schema() -> Result<TopicType, SchemaError> {
+        let mut tracer = Tracer::default();
+        for _ in 0..MAX_TRACES {
+            T::deserialize(&mut tracer)?;
+            if tracer.is_complete() {
+                let mut nodes = Vec::new();
+                let root = tracer.last;
+                tracer.copy(root, &mut nodes);
+                return Ok(TopicType { nodes: nodes });
+            }
+        }
+
+        Err(SchemaError::Unsupported(String::from("too many enum variants")))
+    }
+}
+
+enum Node {
+    Shape(Shape),
+    /// Index into `Tracer::enums`, as variants are discovered incrementally
+    Enum(usize),
+}
+
+struct EnumTrace {
+    name: &'static str,
+    names: &'static [&'static str],
+    variants: Vec<Option<Variant>>,
+    visits: usize,
+}
+
+#[derive(Default)]
+struct Tracer {
+    nodes: Vec<Node>,
+    enums: Vec<EnumTrace>,
+    registry: HashMap<(&'static str, usize), usize>,
+    /// Names of the containers currently being traced
+    stack: Vec<&'static str>,
+    /// Node of the most recently traced value
+    last: usize,
+}
+
+impl Tracer {
+    fn push(&mut self, node: Node) {
+        self.last = self.nodes.len();
+        self.nodes.push(node);
+    }
+
+    fn shape(&mut self, shape: Shape) {
+        self.push(Node::Shape(shape))
+    }
+
+    fn enter(&mut self, name: &'static str) -> Result<(), SchemaError> {
+        if self.stack.contains(&name) {
+            return Err(SchemaError::Recursive(String::from(name)));
+        }
+        self.stack.push(name);
+        Ok(())
+    }
+
+    fn exit(&mut self) {
+        self.stack.pop();
+    }
+
+    fn is_complete(&self) -> bool {
+        self.enums.iter().all(|e| e.variants.iter().all(Option::is_some))
+    }
+
+    /// Copies the tree rooted at `node` into `out`, discarding the nodes of
+    /// previous passes which are no longer reachable.
+    fn copy(&self, node: usize, out: &mut Vec<Shape>) -> usize {
+        let pos = out.len();
+        out.push(Shape::Unit);
+        let shape = match self.nodes[node] {
+            Node::Shape(ref shape) => shape.map(&mut |x| self.copy(x, out)),
+            Node::Enum(id) => {
+                let trace = &self.enums[id];
+                let variants = trace.names
+                    .iter()
+                    .zip(&trace.variants)
+                    .map(|(name, v)| {
+                        let v = v.as_ref().expect("incomplete enum trace");
+                        (String::from(*name), v.map(&mut |x| self.copy(x, out)))
+                    })
+                    .collect();
+                Shape::Enum(String::from(trace.name), variants)
+            }
+        };
+        out[pos] = shape;
+        pos
+    }
+
+    /// Picks the variant to trace, preferring variants not yet observed.
+    fn next_variant(&mut self,
+                    name: &'static str,
+                    names: &'static [&'static str])
+                    -> Result<(usize, usize), SchemaError> {
+        if names.is_empty() {
+            return Err(SchemaError::Unsupported(format!("empty enum {}", name)));
+        }
+
+        let key = (name, names.as_ptr() as usize);
+        let id = match self.registry.get(&key) {
+            Some(&id) => id,
+            None => {
+                let id = self.enums.len();
+                self.enums.push(EnumTrace {
+                    name: name,
+                    names: names,
+                    variants: vec![None; names.len()],
+                    visits: 0,
+                });
+                self.registry.insert(key, id);
+                id
+            }
+        };
+
+        let trace = &mut self.enums[id];
+        trace.visits += 1;
+        let index = trace.variants
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(trace.visits % names.len());
+
+        Ok((id, index))
+    }
+}
+
+struct SeqTracer<'a> {
+    tracer: &'a mut Tracer,
+    remaining: usize,
+    elements: Vec<usize>,
+}
+
+impl<'a> SeqTracer<'a> {
+    fn new(tracer: &'a mut Tracer, len: usize) -> Self {
+        SeqTracer {
+            tracer: tracer,
+            remaining: len,
+            elements: Vec::with_capacity(len),
+        }
+    }
+
+    /// Returns the traced elements, making sure all of them were visited.
+    fn finish(self) -> Result<Vec<usize>, SchemaError> {
+        if self.remaining > 0 {
+            return Err(SchemaError::Unsupported(String::from("partially visited sequence")));
+        }
+        Ok(self.elements)
+    }
+}
+
+impl<'de, 'a> SeqAccess<'de> for SeqTracer<'a> {
+    type Error = SchemaError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, SchemaError>
+        where T: DeserializeSeed<'de>
+    {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let value = seed.deserialize(&mut *self.tracer)?;
+        self.elements.push(self.tracer.last);
+        Ok(Some(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+struct MapTracer<'a> {
+    tracer: &'a mut Tracer,
+    done: bool,
+    key: Option<usize>,
+    value: Option<usize>,
+}
+
+impl<'de, 'a> MapAccess<'de> for MapTracer<'a> {
+    type Error = SchemaError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, SchemaError>
+        where K: DeserializeSeed<'de>
+    {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        let key = seed.deserialize(&mut *self.tracer)?;
+        self.key = Some(self.tracer.last);
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, SchemaError>
+        where V: DeserializeSeed<'de>
+    {
+        let value = seed.deserialize(&mut *self.tracer)?;
+        self.value = Some(self.tracer.last);
+        Ok(value)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(if self.done { 0 } else { 1 })
+    }
+}
+
+struct EnumTracer<'a> {
+    tracer: &'a mut Tracer,
+    id: usize,
+    index: usize,
+}
+
+impl<'a> EnumTracer<'a> {
+    fn record(&mut self, variant: Variant) {
+        self.tracer.enums[self.id].variants[self.index] = Some(variant);
+    }
+}
+
+impl<'de, 'a> EnumAccess<'de> for EnumTracer<'a> {
+    type Error = SchemaError;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self), SchemaError>
+        where V: DeserializeSeed<'de>
+    {
+        let index: de::value::U32Deserializer<SchemaError> = (self.index as u32).into_deserializer();
+        let value = seed.deserialize(index)?;
+        Ok((value, self))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for EnumTracer<'a> {
+    type Error = SchemaError;
+
+    fn unit_variant(mut self) -> Result<(), SchemaError> {
+        self.record(Variant::Unit);
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(mut self, seed: T) -> Result<T::Value, SchemaError>
+        where T: DeserializeSeed<'de>
+    {
+        let value = seed.deserialize(&mut *self.tracer)?;
+        let inner = self.tracer.last;
+        self.record(Variant::Newtype(inner));
+        Ok(value)
+    }
+
+    fn tuple_variant<V>(mut self, len: usize, visitor: V) -> Result<V::Value, SchemaError>
+        where V: Visitor<'de>
+    {
+        let (value, elements) = {
+            let mut seq = SeqTracer::new(&mut *self.tracer, len);
+            let value = visitor.visit_seq(&mut seq)?;
+            (value, seq.finish()?)
+        };
+        self.record(Variant::Tuple(elements));
+        Ok(value)
+    }
+
+    fn struct_variant<V>(mut self,
+                         fields: &'static [&'static str],
+                         visitor: V)
+                         -> Result<V::Value, SchemaError>
+        where V: Visitor<'de>
+    {
+        let (value, elements) = {
+            let mut seq = SeqTracer::new(&mut *self.tracer, fields.len());
+            let value = visitor.visit_seq(&mut seq)?;
+            (value, seq.finish()?)
+        };
+        let fields = fields.iter().map(|f| String::from(*f)).zip(elements).collect();
+        self.record(Variant::Struct(fields));
+        Ok(value)
+    }
+}
+
+macro_rules! trace_primitive {
+    ($method:ident, $visit:ident, $shape:ident, $value:expr) => {
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+            self.shape(Shape::$shape);
+            visitor.$visit($value)
+        }
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for &'a mut Tracer {
+    type Error = SchemaError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, SchemaError> {
+        Err(SchemaError::Unsupported(String::from("self-describing type")))
+    }
+
+    trace_primitive!(deserialize_bool, visit_bool, Bool, false);
+    trace_primitive!(deserialize_i8, visit_i8, I8, 0);
+    trace_primitive!(deserialize_i16, visit_i16, I16, 0);
+    trace_primitive!(deserialize_i32, visit_i32, I32, 0);
+    trace_primitive!(deserialize_i64, visit_i64, I64, 0);
+    trace_primitive!(deserialize_u8, visit_u8, U8, 0);
+    trace_primitive!(deserialize_u16, visit_u16, U16, 0);
+    trace_primitive!(deserialize_u32, visit_u32, U32, 0);
+    trace_primitive!(deserialize_u64, visit_u64, U64, 0);
+    trace_primitive!(deserialize_f32, visit_f32, F32, 0.0);
+    trace_primitive!(deserialize_f64, visit_f64, F64, 0.0);
+    trace_primitive!(deserialize_char, visit_char, Char, '\0');
+    trace_primitive!(deserialize_str, visit_str, Str, "");
+    trace_primitive!(deserialize_string, visit_string, Str, String::new());
+    trace_primitive!(deserialize_bytes, visit_bytes, Bytes, &[]);
+    trace_primitive!(deserialize_byte_buf, visit_byte_buf, Bytes, Vec::new());
+    trace_primitive!(deserialize_unit, visit_unit, Unit, ());
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        let value = visitor.visit_some(&mut *self)?;
+        let inner = self.last;
+        self.shape(Shape::Option(inner));
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V>(self,
+                                  name: &'static str,
+                                  visitor: V)
+                                  -> Result<V::Value, SchemaError>
+        where V: Visitor<'de>
+    {
+        self.shape(Shape::UnitStruct(String::from(name)));
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(self,
+                                     name: &'static str,
+                                     visitor: V)
+                                     -> Result<V::Value, SchemaError>
+        where V: Visitor<'de>
+    {
+        self.enter(name)?;
+        let value = visitor.visit_newtype_struct(&mut *self)?;
+        self.exit();
+        let inner = self.last;
+        self.shape(Shape::NewtypeStruct(String::from(name), inner));
+        Ok(value)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        let (value, elements) = {
+            let mut seq = SeqTracer::new(&mut *self, 1);
+            let value = visitor.visit_seq(&mut seq)?;
+            (value, seq.finish()?)
+        };
+        self.shape(Shape::Seq(elements[0]));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value, SchemaError>
+        where V: Visitor<'de>
+    {
+        let (value, elements) = {
+            let mut seq = SeqTracer::new(&mut *self, len);
+            let value = visitor.visit_seq(&mut seq)?;
+            (value, seq.finish()?)
+        };
+        self.shape(Shape::Tuple(elements));
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V>(self,
+                                   name: &'static str,
+                                   len: usize,
+                                   visitor: V)
+                                   -> Result<V::Value, SchemaError>
+        where V: Visitor<'de>
+    {
+        self.enter(name)?;
+        let (value, elements) = {
+            let mut seq = SeqTracer::new(&mut *self, len);
+            let value = visitor.visit_seq(&mut seq)?;
+            (value, seq.finish()?)
+        };
+        self.exit();
+        self.shape(Shape::TupleStruct(String::from(name), elements));
+        Ok(value)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        let (value, key, val) = {
+            let mut map = MapTracer {
+                tracer: &mut *self,
+                done: false,
+                key: None,
+                value: None,
+            };
+            let value = visitor.visit_map(&mut map)?;
+            (value, map.key, map.value)
+        };
+
+        match (key, val) {
+            (Some(key), Some(val)) => {
+                self.shape(Shape::Map(key, val));
+                Ok(value)
+            }
+            _ => Err(SchemaError::Unsupported(String::from("partially visited map"))),
+        }
+    }
+
+    fn deserialize_struct<V>(self,
+                             name: &'static str,
+                             fields: &'static [&'static str],
+                             visitor: V)
+                             -> Result<V::Value, SchemaError>
+        where V: Visitor<'de>
+    {
+        self.enter(name)?;
+        let (value, elements) = {
+            let mut seq = SeqTracer::new(&mut *self, fields.len());
+            let value = visitor.visit_seq(&mut seq)?;
+            (value, seq.finish()?)
+        };
+        self.exit();
+        let fields = fields.iter().map(|f| String::from(*f)).zip(elements).collect();
+        self.shape(Shape::Struct(String::from(name), fields));
+        Ok(value)
+    }
+
+    fn deserialize_enum<V>(self,
+                           name: &'static str,
+                           variants: &'static [&'static str],
+                           visitor: V)
+                           -> Result<V::Value, SchemaError>
+        where V: Visitor<'de>
+    {
+        self.enter(name)?;
+        let (id, index) = self.next_variant(name, variants)?;
+        let value = visitor.visit_enum(EnumTracer {
+                tracer: &mut *self,
+                id: id,
+                index: index,
+            })?;
+        self.exit();
+        self.push(Node::Enum(id));
+        Ok(value)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _: V) -> Result<V::Value, SchemaError> {
+        Err(SchemaError::Unsupported(String::from("identifier outside of enum")))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, SchemaError> {
+        Err(SchemaError::Unsupported(String::from("ignored value")))
+    }
+}
+
+/// Evolution rules of a topic, deciding which subscriber schemas are
+/// accepted for the published data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Abomonation)]
+pub enum Compatibility {
+    /// Subscribers must expect exactly the published structure
+    None,
+    /// Subscribers may use a newer schema, with additional optional fields,
+    /// widened numbers or reordered fields
+    Backward,
+    /// Subscribers may use an older schema, fields unknown to them are
+    /// dropped and fields may be reordered
+    Forward,
+    /// Subscribers may use both newer and older schemas
+    Full,
+}
+
+impl Default for Compatibility {
+    fn default() -> Self {
+        Compatibility::None
+    }
+}
+
+impl Compatibility {
+    fn allows_missing(&self) -> bool {
+        *self == Compatibility::Backward || *self == Compatibility::Full
+    }
+
+    fn allows_widening(&self) -> bool {
+        *self == Compatibility::Backward || *self == Compatibility::Full
+    }
+
+    fn allows_extra(&self) -> bool {
+        *self == Compatibility::Forward || *self == Compatibility::Full
+    }
+}
+
+/// Describes how values encoded with the publisher's schema are rearranged
+/// into the layout expected by a subscriber. Structs are encoded as arrays,
+/// hence fields are addressed by position.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Projection {
+    /// The encoded value can be decoded as is
+    Identity,
+    Option(Box<Projection>),
+    Seq(Box<Projection>),
+    Map(Box<Projection>, Box<Projection>),
+    Tuple(Vec<Projection>),
+    /// Fields in the order expected by the subscriber
+    Struct(Vec<Field>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Field {
+    /// Field at the given position in the published value
+    Published(usize, Projection),
+    /// Optional field unknown to the publisher
+    Missing,
+}
+
+impl Projection {
+    /// Derives the projection from the published schema `writer` into the
+    /// schema `reader` expected by a subscriber, or `None` if the reader
+    /// is not compatible under the given rules.
+    pub fn new(reader: &TopicType, writer: &TopicType, mode: Compatibility) -> Option<Self> {
+        if !reader.is_valid() || !writer.is_valid() {
+            return None;
+        }
+
+        if mode == Compatibility::None {
+            if reader.compare(0, writer, 0) {
+                return Some(Projection::Identity);
+            } else {
+                return None;
+            }
+        }
+
+        project(reader, 0, writer, 0, mode)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Projection::Identity
+    }
+}
+
+/// Returns `(signed, bits)` for integer shapes.
+fn integer(shape: &Shape) -> Option<(bool, u32)> {
+    match *shape {
+        Shape::I8 => Some((true, 8)),
+        Shape::I16 => Some((true, 16)),
+        Shape::I32 => Some((true, 32)),
+        Shape::I64 => Some((true, 64)),
+        Shape::U8 => Some((false, 8)),
+        Shape::U16 => Some((false, 16)),
+        Shape::U32 => Some((false, 32)),
+        Shape::U64 => Some((false, 64)),
+        _ => None,
+    }
+}
+
+/// Checks if every value of `from` can be represented by `to`. Since the
+/// encoding of numbers does not depend on their type, widened numbers can
+/// be decoded without any conversion.
+fn widens(from: &Shape, to: &Shape) -> bool {
+    match (integer(from), integer(to)) {
+        (Some((false, a)), Some((_, b))) => a < b,
+        (Some((true, a)), Some((true, b))) => a < b,
+        _ => *from == Shape::F32 && *to == Shape::F64,
+    }
+}
+
+/// Wraps a nested projection, unless it leaves the value unchanged.
+fn wrap(p: Projection, f: fn(Box<Projection>) -> Projection) -> Projection {
+    if p.is_identity() {
+        Projection::Identity
+    } else {
+        f(Box::new(p))
+    }
+}
+
+fn project(reader: &TopicType,
+           a: usize,
+           writer: &TopicType,
+           b: usize,
+           mode: Compatibility)
+           -> Option<Projection> {
+    // shapes whose contents cannot be rearranged, as their encoding is
+    // determined by the serializer
+    let exact = |x: usize, y: usize| match project(reader, x, writer, y, mode) {
+        Some(Projection::Identity) => true,
+        _ => false,
+    };
+
+    match (&reader.nodes[a], &writer.nodes[b]) {
+        (&Shape::Option(x), &Shape::Option(y)) => {
+            project(reader, x, writer, y, mode).map(|p| wrap(p, Projection::Option))
+        }
+        (&Shape::Seq(x), &Shape::Seq(y)) => {
+            project(reader, x, writer, y, mode).map(|p| wrap(p, Projection::Seq))
+        }
+        (&Shape::Map(k1, v1), &Shape::Map(k2, v2)) => {
+            let k = project(reader, k1, writer, k2, mode)?;
+            let v = project(reader, v1, writer, v2, mode)?;
+            if k.is_identity() && v.is_identity() {
+                Some(Projection::Identity)
+            } else {
+                Some(Projection::Map(Box::new(k), Box::new(v)))
+            }
+        }
+        (&Shape::Tuple(ref xs), &Shape::Tuple(ref ys)) |
+        (&Shape::TupleStruct(_, ref xs), &Shape::TupleStruct(_, ref ys)) => {
+            if xs.len() != ys.len() {
+                return None;
+            }
+            let mut elements = Vec::with_capacity(xs.len());
+            for (&x, &y) in xs.iter().zip(ys) {
+                elements.push(project(reader, x, writer, y, mode)?);
+            }
+            if elements.iter().all(Projection::is_identity) {
+                Some(Projection::Identity)
+            } else {
+                Some(Projection::Tuple(elements))
+            }
+        }
+        (&Shape::Struct(_, ref xs), &Shape::Struct(_, ref ys)) => {
+            let mut used = vec![false; ys.len()];
+            let mut identity = xs.len() == ys.len();
+            let mut fields = Vec::with_capacity(xs.len());
+            for (i, &(ref name, x)) in xs.iter().enumerate() {
+                match ys.iter().position(|&(ref n, _)| n == name) {
+                    Some(j) => {
+                        let p = project(reader, x, writer, ys[j].1, mode)?;
+                        identity = identity && i == j && p.is_identity();
+                        used[j] = true;
+                        fields.push(Field::Published(j, p));
+                    }
+                    None => {
+                        match reader.nodes[x] {
+                            Shape::Option(_) if mode.allows_missing() => {
+                                identity = false;
+                                fields.push(Field::Missing);
+                            }
+                            _ => return None,
+                        }
+                    }
+                }
+            }
+
+            if !mode.allows_extra() && used.iter().any(|u| !u) {
+                None
+            } else if identity {
+                Some(Projection::Identity)
+            } else {
+                Some(Projection::Struct(fields))
+            }
+        }
+        (&Shape::NewtypeStruct(_, x), &Shape::NewtypeStruct(_, y)) => {
+            if exact(x, y) {
+                Some(Projection::Identity)
+            } else {
+                None
+            }
+        }
+        (&Shape::Enum(_, ref xs), &Shape::Enum(_, ref ys)) => {
+            let variants = xs.len() == ys.len() &&
+                           xs.iter().zip(ys).all(|(&(ref n, ref x), &(ref m, ref y))| {
+                n == m &&
+                match (x, y) {
+                    (&Variant::Unit, &Variant::Unit) => true,
+                    (&Variant::Newtype(x), &Variant::Newtype(y)) => exact(x, y),
+                    (&Variant::Tuple(ref xs), &Variant::Tuple(ref ys)) => {
+                        xs.len() == ys.len() && xs.iter().zip(ys).all(|(&x, &y)| exact(x, y))
+                    }
+                    (&Variant::Struct(ref xs), &Variant::Struct(ref ys)) => {
+                        xs.len() == ys.len() &&
+                        xs.iter().zip(ys).all(|(&(ref n, x), &(ref m, y))| n == m && exact(x, y))
+                    }
+                    _ => false,
+                }
+            });
+
+            if variants {
+                Some(Projection::Identity)
+            } else {
+                None
+            }
+        }
+        (&Shape::UnitStruct(_), &Shape::UnitStruct(_)) => Some(Projection::Identity),
+        (x, y) if x == y => Some(Projection::Identity),
+        (x, y) if mode.allows_widening() && widens(y, x) => Some(Projection::Identity),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Point {
+        x: i32,
+        y: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Event {
+        Start,
+        Value(u64),
+        Pair(u8, u8),
+        Named { id: u32 },
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct List {
+        next: Option<Box<List>>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Old {
+        a: u32,
+        b: String,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct New {
+        b: String,
+        a: u64,
+        c: Option<bool>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Subset {
+        b: String,
+    }
+
+    #[test]
+    fn trace_primitives() {
+        assert_eq!(u64::schema().unwrap().nodes, vec![Shape::U64]);
+        assert_eq!(String::schema().unwrap().nodes, vec![Shape::Str]);
+        assert_eq!(<()>::schema().unwrap().nodes, vec![Shape::Unit]);
+    }
+
+    #[test]
+    fn trace_containers() {
+        let schema = <(u32, Vec<String>, Option<bool>)>::schema().unwrap();
+        assert_eq!(schema.nodes,
+                   vec![Shape::Tuple(vec![1, 2, 4]),
+                        Shape::U32,
+                        Shape::Seq(3),
+                        Shape::Str,
+                        Shape::Option(5),
+                        Shape::Bool]);
+        assert!(schema.is_valid());
+
+        let schema = HashMap::<String, u8>::schema().unwrap();
+        assert_eq!(schema.nodes, vec![Shape::Map(1, 2), Shape::Str, Shape::U8]);
+    }
+
+    #[test]
+    fn trace_struct() {
+        let schema = Point::schema().unwrap();
+        let fields = vec![(String::from("x"), 1), (String::from("y"), 2)];
+        assert_eq!(schema.nodes,
+                   vec![Shape::Struct(String::from("Point"), fields),
+                        Shape::I32,
+                        Shape::Option(3),
+                        Shape::Str]);
+        assert_eq!(schema.to_string(), "Point { x: i32, y: Option<String> }");
+    }
+
+    #[test]
+    fn trace_all_enum_variants() {
+        let schema = Vec::<Event>::schema().unwrap();
+        assert!(schema.is_valid());
+
+        let variants = match schema.nodes[1] {
+            Shape::Enum(ref name, ref variants) => {
+                assert_eq!(name, "Event");
+                variants.clone()
+            }
+            ref other => panic!("expected enum, got {:?}", other),
+        };
+
+        let names: Vec<&str> = variants.iter().map(|&(ref n, _)| &**n).collect();
+        assert_eq!(names, vec!["Start", "Value", "Pair", "Named"]);
+        match (&variants[0].1, &variants[1].1, &variants[2].1, &variants[3].1) {
+            (&Variant::Unit,
+             &Variant::Newtype(v),
+             &Variant::Tuple(ref p),
+             &Variant::Struct(ref f)) => {
+                assert_eq!(schema.nodes[v], Shape::U64);
+                assert_eq!(p.len(), 2);
+                assert_eq!(f.len(), 1);
+                assert_eq!(f[0].0, "id");
+            }
+            other => panic!("unexpected variants: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reject_unsupported_types() {
+        match List::schema() {
+            Err(SchemaError::Recursive(ref name)) => assert_eq!(name, "List"),
+            other => panic!("expected recursive type error, got {:?}", other),
+        }
+
+        match serde_json::Value::schema() {
+            Err(SchemaError::Unsupported(_)) => (),
+            other => panic!("expected unsupported type error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_tree() {
+        assert!(!TopicType { nodes: vec![] }.is_valid());
+        assert!(!TopicType { nodes: vec![Shape::Seq(0)] }.is_valid());
+        assert!(!TopicType { nodes: vec![Shape::Option(2), Shape::U8] }.is_valid());
+        assert!(TopicType { nodes: vec![Shape::Option(1), Shape::U8] }.is_valid());
+    }
+
+    #[test]
+    fn compare_structure() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Renamed {
+            x: i32,
+            y: Option<String>,
+        }
+
+        // struct names are ignored, field names are not
+        assert!(Point::schema().unwrap().is_compatible(&Renamed::schema().unwrap()));
+        assert!(!Old::schema().unwrap().is_compatible(&Subset::schema().unwrap()));
+        assert!(!u32::schema().unwrap().is_compatible(&u64::schema().unwrap()));
+    }
+
+    #[test]
+    fn project_without_compatibility() {
+        let old = Old::schema().unwrap();
+        let new = New::schema().unwrap();
+        assert_eq!(Projection::new(&old, &old, Compatibility::None),
+                   Some(Projection::Identity));
+        assert_eq!(Projection::new(&new, &old, Compatibility::None), None);
+
+        let small = u32::schema().unwrap();
+        let large = u64::schema().unwrap();
+        assert_eq!(Projection::new(&large, &small, Compatibility::None), None);
+    }
+
+    #[test]
+    fn project_backward() {
+        let old = Old::schema().unwrap();
+        let new = New::schema().unwrap();
+
+        // newer readers reorder fields, widen numbers and fill in options
+        let expected = Projection::Struct(vec![Field::Published(1, Projection::Identity),
+                                               Field::Published(0, Projection::Identity),
+                                               Field::Missing]);
+        assert_eq!(Projection::new(&new, &old, Compatibility::Backward), Some(expected));
+
+        // older readers cannot drop fields
+        assert_eq!(Projection::new(&old, &new, Compatibility::Backward), None);
+
+        // numbers are never narrowed
+        let small = u32::schema().unwrap();
+        let large = u64::schema().unwrap();
+        assert_eq!(Projection::new(&large, &small, Compatibility::Backward),
+                   Some(Projection::Identity));
+        assert_eq!(Projection::new(&small, &large, Compatibility::Backward), None);
+    }
+
+    #[test]
+    fn project_forward() {
+        let old = Old::schema().unwrap();
+        let new = New::schema().unwrap();
+        let subset = Subset::schema().unwrap();
+
+        let expected = Projection::Struct(vec![Field::Published(1, Projection::Identity)]);
+        assert_eq!(Projection::new(&subset, &old, Compatibility::Forward), Some(expected));
+
+        // missing fields and widening require backward compatibility
+        assert_eq!(Projection::new(&new, &old, Compatibility::Forward), None);
+        assert!(Projection::new(&new, &old, Compatibility::Full).is_some());
+    }
+
+    #[test]
+    fn project_nested() {
+        let old = Vec::<Old>::schema().unwrap();
+        let subset = Vec::<Subset>::schema().unwrap();
+
+        let field = Projection::Struct(vec![Field::Published(1, Projection::Identity)]);
+        assert_eq!(Projection::new(&subset, &old, Compatibility::Full),
+                   Some(Projection::Seq(Box::new(field))));
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Decoding of published values into the schema expected by a subscriber.
+//!
+//! Values are first decoded into a generic MessagePack tree, rearranged
+//! according to a `Projection` and then decoded into the subscriber's type.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+
+use serde::de::{self, Deserialize, DeserializeOwned, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer, SerializeMap, SerializeSeq};
+
+use strymon_communication::message::MessageBuf;
+
+use model::schema::{Field, Projection};
+
+/// A generic MessagePack value.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Nil,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a MessagePack value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> ::std::result::Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> ::std::result::Result<Value, E> {
+        Ok(Value::I64(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> ::std::result::Result<Value, E> {
+        Ok(Value::U64(v))
+    }
+
+    fn visit_f32<E: de::Error>(self, v: f32) -> ::std::result::Result<Value, E> {
+        Ok(Value::F32(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> ::std::result::Result<Value, E> {
+        Ok(Value::F64(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> ::std::result::Result<Value, E> {
+        Ok(Value::Str(String::from(v)))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> ::std::result::Result<Value, E> {
+        Ok(Value::Str(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> ::std::result::Result<Value, E> {
+        Ok(Value::Bin(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> ::std::result::Result<Value, E> {
+        Ok(Value::Bin(v))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> ::std::result::Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E: de::Error>(self) -> ::std::result::Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> ::std::result::Result<Value, D::Error>
+        where D: Deserializer<'de>
+    {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> ::std::result::Result<Value, A::Error>
+        where A: SeqAccess<'de>
+    {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> ::std::result::Result<Value, A::Error>
+        where A: MapAccess<'de>
+    {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Value::Map(entries))
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        match *self {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Bool(v) => serializer.serialize_bool(v),
+            Value::I64(v) => serializer.serialize_i64(v),
+            Value::U64(v) => serializer.serialize_u64(v),
+            Value::F32(v) => serializer.serialize_f32(v),
+            Value::F64(v) => serializer.serialize_f64(v),
+            Value::Str(ref v) => serializer.serialize_str(v),
+            Value::Bin(ref v) => serializer.serialize_bytes(v),
+            Value::Array(ref values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Value::Map(ref entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for &(ref key, ref value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+fn mismatch() -> Error {
+    Error::new(ErrorKind::InvalidData, "published value does not match its schema")
+}
+
+fn apply(projection: &Projection, value: Value) -> Result<Value> {
+    Ok(match (projection, value) {
+        (&Projection::Identity, value) => value,
+        (&Projection::Option(_), Value::Nil) => Value::Nil,
+        (&Projection::Option(ref p), value) => apply(p, value)?,
+        (&Projection::Seq(ref p), Value::Array(values)) => {
+            let values: Result<_> = values.into_iter().map(|v| apply(p, v)).collect();
+            Value::Array(values?)
+        }
+        (&Projection::Map(ref k, ref v), Value::Map(entries)) => {
+            let entries: Result<_> = entries.into_iter()
+                .map(|(key, value)| Ok((apply(k, key)?, apply(v, value)?)))
+                .collect();
+            Value::Map(entries?)
+        }
+        (&Projection::Tuple(ref ps), Value::Array(values)) => {
+            if ps.len() != values.len() {
+                return Err(mismatch());
+            }
+            let values: Result<_> = ps.iter().zip(values).map(|(p, v)| apply(p, v)).collect();
+            Value::Array(values?)
+        }
+        (&Projection::Struct(ref fields), Value::Array(mut values)) => {
+            let mut projected = Vec::with_capacity(fields.len());
+            for field in fields {
+                match *field {
+                    Field::Published(i, ref p) => {
+                        let value = values.get_mut(i).ok_or_else(mismatch)?;
+                        projected.push(apply(p, mem::replace(value, Value::Nil))?);
+                    }
+                    Field::Missing => projected.push(Value::Nil),
+                }
+            }
+            Value::Array(projected)
+        }
+        _ => return Err(mismatch()),
+    })
+}
+
+/// Decodes published values for a subscriber whose schema differs from
+/// the schema of the publisher.
+#[derive(Clone, Debug)]
+pub struct Decoder {
+    projection: Projection,
+}
+
+impl Decoder {
+    pub fn new(projection: Projection) -> Self {
+        Decoder { projection: projection }
+    }
+
+    /// Removes a batch of published values from the front of the buffer.
+    pub fn pop<D: DeserializeOwned>(&self, buf: &mut MessageBuf) -> Result<Vec<D>> {
+        let values = buf.pop::<Vec<Value>>()?;
+        let mut projected = MessageBuf::empty();
+        let values: Result<Vec<Value>> = values.into_iter()
+            .map(|v| apply(&self.projection, v))
+            .collect();
+        projected.push(values?)?;
+        projected.pop::<Vec<D>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use strymon_communication::message::MessageBuf;
+
+    use model::schema::{Compatibility, Field, Projection, Schema};
+    use super::{apply, Decoder, Value};
+
+    #[derive(Serialize, Deserialize)]
+    struct Old {
+        a: u32,
+        b: String,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct New {
+        b: String,
+        a: u64,
+        c: Option<bool>,
+    }
+
+    fn reorder() -> Projection {
+        Projection::Struct(vec![Field::Published(1, Projection::Identity),
+                                Field::Published(0, Projection::Identity),
+                                Field::Missing])
+    }
+
+    fn old(a: u64, b: &str) -> Value {
+        Value::Array(vec![Value::U64(a), Value::Str(String::from(b))])
+    }
+
+    fn new(a: u64, b: &str) -> Value {
+        Value::Array(vec![Value::Str(String::from(b)), Value::U64(a), Value::Nil])
+    }
+
+    #[test]
+    fn apply_identity() {
+        let value = old(1, "x");
+        assert_eq!(apply(&Projection::Identity, value.clone()).unwrap(), value);
+    }
+
+    #[test]
+    fn apply_struct() {
+        assert_eq!(apply(&reorder(), old(1, "x")).unwrap(), new(1, "x"));
+
+        // fields outside of the published value are rejected
+        let missing = Value::Array(vec![Value::U64(1)]);
+        assert!(apply(&reorder(), missing).is_err());
+        assert!(apply(&reorder(), Value::U64(1)).is_err());
+    }
+
+    #[test]
+    fn apply_nested() {
+        let option = Projection::Option(Box::new(reorder()));
+        assert_eq!(apply(&option, Value::Nil).unwrap(), Value::Nil);
+        assert_eq!(apply(&option, old(1, "x")).unwrap(), new(1, "x"));
+
+        let seq = Projection::Seq(Box::new(reorder()));
+        let values = Value::Array(vec![old(1, "x"), old(2, "y")]);
+        assert_eq!(apply(&seq, values).unwrap(),
+                   Value::Array(vec![new(1, "x"), new(2, "y")]));
+
+        let map = Projection::Map(Box::new(Projection::Identity), Box::new(reorder()));
+        let entries = Value::Map(vec![(Value::U64(0), old(1, "x"))]);
+        assert_eq!(apply(&map, entries).unwrap(),
+                   Value::Map(vec![(Value::U64(0), new(1, "x"))]));
+    }
+
+    #[test]
+    fn apply_tuple() {
+        let tuple = Projection::Tuple(vec![Projection::Identity, reorder()]);
+        let value = Value::Array(vec![Value::Bool(true), old(1, "x")]);
+        assert_eq!(apply(&tuple, value).unwrap(),
+                   Value::Array(vec![Value::Bool(true), new(1, "x")]));
+
+        let short = Value::Array(vec![Value::Bool(true)]);
+        assert!(apply(&tuple, short).is_err());
+    }
+
+    #[test]
+    fn decode_published_batch() {
+        let projection = Projection::new(&New::schema().unwrap(),
+                                         &Old::schema().unwrap(),
+                                         Compatibility::Backward)
+            .unwrap();
+        let decoder = Decoder::new(projection);
+
+        let mut buf = MessageBuf::empty();
+        let batch = vec![Old {
+                             a: 1,
+                             b: String::from("x"),
+                         },
+                         Old {
+                             a: 2,
+                             b: String::from("y"),
+                         }];
+        buf.push(&batch).unwrap();
+
+        let decoded = decoder.pop::<New>(&mut buf).unwrap();
+        assert_eq!(decoded,
+                   vec![New {
+                            b: String::from("x"),
+                            a: 1,
+                            c: None,
+                        },
+                        New {
+                            b: String::from("y"),
+                            a: 2,
+                            c: None,
+                        }]);
+    }
+}
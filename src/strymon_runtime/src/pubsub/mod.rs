@@ -8,3 +8,16 @@
 
 pub mod publisher;
 pub mod subscriber;
+pub mod decoder;
+
+/// Header of every message sent by a `TimelyPublisher`, followed by the
+/// contents listed for each kind.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum MessageKind {
+    /// `data, time, frontier` and optionally the number of dropped batches
+    Batch,
+    /// `frontier`, sent if the frontier advances without any data
+    Progress,
+    /// `frontier`, which is empty, sent once the stream is complete
+    Close,
+}
@@ -6,13 +6,18 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp;
 use std::io::{Result, Error};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use std::rc::Rc;
 use std::sync::Arc;
 
 use serde::ser::Serialize;
 
 use futures::{Future, Poll, Async};
+use futures::task;
 use futures::executor::{self, Spawn};
 use futures::stream::Stream;
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
@@ -23,14 +28,30 @@ use strymon_communication::message::MessageBuf;
 
 use super::{Nop, PublisherServer, SubscriberId, SubscriberEvent};
 
+/// Maximum number of entries sent to a new subscriber in a single message
+/// of the initial snapshot.
+const SNAPSHOT_CHUNK: usize = 4096;
+
+/// The state of the collection at the time a subscriber was accepted, sent
+/// to it in chunks. Every message of a collection topic is followed by a
+/// flag which is only set if more chunks of the snapshot follow.
+struct Snapshot<D> {
+    tx: Sender,
+    data: Rc<Vec<(D, i32)>>,
+    offset: usize,
+    /// Updates published while the snapshot is still being sent
+    backlog: Vec<MessageBuf>,
+}
+
 pub struct CollectionPublisher<D> {
     server: PublisherServer,
     subscribers: BTreeMap<SubscriberId, Sender>,
+    snapshots: BTreeMap<SubscriberId, Snapshot<D>>,
     source: UnboundedReceiver<Vec<(D, i32)>>,
-    collection: Vec<(D, i32)>,
+    collection: HashMap<D, i32>,
 }
 
-impl<D: Serialize + Eq + 'static> CollectionPublisher<D> {
+impl<D: Serialize + Eq + Hash + Clone + 'static> CollectionPublisher<D> {
     pub fn new(network: &Network) -> Result<((String, u16), Mutator<D>, Self)> {
         let server = PublisherServer::new(network)?;
         let addr = {
@@ -45,32 +66,85 @@ impl<D: Serialize + Eq + 'static> CollectionPublisher<D> {
         let publisher = CollectionPublisher {
             server: server,
             subscribers: BTreeMap::new(),
-            collection: Vec::new(),
+            snapshots: BTreeMap::new(),
+            collection: HashMap::new(),
             source: rx,
         };
 
         Ok((addr, sink, publisher))
     }
 
+    /// Secret subscribers have to present when connecting.
+    pub fn secret(&self) -> Option<u64> {
+        self.server.secret()
+    }
+
+    /// Accepts subscribers without checking their secret.
+    pub fn public(&mut self) {
+        self.server.public();
+    }
+
+    /// Sums up the counts of a batch of updates, dropping the ones which
+    /// cancel out.
+    fn consolidate(updates: Vec<(D, i32)>) -> Vec<(D, i32)> {
+        let mut batch = HashMap::with_capacity(updates.len());
+        for (item, delta) in updates {
+            *batch.entry(item).or_insert(0) += delta;
+        }
+
+        batch.into_iter().filter(|&(_, delta)| delta != 0).collect()
+    }
+
     fn update_from(&mut self, updates: Vec<(D, i32)>) {
-        // warning: this is a naive nested loop join,
-        //          we might want to use specialization at some point
-        for (new, delta) in updates {
-            let position = self.collection.iter().position(|&(ref old, _)| old == &new);
-            if let Some(pos) = position {
-                // update count in already existing entry
-                self.collection[pos].1 += delta;
-                let count = self.collection[pos].1;
-                if count == 0 {
-                    self.collection.swap_remove(pos);
+        for (item, delta) in updates {
+            let count = match self.collection.entry(item) {
+                Entry::Occupied(mut entry) => {
+                    *entry.get_mut() += delta;
+                    let count = *entry.get();
+                    if count == 0 {
+                        entry.remove();
+                    }
+                    count
                 }
+                Entry::Vacant(entry) => {
+                    if delta > 0 {
+                        entry.insert(delta);
+                    }
+                    delta
+                }
+            };
+
+            assert!(count >= 0, "negative amount in collection");
+        }
+    }
+
+    /// Sends the next chunk of every pending snapshot, returns true if there
+    /// are snapshots left to send.
+    fn send_snapshots(&mut self) -> bool {
+        let mut completed = Vec::new();
+        for (&id, snapshot) in self.snapshots.iter_mut() {
+            let end = cmp::min(snapshot.offset + SNAPSHOT_CHUNK, snapshot.data.len());
+            let mut buf = MessageBuf::empty();
+            buf.push::<&[(D, i32)]>(&snapshot.data[snapshot.offset..end]).unwrap();
+            buf.push(end < snapshot.data.len()).unwrap();
+            snapshot.tx.send(buf);
+            snapshot.offset = end;
 
-                assert!(count >= 0, "negative amount in collection");
-            } else if delta > 0 {
-                // add a new one
-                self.collection.push((new, delta));
+            if snapshot.offset == snapshot.data.len() {
+                completed.push(id);
             }
         }
+
+        // subscribers with a complete snapshot catch up and go live
+        for id in completed {
+            let snapshot = self.snapshots.remove(&id).unwrap();
+            for buf in snapshot.backlog {
+                snapshot.tx.send(buf);
+            }
+            self.subscribers.insert(id, snapshot.tx);
+        }
+
+        !self.snapshots.is_empty()
     }
 
     pub fn spawn(self) -> SpawnedPublisher {
@@ -93,7 +167,7 @@ impl SpawnedPublisher {
     }
 }
 
-impl<D: Serialize + Eq + 'static> Future for CollectionPublisher<D> {
+impl<D: Serialize + Eq + Hash + Clone + 'static> Future for CollectionPublisher<D> {
     type Item = ();
     type Error = Error;
 
@@ -125,33 +199,60 @@ impl<D: Serialize + Eq + 'static> Future for CollectionPublisher<D> {
                 SubscriberEvent::Accepted(id, tx) => {
                     accepted.push((id, tx));
                 }
+                SubscriberEvent::Message(id, _) => {
+                    error!("unexpected message from subscriber {:?}", id);
+                    self.subscribers.remove(&id);
+                    self.snapshots.remove(&id);
+                    accepted.retain(|&(other, _)| other != id);
+                }
                 SubscriberEvent::Disconnected(id) |
                 SubscriberEvent::Error(id, _) => {
                     self.subscribers.remove(&id);
+                    self.snapshots.remove(&id);
+                    accepted.retain(|&(other, _)| other != id);
                 }
             }
         }
 
-        // step 4: send updates to those who understand them
-        if !self.subscribers.is_empty() && !all_updates.is_empty() {
+        // step 4: send updates to those who understand them, subscribers
+        // still receiving their snapshot get them once it is complete
+        let updates = Self::consolidate(all_updates);
+        if !updates.is_empty() {
             let mut buf = MessageBuf::empty();
-            buf.push::<&Vec<(D, i32)>>(&all_updates).unwrap();
+            buf.push::<&Vec<(D, i32)>>(&updates).unwrap();
+            buf.push(false).unwrap();
             for sub in self.subscribers.values() {
                 sub.send(buf.clone())
             }
+            for snapshot in self.snapshots.values_mut() {
+                snapshot.backlog.push(buf.clone());
+            }
         }
 
         // step 5: merge updates with local collection copy
-        self.update_from(all_updates);
+        self.update_from(updates);
 
-        // step 6: inform incoming subscribers about current collection state
+        // step 6: take a snapshot of the current collection state for
+        // incoming subscribers, it is sent in chunks to not delay updates
         if !accepted.is_empty() {
-            let mut buf = MessageBuf::empty();
-            buf.push::<&Vec<(D, i32)>>(&self.collection).unwrap();
-            for &(_, ref sub) in accepted.iter() {
-                sub.send(buf.clone())
+            let data = Rc::new(self.collection
+                .iter()
+                .map(|(item, &count)| (item.clone(), count))
+                .collect::<Vec<_>>());
+            for (id, tx) in accepted {
+                self.snapshots.insert(id,
+                                      Snapshot {
+                                          tx: tx,
+                                          data: data.clone(),
+                                          offset: 0,
+                                          backlog: Vec::new(),
+                                      });
             }
-            self.subscribers.extend(accepted);
+        }
+
+        // step 7: continue sending snapshots, yield to other tasks in between
+        if self.send_snapshots() {
+            task::current().notify();
         }
 
         Ok(Async::NotReady)
@@ -16,11 +16,13 @@ use strymon_communication::Network;
 use strymon_communication::transport::Sender;
 use strymon_communication::message::MessageBuf;
 
-use super::{PollServer, PublisherServer, SubscriberId, SubscriberEvent};
+use super::{Backpressure, PollServer, PublisherServer, SubscriberId, SubscriberEvent};
+use super::{queue_depth, send_batch};
 
 pub struct Publisher<D> {
     server: PollServer,
     subscribers: BTreeMap<SubscriberId, Sender>,
+    backpressure: Option<Backpressure>,
     marker: PhantomData<D>,
 }
 
@@ -36,16 +38,40 @@ impl<D: Serialize> Publisher<D> {
             Publisher {
                 server: PollServer::from(server),
                 subscribers: BTreeMap::new(),
+                backpressure: None,
                 marker: PhantomData,
             }))
     }
 
+    /// Secret subscribers have to present when connecting.
+    pub fn secret(&self) -> Option<u64> {
+        self.server.secret
+    }
+
+    /// Bounds the send queue of every subscriber, applies to subscribers
+    /// connecting after this call.
+    pub fn bound(&mut self, backpressure: Backpressure) {
+        self.backpressure = Some(backpressure);
+    }
+
+    /// Number of batches queued for the slowest subscriber.
+    pub fn queue_depth(&self) -> usize {
+        queue_depth(self.subscribers.values())
+    }
+
     pub fn publish(&mut self, item: &Vec<D>) -> Result<()> {
         for event in self.server.poll_events()? {
             match event {
                 SubscriberEvent::Accepted(id, tx) => {
+                    if let Some(ref backpressure) = self.backpressure {
+                        backpressure.apply(&tx);
+                    }
                     self.subscribers.insert(id, tx);
                 }
+                SubscriberEvent::Message(id, _) => {
+                    error!("unexpected message from subscriber {:?}", id);
+                    self.subscribers.remove(&id);
+                }
                 SubscriberEvent::Disconnected(id) |
                 SubscriberEvent::Error(id, _) => {
                     self.subscribers.remove(&id);
@@ -57,7 +83,7 @@ impl<D: Serialize> Publisher<D> {
             let mut buf = MessageBuf::empty();
             buf.push::<&[D]>(item.as_slice()).unwrap();
             for sub in self.subscribers.values() {
-                sub.send(buf.clone())
+                send_batch(sub, &buf);
             }
         }
 
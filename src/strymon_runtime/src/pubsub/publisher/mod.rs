@@ -9,27 +9,82 @@
 use std::io::{Result, Error, ErrorKind};
 use std::mem;
 use std::sync::Arc;
+use std::collections::HashMap;
+
+use rand;
 
 use futures::{Poll, Async};
 use futures::stream::{Stream, Fuse};
 use futures::executor::{self, Notify, Spawn};
 
 use strymon_communication::Network;
-use strymon_communication::transport::{Listener, Receiver, Sender};
+use strymon_communication::transport::{Delivery, Listener, Receiver, Sender};
+use strymon_communication::message::MessageBuf;
+
+pub use strymon_communication::transport::Overflow;
 
 pub mod item;
 pub mod timely;
 pub mod collection;
+pub mod retention;
+pub mod timestamped;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SubscriberId(pub u32);
 
+/// Limits the number of batches queued for a single slow subscriber.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Backpressure {
+    /// Maximum number of queued batches per subscriber
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Backpressure {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Backpressure {
+            capacity: capacity,
+            overflow: overflow,
+        }
+    }
+
+    fn apply(&self, tx: &Sender) {
+        tx.bound(self.capacity, self.overflow);
+    }
+}
+
+/// Sends a batch to a subscriber, the batch may be dropped if the queue of
+/// the subscriber is full. If batches for this subscriber have been dropped
+/// since the last call, the number of dropped batches is appended to the
+/// batch to inform the subscriber about the gap.
+fn send_batch(tx: &Sender, buf: &MessageBuf) {
+    let dropped = tx.take_dropped();
+    if dropped > 0 {
+        let mut flagged = buf.clone();
+        flagged.push(dropped as u64).unwrap();
+        tx.send_with(flagged, Delivery::Droppable);
+    } else {
+        tx.send_with(buf.clone(), Delivery::Droppable);
+    }
+}
+
+/// Returns the number of batches queued for the slowest subscriber.
+fn queue_depth<'a, I: IntoIterator<Item = &'a Sender>>(subscribers: I) -> usize {
+    subscribers.into_iter().map(Sender::queued).max().unwrap_or(0)
+}
+
+/// Accepts subscribers once they have presented the secret of the
+/// publication in their first message.
 struct PublisherServer {
     listener: Fuse<Listener>,
     subscribers: Vec<(SubscriberId, Receiver)>,
+    /// Subscribers which have not presented the secret yet
+    connecting: HashMap<SubscriberId, Sender>,
     events: Vec<SubscriberEvent>,
     next_id: u32,
     addr: (String, u16),
+    /// Any secret is accepted if `None`
+    secret: Option<u64>,
 }
 
 impl PublisherServer {
@@ -42,9 +97,11 @@ impl PublisherServer {
         Ok(PublisherServer {
             listener: listener.fuse(),
             subscribers: Vec::new(),
+            connecting: HashMap::new(),
             events: Vec::new(),
             next_id: 0,
             addr: addr,
+            secret: Some(rand::random()),
         })
     }
 
@@ -52,12 +109,21 @@ impl PublisherServer {
         (&self.addr.0, self.addr.1)
     }
 
+    pub fn secret(&self) -> Option<u64> {
+        self.secret
+    }
+
+    /// Accepts subscribers regardless of the secret they present.
+    pub fn public(&mut self) {
+        self.secret = None;
+    }
+
     fn poll_listener(&mut self) -> Result<()> {
         while let Async::Ready(Some((tx, rx))) = self.listener.poll()? {
             self.next_id += 1;
             let id = SubscriberId(self.next_id);
 
-            self.events.push(SubscriberEvent::Accepted(id, tx));
+            self.connecting.insert(id, tx);
             self.subscribers.push((id, rx));
         }
 
@@ -87,19 +153,39 @@ impl PublisherServer {
 
     fn poll_subscribers(&mut self) {
         let events = &mut self.events;
+        let connecting = &mut self.connecting;
+        let secret = self.secret;
         PublisherServer::retain(&mut self.subscribers, move |&mut (id, ref mut rx)| {
+            // publishers only learn about subscribers once they are accepted
+            let accepted = !connecting.contains_key(&id);
             match rx.poll() {
                 Ok(Async::NotReady) => true,
-                Ok(Async::Ready(Some(_))) => {
-                    error!("unexpected message from subscriber {:?}", id);
-                    false
+                Ok(Async::Ready(Some(msg))) => {
+                    if accepted {
+                        events.push(SubscriberEvent::Message(id, msg));
+                        true
+                    } else if authenticate(secret, msg) {
+                        let tx = connecting.remove(&id).unwrap();
+                        events.push(SubscriberEvent::Accepted(id, tx));
+                        true
+                    } else {
+                        warn!("rejecting subscriber {:?} with invalid secret", id);
+                        connecting.remove(&id);
+                        false
+                    }
                 }
                 Ok(Async::Ready(None)) => {
-                    events.push(SubscriberEvent::Disconnected(id));
+                    if accepted {
+                        events.push(SubscriberEvent::Disconnected(id));
+                    }
+                    connecting.remove(&id);
                     false
                 }
                 Err(err) => {
-                    events.push(SubscriberEvent::Error(id, err));
+                    if accepted {
+                        events.push(SubscriberEvent::Error(id, err));
+                    }
+                    connecting.remove(&id);
                     false
                 }
             }
@@ -107,8 +193,18 @@ impl PublisherServer {
     }
 }
 
+/// Checks the secret presented in the first message of a subscriber.
+fn authenticate(secret: Option<u64>, mut msg: MessageBuf) -> bool {
+    match msg.pop::<Option<u64>>() {
+        Ok(presented) => secret.is_none() || presented == secret,
+        Err(_) => false,
+    }
+}
+
 pub enum SubscriberEvent {
     Accepted(SubscriberId, Sender),
+    /// A request sent by the subscriber
+    Message(SubscriberId, MessageBuf),
     Error(SubscriberId, Error),
     Disconnected(SubscriberId),
 }
@@ -139,7 +235,8 @@ impl Stream for PublisherServer {
     }
 }
 
-struct Nop;
+/// Polls futures outside of an event loop, they are polled periodically.
+pub(crate) struct Nop;
 impl Notify for Nop {
     fn notify(&self, _: usize) {}
 }
@@ -147,6 +244,7 @@ impl Notify for Nop {
 struct PollServer {
     server: Spawn<PublisherServer>,
     notify: Arc<Nop>,
+    secret: Option<u64>,
 }
 
 impl PollServer {
@@ -164,6 +262,7 @@ impl PollServer {
 impl From<PublisherServer> for PollServer {
     fn from(server: PublisherServer) -> Self {
         PollServer {
+            secret: server.secret(),
             server: executor::spawn(server),
             notify: Arc::new(Nop),
         }
@@ -0,0 +1,335 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Retention of published batches, allowing late subscribers of a stream
+//! topic to replay data published before they connected.
+//!
+//! Batches are either kept in memory or appended to segment files on disk.
+//! A segment file is deleted once all of its batches have been evicted.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Result, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use rand;
+
+use strymon_communication::message::MessageBuf;
+
+/// Maximum size of a segment file before a new one is started.
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Decides when retained batches are evicted.
+#[derive(Clone, Debug)]
+pub enum Limit {
+    /// Keeps batches published within the given duration
+    Duration(Duration),
+    /// Keeps at most the given number of bytes of encoded batches
+    Size(usize),
+}
+
+/// Where retained batches are kept.
+#[derive(Clone, Debug)]
+pub enum Storage {
+    Memory,
+    /// Spills batches to files in the given directory
+    Disk(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct Retention {
+    pub limit: Limit,
+    pub storage: Storage,
+}
+
+impl Retention {
+    pub fn new(limit: Limit, storage: Storage) -> Self {
+        Retention {
+            limit: limit,
+            storage: storage,
+        }
+    }
+}
+
+enum Location {
+    Memory(MessageBuf),
+    Disk { segment: u64, offset: u64 },
+}
+
+struct Entry<T> {
+    time: T,
+    published: Instant,
+    size: usize,
+    location: Location,
+}
+
+struct Segment {
+    id: u64,
+    file: File,
+    len: u64,
+    /// Number of retained batches stored in this segment
+    entries: usize,
+}
+
+/// Segment files of a single buffer, in a directory owned by the buffer.
+struct Segments {
+    dir: PathBuf,
+    segments: VecDeque<Segment>,
+    next_id: u64,
+}
+
+impl Segments {
+    fn new(parent: &PathBuf) -> Result<Self> {
+        let dir = parent.join(format!("replay-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir)?;
+
+        Ok(Segments {
+            dir: dir,
+            segments: VecDeque::new(),
+            next_id: 0,
+        })
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.seg", id))
+    }
+
+    fn append(&mut self, buf: &MessageBuf) -> Result<(u64, u64)> {
+        let full = self.segments.back().map_or(true, |s| s.len >= SEGMENT_SIZE);
+        if full {
+            let id = self.next_id;
+            self.next_id += 1;
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(self.path(id))?;
+            self.segments.push_back(Segment {
+                id: id,
+                file: file,
+                len: 0,
+                entries: 0,
+            });
+        }
+
+        let segment = self.segments.back_mut().unwrap();
+        let offset = segment.len;
+        buf.write(&mut segment.file)?;
+        segment.len = segment.file.seek(SeekFrom::End(0))?;
+        segment.entries += 1;
+
+        Ok((segment.id, offset))
+    }
+
+    fn read(&mut self, id: u64, offset: u64) -> Result<MessageBuf> {
+        let segment = self.segments
+            .iter_mut()
+            .find(|s| s.id == id)
+            .expect("retained batch without segment");
+        segment.file.seek(SeekFrom::Start(offset))?;
+        let buf = MessageBuf::read(&mut segment.file)?;
+        Ok(buf.expect("truncated segment file"))
+    }
+
+    fn evict(&mut self, id: u64) {
+        if let Some(segment) = self.segments.iter_mut().find(|s| s.id == id) {
+            segment.entries -= 1;
+        }
+
+        // the last segment is still being written to
+        while self.segments.len() > 1 && self.segments[0].entries == 0 {
+            let segment = self.segments.pop_front().unwrap();
+            if let Err(err) = fs::remove_file(self.path(segment.id)) {
+                warn!("failed to remove segment file: {}", err);
+            }
+        }
+    }
+}
+
+impl Drop for Segments {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.dir) {
+            warn!("failed to remove retention directory: {}", err);
+        }
+    }
+}
+
+/// Retained batches of a stream topic, in the order they were published.
+pub struct Buffer<T> {
+    limit: Limit,
+    entries: VecDeque<Entry<T>>,
+    size: usize,
+    segments: Option<Segments>,
+}
+
+impl<T> Buffer<T> {
+    pub fn new(retention: Retention) -> Result<Self> {
+        let segments = match retention.storage {
+            Storage::Memory => None,
+            Storage::Disk(ref dir) => Some(Segments::new(dir)?),
+        };
+
+        Ok(Buffer {
+            limit: retention.limit,
+            entries: VecDeque::new(),
+            size: 0,
+            segments: segments,
+        })
+    }
+
+    /// Retains an encoded batch with the given timestamp.
+    pub fn append(&mut self, time: T, buf: &MessageBuf) -> Result<()> {
+        let location = match self.segments {
+            Some(ref mut segments) => {
+                let (segment, offset) = segments.append(buf)?;
+                Location::Disk {
+                    segment: segment,
+                    offset: offset,
+                }
+            }
+            None => Location::Memory(buf.clone()),
+        };
+
+        self.size += buf.len();
+        self.entries.push_back(Entry {
+            time: time,
+            published: Instant::now(),
+            size: buf.len(),
+            location: location,
+        });
+
+        self.evict();
+        Ok(())
+    }
+
+    fn evict(&mut self) {
+        let now = Instant::now();
+        loop {
+            let expired = match (self.entries.front(), &self.limit) {
+                (Some(entry), &Limit::Duration(max)) => now.duration_since(entry.published) > max,
+                (Some(_), &Limit::Size(max)) => self.size > max,
+                (None, _) => false,
+            };
+
+            if !expired {
+                break;
+            }
+
+            let entry = self.entries.pop_front().unwrap();
+            self.size -= entry.size;
+            if let (Location::Disk { segment, .. }, Some(segments)) =
+                (entry.location, self.segments.as_mut()) {
+                segments.evict(segment);
+            }
+        }
+    }
+
+    /// Returns all retained batches whose timestamp is not less than `from`,
+    /// in the order they were published.
+    pub fn replay<F>(&mut self, from: &T, less_equal: F) -> Result<Vec<MessageBuf>>
+        where F: Fn(&T, &T) -> bool
+    {
+        self.evict();
+
+        let mut batches = Vec::new();
+        for entry in self.entries.iter().filter(|e| less_equal(from, &e.time)) {
+            let buf = match entry.location {
+                Location::Memory(ref buf) => buf.clone(),
+                Location::Disk { segment, offset } => {
+                    self.segments.as_mut().unwrap().read(segment, offset)?
+                }
+            };
+            batches.push(buf);
+        }
+
+        Ok(batches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use rand;
+
+    use strymon_communication::message::MessageBuf;
+
+    use super::{Buffer, Limit, Retention, Storage};
+
+    fn batch(i: u64) -> MessageBuf {
+        MessageBuf::new(format!("batch {}", i)).unwrap()
+    }
+
+    fn replay_all(buffer: &mut Buffer<u64>, from: u64) -> Vec<String> {
+        buffer.replay(&from, |a, b| a <= b)
+            .unwrap()
+            .into_iter()
+            .map(|mut buf| buf.pop::<String>().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replay_from_time() {
+        let retention = Retention::new(Limit::Size(usize::max_value()), Storage::Memory);
+        let mut buffer = Buffer::new(retention).unwrap();
+        for i in 0..4 {
+            buffer.append(i, &batch(i)).unwrap();
+        }
+
+        assert_eq!(replay_all(&mut buffer, 2), vec!["batch 2", "batch 3"]);
+        assert_eq!(replay_all(&mut buffer, 0).len(), 4);
+    }
+
+    #[test]
+    fn evict_by_size() {
+        let size = batch(0).len();
+        let retention = Retention::new(Limit::Size(2 * size), Storage::Memory);
+        let mut buffer = Buffer::new(retention).unwrap();
+        for i in 0..4 {
+            buffer.append(i, &batch(i)).unwrap();
+        }
+
+        assert_eq!(replay_all(&mut buffer, 0), vec!["batch 2", "batch 3"]);
+    }
+
+    #[test]
+    fn evict_by_duration() {
+        let limit = Limit::Duration(Duration::from_millis(50));
+        let mut buffer = Buffer::new(Retention::new(limit, Storage::Memory)).unwrap();
+        buffer.append(0, &batch(0)).unwrap();
+        assert_eq!(replay_all(&mut buffer, 0), vec!["batch 0"]);
+
+        thread::sleep(Duration::from_millis(100));
+        buffer.append(1, &batch(1)).unwrap();
+        assert_eq!(replay_all(&mut buffer, 0), vec!["batch 1"]);
+    }
+
+    #[test]
+    fn replay_from_disk() {
+        let dir = env::temp_dir().join(format!("strymon_retention_{}", rand::random::<u64>()));
+        let size = batch(0).len();
+        let retention = Retention::new(Limit::Size(3 * size), Storage::Disk(dir.clone()));
+        {
+            let mut buffer = Buffer::new(retention).unwrap();
+            for i in 0..5 {
+                buffer.append(i, &batch(i)).unwrap();
+            }
+
+            assert_eq!(replay_all(&mut buffer, 0), vec!["batch 2", "batch 3", "batch 4"]);
+            assert_eq!(replay_all(&mut buffer, 4), vec!["batch 4"]);
+        }
+
+        // segment files are removed together with the buffer
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
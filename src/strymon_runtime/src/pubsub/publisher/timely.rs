@@ -11,21 +11,38 @@ use std::marker::PhantomData;
 use std::collections::BTreeMap;
 
 use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
 
 use strymon_communication::Network;
-use strymon_communication::transport::Sender;
+use strymon_communication::transport::{Delivery, Sender};
 use strymon_communication::message::MessageBuf;
 
-use super::{PollServer, PublisherServer, SubscriberId, SubscriberEvent};
+use super::{Backpressure, PollServer, PublisherServer, SubscriberId, SubscriberEvent};
+use super::{queue_depth, send_batch};
+use super::retention::{Buffer, Retention};
+use pubsub::MessageKind;
+
+/// Batches retained for replay, along with the partial order of timestamps.
+struct Replay<T> {
+    buffer: Buffer<T>,
+    less_equal: fn(&T, &T) -> bool,
+}
 
 pub struct TimelyPublisher<T, D> {
     server: PollServer,
     subscribers: BTreeMap<SubscriberId, Sender>,
+    /// Subscribers which have not yet told us where to start
+    pending: BTreeMap<SubscriberId, Sender>,
+    replay: Option<Replay<T>>,
+    backpressure: Option<Backpressure>,
+    /// The frontier last announced to subscribers
+    frontier: Option<Vec<T>>,
+    closed: bool,
     marker: PhantomData<(T, D)>,
 }
 
 impl<T, D> TimelyPublisher<T, D>
-    where T: Serialize,
+    where T: Serialize + DeserializeOwned + Clone + PartialEq,
           D: Serialize
 {
     pub fn new(network: &Network) -> Result<((String, u16), Self)> {
@@ -39,33 +56,165 @@ impl<T, D> TimelyPublisher<T, D>
             TimelyPublisher {
                 server: PollServer::from(server),
                 subscribers: BTreeMap::new(),
+                pending: BTreeMap::new(),
+                replay: None,
+                backpressure: None,
+                frontier: None,
+                closed: false,
                 marker: PhantomData,
             }))
     }
 
-    pub fn publish(&mut self, frontier: &[T], time: &T, item: &[D]) -> Result<()> {
+    /// Secret subscribers have to present when connecting.
+    pub fn secret(&self) -> Option<u64> {
+        self.server.secret
+    }
+
+    /// Retains published batches, so that subscribers can request a replay
+    /// of the batches starting at a given timestamp.
+    pub fn retain(&mut self, retention: Retention, less_equal: fn(&T, &T) -> bool) -> Result<()> {
+        self.replay = Some(Replay {
+            buffer: Buffer::new(retention)?,
+            less_equal: less_equal,
+        });
+
+        Ok(())
+    }
+
+    /// Bounds the send queue of every subscriber, applies to subscribers
+    /// connecting after this call.
+    pub fn bound(&mut self, backpressure: Backpressure) {
+        self.backpressure = Some(backpressure);
+    }
+
+    /// Number of batches queued for the slowest subscriber.
+    pub fn queue_depth(&self) -> usize {
+        queue_depth(self.subscribers.values())
+    }
+
+    /// Handles connecting and disconnecting subscribers.
+    pub fn poll(&mut self) -> Result<()> {
         for event in self.server.poll_events()? {
             match event {
                 SubscriberEvent::Accepted(id, tx) => {
+                    if let Some(ref backpressure) = self.backpressure {
+                        backpressure.apply(&tx);
+                    }
+                    self.pending.insert(id, tx);
+                }
+                SubscriberEvent::Message(id, mut msg) => {
+                    let tx = match self.pending.remove(&id) {
+                        Some(tx) => tx,
+                        None => {
+                            error!("unexpected message from subscriber {:?}", id);
+                            self.subscribers.remove(&id);
+                            continue;
+                        }
+                    };
+
+                    let from = match msg.pop::<Option<T>>() {
+                        Ok(from) => from,
+                        Err(err) => {
+                            error!("invalid request from subscriber {:?}: {}", id, err);
+                            continue;
+                        }
+                    };
+
+                    // replay retained batches before any live ones
+                    if let (Some(from), Some(replay)) = (from, self.replay.as_mut()) {
+                        for buf in replay.buffer.replay(&from, replay.less_equal)? {
+                            send_batch(&tx, &buf);
+                        }
+                    }
+
+                    // allows the subscriber to advance before the next batch
+                    if self.closed {
+                        tx.send(frontier_message::<T>(MessageKind::Close, &[]));
+                    } else if let Some(ref frontier) = self.frontier {
+                        let buf = frontier_message(MessageKind::Progress, frontier);
+                        tx.send_with(buf, Delivery::Transient);
+                    }
+
                     self.subscribers.insert(id, tx);
                 }
                 SubscriberEvent::Disconnected(id) |
                 SubscriberEvent::Error(id, _) => {
+                    self.pending.remove(&id);
                     self.subscribers.remove(&id);
                 }
             }
         }
 
-        if !self.subscribers.is_empty() {
+        Ok(())
+    }
+
+    pub fn publish(&mut self, frontier: &[T], time: &T, item: &[D]) -> Result<()> {
+        self.poll()?;
+
+        if !self.subscribers.is_empty() || self.replay.is_some() {
             let mut buf = MessageBuf::empty();
+            buf.push(MessageKind::Batch).unwrap();
             buf.push::<&[D]>(item).unwrap();
             buf.push::<&T>(time).unwrap();
             buf.push::<&[T]>(frontier).unwrap();
 
             for sub in self.subscribers.values() {
-                sub.send(buf.clone())
+                send_batch(sub, &buf);
+            }
+
+            if let Some(ref mut replay) = self.replay {
+                replay.buffer.append(time.clone(), &buf)?;
             }
         }
+
+        self.frontier = Some(frontier.to_vec());
         Ok(())
     }
+
+    /// Announces the frontier to subscribers if it has changed since the
+    /// last published batch, allowing them to make progress even if no data
+    /// is published.
+    pub fn progress(&mut self, frontier: &[T]) -> Result<()> {
+        if self.frontier.as_ref().map_or(false, |f| &f[..] == frontier) {
+            return Ok(());
+        }
+
+        // every later message carries a newer frontier, so progress may be
+        // dropped for slow subscribers, unlike the closing message
+        if !self.subscribers.is_empty() {
+            let buf = frontier_message(MessageKind::Progress, frontier);
+            for sub in self.subscribers.values() {
+                sub.send_with(buf.clone(), Delivery::Transient);
+            }
+        }
+
+        self.frontier = Some(frontier.to_vec());
+        Ok(())
+    }
+
+    /// Informs subscribers that the stream is complete. Subscribers which
+    /// lose their connection without receiving this message treat the
+    /// publisher as lost.
+    pub fn close(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.poll()?;
+        let buf = frontier_message::<T>(MessageKind::Close, &[]);
+        for sub in self.subscribers.values() {
+            sub.send(buf.clone());
+        }
+
+        self.closed = true;
+        self.frontier = Some(Vec::new());
+        Ok(())
+    }
+}
+
+fn frontier_message<T: Serialize>(kind: MessageKind, frontier: &[T]) -> MessageBuf {
+    let mut buf = MessageBuf::empty();
+    buf.push(kind).unwrap();
+    buf.push(frontier).unwrap();
+    buf
 }
@@ -0,0 +1,293 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::io::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use std::ops::Add;
+use std::rc::Rc;
+
+use serde::ser::Serialize;
+
+use strymon_communication::Network;
+use strymon_communication::transport::Sender;
+use strymon_communication::message::MessageBuf;
+
+use super::{PollServer, PublisherServer, SubscriberId, SubscriberEvent};
+
+/// Maximum number of updates sent to a new subscriber in a single message
+/// of the initial snapshot.
+const SNAPSHOT_CHUNK: usize = 4096;
+
+/// The updates published before a subscriber was accepted, sent to it in
+/// chunks along with the frontier at that time.
+struct Snapshot<T, D, R> {
+    tx: Sender,
+    data: Rc<Vec<(D, T, R)>>,
+    frontier: Rc<Vec<T>>,
+    offset: usize,
+    /// Batches published while the snapshot is still being sent
+    backlog: Vec<MessageBuf>,
+}
+
+/// Publishes a collection whose updates carry the logical time at which
+/// they happened. Every message consists of a batch of `(item, time, diff)`
+/// updates, the frontier of the publisher and a flag which is only set if
+/// more chunks of the initial snapshot follow. New subscribers first receive
+/// a snapshot of all updates published so far. The last message carries an
+/// empty frontier, subscribers treat a connection closed before it as a lost
+/// publisher. Differences of type `R` are summed up, updates with a
+/// difference of `R::default()` are dropped.
+pub struct TimestampedPublisher<T, D, R = i32> {
+    server: PollServer,
+    subscribers: BTreeMap<SubscriberId, Sender>,
+    snapshots: BTreeMap<SubscriberId, Snapshot<T, D, R>>,
+    /// Consolidated updates per item, the times of completed updates are
+    /// advanced to the frontier
+    updates: HashMap<D, Vec<(T, R)>>,
+    /// Items with updates at each distinct time, so a frontier change only
+    /// visits the updates at times it has passed. May contain items whose
+    /// update at that time has since cancelled out.
+    times: Vec<(T, HashSet<D>)>,
+    frontier: Vec<T>,
+    less_equal: fn(&T, &T) -> bool,
+}
+
+impl<T, D, R> TimestampedPublisher<T, D, R>
+    where T: Serialize + Clone + PartialEq,
+          D: Serialize + Clone + Eq + Hash,
+          R: Serialize + Copy + Add<Output = R> + Default + PartialEq
+{
+    /// Creates a new publisher, `less_equal` compares timestamps in their
+    /// partial order.
+    pub fn new(network: &Network, less_equal: fn(&T, &T) -> bool) -> Result<((String, u16), Self)> {
+        let server = PublisherServer::new(network)?;
+        let addr = {
+            let (host, port) = server.external_addr();
+            (host.to_string(), port)
+        };
+
+        Ok((addr,
+            TimestampedPublisher {
+                server: PollServer::from(server),
+                subscribers: BTreeMap::new(),
+                snapshots: BTreeMap::new(),
+                updates: HashMap::new(),
+                times: Vec::new(),
+                frontier: Vec::new(),
+                less_equal: less_equal,
+            }))
+    }
+
+    /// Secret subscribers have to present when connecting.
+    pub fn secret(&self) -> Option<u64> {
+        self.server.secret
+    }
+
+    /// Publishes a batch of updates along with the current frontier. Batches
+    /// without any updates are only sent if the frontier has changed.
+    pub fn publish(&mut self, frontier: &[T], updates: &[(D, T, R)]) -> Result<()> {
+        let mut accepted = Vec::new();
+        for event in self.server.poll_events()? {
+            match event {
+                SubscriberEvent::Accepted(id, tx) => {
+                    accepted.push((id, tx));
+                }
+                SubscriberEvent::Message(id, _) => {
+                    error!("unexpected message from subscriber {:?}", id);
+                    self.subscribers.remove(&id);
+                    self.snapshots.remove(&id);
+                    accepted.retain(|&(other, _)| other != id);
+                }
+                SubscriberEvent::Disconnected(id) |
+                SubscriberEvent::Error(id, _) => {
+                    self.subscribers.remove(&id);
+                    self.snapshots.remove(&id);
+                    accepted.retain(|&(other, _)| other != id);
+                }
+            }
+        }
+
+        // subscribers still receiving their snapshot get the batch once it
+        // is complete
+        let progress = frontier != &self.frontier[..];
+        let listening = !self.subscribers.is_empty() || !self.snapshots.is_empty();
+        if listening && (progress || !updates.is_empty()) {
+            let mut buf = MessageBuf::empty();
+            buf.push::<&[(D, T, R)]>(updates).unwrap();
+            buf.push::<&[T]>(frontier).unwrap();
+            buf.push(false).unwrap();
+            for sub in self.subscribers.values() {
+                sub.send(buf.clone())
+            }
+            for snapshot in self.snapshots.values_mut() {
+                snapshot.backlog.push(buf.clone());
+            }
+        }
+
+        self.update_from(updates);
+        if progress {
+            self.frontier = frontier.to_vec();
+            self.advance();
+        }
+
+        // take a snapshot of the state including this batch for incoming
+        // subscribers, it is sent in chunks to not delay later batches
+        if !accepted.is_empty() {
+            let data: Vec<(D, T, R)> = self.updates
+                .iter()
+                .flat_map(|(item, times)| {
+                    times.iter().map(move |&(ref time, diff)| (item.clone(), time.clone(), diff))
+                })
+                .collect();
+            let data = Rc::new(data);
+            let frontier = Rc::new(self.frontier.clone());
+            for (id, tx) in accepted {
+                self.snapshots.insert(id,
+                                      Snapshot {
+                                          tx: tx,
+                                          data: data.clone(),
+                                          frontier: frontier.clone(),
+                                          offset: 0,
+                                          backlog: Vec::new(),
+                                      });
+            }
+        }
+
+        self.send_snapshots();
+
+        Ok(())
+    }
+
+    /// Sends the next chunk of every pending snapshot. Subscribers whose
+    /// snapshot is complete receive the batches published in the meantime.
+    fn send_snapshots(&mut self) {
+        let mut completed = Vec::new();
+        for (&id, snapshot) in self.snapshots.iter_mut() {
+            let end = cmp::min(snapshot.offset + SNAPSHOT_CHUNK, snapshot.data.len());
+            let mut buf = MessageBuf::empty();
+            buf.push::<&[(D, T, R)]>(&snapshot.data[snapshot.offset..end]).unwrap();
+            buf.push::<&[T]>(&snapshot.frontier).unwrap();
+            buf.push(end < snapshot.data.len()).unwrap();
+            snapshot.tx.send(buf);
+            snapshot.offset = end;
+
+            if snapshot.offset == snapshot.data.len() {
+                completed.push(id);
+            }
+        }
+
+        for id in completed {
+            let snapshot = self.snapshots.remove(&id).unwrap();
+            for buf in snapshot.backlog {
+                snapshot.tx.send(buf);
+            }
+            self.subscribers.insert(id, snapshot.tx);
+        }
+    }
+
+    /// Advances the times of completed updates to a single-element
+    /// frontier. This does not change the accumulated collection at any
+    /// later time, but allows updates of the same item to be merged.
+    fn advance(&mut self) {
+        if self.frontier.len() != 1 {
+            return;
+        }
+
+        let less_equal = self.less_equal;
+        let frontier = self.frontier[0].clone();
+        let (passed, pending) = self.times
+            .drain(..)
+            .partition::<Vec<_>, _>(|&(ref time, _)| {
+                *time != frontier && less_equal(time, &frontier)
+            });
+        self.times = pending;
+
+        for (time, items) in passed {
+            for item in items {
+                let remaining = match self.updates.get_mut(&item) {
+                    Some(times) => {
+                        // the update might have cancelled out in the meantime
+                        if let Some(pos) = times.iter().position(|&(ref t, _)| *t == time) {
+                            let (_, diff) = times.swap_remove(pos);
+                            accumulate(times, frontier.clone(), diff);
+                        }
+                        !times.is_empty()
+                    }
+                    None => continue,
+                };
+
+                if remaining {
+                    index(&mut self.times, &frontier, item);
+                } else {
+                    self.updates.remove(&item);
+                }
+            }
+        }
+    }
+
+    fn update_from(&mut self, updates: &[(D, T, R)]) {
+        for (item, time, diff) in updates.iter().cloned() {
+            if diff == R::default() {
+                continue;
+            }
+
+            index(&mut self.times, &time, item.clone());
+            match self.updates.entry(item) {
+                Entry::Occupied(mut entry) => {
+                    accumulate(entry.get_mut(), time, diff);
+                    if entry.get().is_empty() {
+                        entry.remove();
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(vec![(time, diff)]);
+                }
+            }
+        }
+    }
+}
+
+/// Records that `item` has an update at `time`. The number of distinct
+/// times is bounded by the times the frontier has not passed yet.
+fn index<T, D>(times: &mut Vec<(T, HashSet<D>)>, time: &T, item: D)
+    where T: PartialEq + Clone,
+          D: Eq + Hash
+{
+    match times.iter().position(|&(ref t, _)| t == time) {
+        Some(pos) => {
+            times[pos].1.insert(item);
+        }
+        None => {
+            let mut items = HashSet::new();
+            items.insert(item);
+            times.push((time.clone(), items));
+        }
+    }
+}
+
+/// Adds a difference to the updates of a single item, dropping the update
+/// if it cancels out. The updates of one item are usually few, so a linear
+/// scan is fine as timestamps are not hashable.
+fn accumulate<T, R>(times: &mut Vec<(T, R)>, time: T, diff: R)
+    where T: PartialEq,
+          R: Copy + Add<Output = R> + Default + PartialEq
+{
+    match times.iter().position(|&(ref t, _)| *t == time) {
+        Some(pos) => {
+            times[pos].1 = times[pos].1 + diff;
+            if times[pos].1 == R::default() {
+                times.swap_remove(pos);
+            }
+        }
+        None if diff != R::default() => times.push((time, diff)),
+        None => (),
+    }
+}
@@ -12,31 +12,89 @@ use std::marker::PhantomData;
 use futures::{Poll, Async};
 use futures::stream::Stream;
 
+use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
 
 use strymon_communication::Network;
 use strymon_communication::transport::{Sender, Receiver};
+use strymon_communication::message::MessageBuf;
 
-use model::Topic;
+use pubsub::decoder::Decoder;
+use pubsub::MessageKind;
 
 pub type CollectionSubscriber<D> = Subscriber<(D, i32)>;
 
 pub struct Subscriber<D> {
     rx: Receiver,
     _tx: Sender,
+    decoder: Option<Decoder>,
+    missed: u64,
+    /// Set for collection topics, whose messages mark the initial snapshot
+    collection: bool,
+    /// Set while more chunks of the initial snapshot are to be received
+    snapshot: bool,
     marker: PhantomData<D>,
 }
 
 impl<D> Subscriber<D> {
-    pub fn connect(topic: &Topic, network: &Network) -> Result<Self> {
-        let (tx, rx) = network.connect((&*topic.addr.0, topic.addr.1))?;
+    /// Connects to the publisher of a single partition of a topic,
+    /// presenting the secret of the partition. Public topics such as the
+    /// catalog do not require a secret.
+    pub fn connect(addr: &(String, u16), network: &Network, secret: Option<u64>) -> Result<Self> {
+        let (tx, rx) = network.connect((&*addr.0, addr.1))?;
+        tx.send(MessageBuf::new(secret)?);
 
         Ok(Subscriber {
             rx: rx,
             _tx: tx,
+            decoder: None,
+            missed: 0,
+            collection: false,
+            snapshot: false,
             marker: PhantomData,
         })
     }
+
+    /// Connects to the publisher of a partition of a collection topic. Its
+    /// initial snapshot may arrive in several batches, see `in_snapshot`.
+    pub fn connect_collection(addr: &(String, u16),
+                              network: &Network,
+                              secret: Option<u64>)
+                              -> Result<Self> {
+        let mut sub = Self::connect(addr, network, secret)?;
+        sub.collection = true;
+        sub.snapshot = true;
+        Ok(sub)
+    }
+
+    /// Returns true while the initial snapshot of a collection is still
+    /// incomplete, i.e. the batches received so far are only part of it.
+    pub fn in_snapshot(&self) -> bool {
+        self.snapshot
+    }
+
+    /// Decode published data with the given decoder, used if the schema of
+    /// the publisher differs from `D`.
+    pub fn decode_with(&mut self, decoder: Decoder) {
+        self.decoder = Some(decoder);
+    }
+
+    /// Number of batches the publisher dropped because this subscriber was
+    /// too slow.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+/// Reads the number of dropped batches a publisher may append to a batch.
+fn pop_gap(buf: &mut MessageBuf) -> Result<u64> {
+    if buf.is_empty() {
+        Ok(0)
+    } else {
+        let dropped = buf.pop::<u64>()?;
+        warn!("publisher dropped {} batches, subscriber is too slow", dropped);
+        Ok(dropped)
+    }
 }
 
 impl<D: DeserializeOwned> Stream for Subscriber<D> {
@@ -45,7 +103,15 @@ impl<D: DeserializeOwned> Stream for Subscriber<D> {
 
     fn poll(&mut self) -> Poll<Option<Vec<D>>, Error> {
         let data = if let Some(mut buf) = try_ready!(self.rx.poll()) {
-            let vec = buf.pop::<Vec<D>>()?;
+            let vec = match self.decoder {
+                Some(ref decoder) => decoder.pop::<D>(&mut buf)?,
+                None => buf.pop::<Vec<D>>()?,
+            };
+            if self.collection {
+                self.snapshot = buf.pop::<bool>()?;
+            } else {
+                self.missed += pop_gap(&mut buf)?;
+            }
             Some(vec)
         } else {
             None
@@ -55,38 +121,161 @@ impl<D: DeserializeOwned> Stream for Subscriber<D> {
     }
 }
 
+/// A message received from the publisher of a stream topic.
+#[derive(Clone, Debug)]
+pub enum TimelyMessage<T, D> {
+    /// A batch of data at a timestamp, along with the frontier of the
+    /// publisher
+    Batch(Vec<T>, T, Vec<D>),
+    /// The frontier of the publisher has advanced without any data
+    Progress(Vec<T>),
+    /// The stream is complete, the publisher will not send anything else
+    Close,
+}
+
 pub struct TimelySubscriber<T, D> {
     rx: Receiver,
     _tx: Sender,
+    decoder: Option<Decoder>,
+    missed: u64,
     marker: PhantomData<(T, D)>,
 }
 
-impl<T, D> TimelySubscriber<T, D> {
-    pub fn connect(topic: &Topic, network: &Network) -> Result<Self> {
-        let (tx, rx) = network.connect((&*topic.addr.0, topic.addr.1))?;
+impl<T: Serialize, D> TimelySubscriber<T, D> {
+    /// Connects to the publisher of a single partition of a topic,
+    /// presenting the secret of the partition.
+    pub fn connect(addr: &(String, u16), network: &Network, secret: Option<u64>) -> Result<Self> {
+        TimelySubscriber::connect_from(addr, network, secret, None)
+    }
+
+    /// Connects to the publisher of a single partition of a topic, asking
+    /// it to replay the batches it has retained starting at `from`.
+    pub fn connect_from(addr: &(String, u16),
+                        network: &Network,
+                        secret: Option<u64>,
+                        from: Option<T>)
+                        -> Result<Self> {
+        let (tx, rx) = network.connect((&*addr.0, addr.1))?;
+        tx.send(MessageBuf::new(secret)?);
+
+        // the publisher waits for this request before sending any data
+        tx.send(MessageBuf::new(from)?);
 
         Ok(TimelySubscriber {
             rx: rx,
             _tx: tx,
+            decoder: None,
+            missed: 0,
             marker: PhantomData,
         })
     }
+
+    /// Decode published data with the given decoder, used if the schema of
+    /// the publisher differs from `D`.
+    pub fn decode_with(&mut self, decoder: Decoder) {
+        self.decoder = Some(decoder);
+    }
+
+    /// Number of batches the publisher dropped because this subscriber was
+    /// too slow.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
 }
 
 impl<T, D> Stream for TimelySubscriber<T, D>
     where T: DeserializeOwned,
           D: DeserializeOwned
 {
-    type Item = (Vec<T>, T, Vec<D>);
+    type Item = TimelyMessage<T, D>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        let msg = if let Some(mut buf) = try_ready!(self.rx.poll()) {
+            match buf.pop::<MessageKind>()? {
+                MessageKind::Batch => {
+                    let data = match self.decoder {
+                        Some(ref decoder) => decoder.pop::<D>(&mut buf)?,
+                        None => buf.pop::<Vec<D>>()?,
+                    };
+                    let time = buf.pop::<T>()?;
+                    let frontier = buf.pop::<Vec<T>>()?;
+                    self.missed += pop_gap(&mut buf)?;
+
+                    Some(TimelyMessage::Batch(frontier, time, data))
+                }
+                MessageKind::Progress => {
+                    let frontier = buf.pop::<Vec<T>>()?;
+                    Some(TimelyMessage::Progress(frontier))
+                }
+                MessageKind::Close => Some(TimelyMessage::Close),
+            }
+        } else {
+            None
+        };
+
+        Ok(Async::Ready(msg))
+    }
+}
+
+/// Receives batches of `(item, time, diff)` updates and the frontier of the
+/// publisher, starting with a snapshot of the whole collection.
+pub struct TimestampedSubscriber<T, D, R = i32> {
+    rx: Receiver,
+    _tx: Sender,
+    decoder: Option<Decoder>,
+    /// Set while more chunks of the initial snapshot are to be received
+    snapshot: bool,
+    marker: PhantomData<(T, D, R)>,
+}
+
+impl<T, D, R> TimestampedSubscriber<T, D, R> {
+    /// Connects to the publisher of a single partition of a topic,
+    /// presenting the secret of the partition.
+    pub fn connect(addr: &(String, u16), network: &Network, secret: Option<u64>) -> Result<Self> {
+        let (tx, rx) = network.connect((&*addr.0, addr.1))?;
+        tx.send(MessageBuf::new(secret)?);
+
+        Ok(TimestampedSubscriber {
+            rx: rx,
+            _tx: tx,
+            decoder: None,
+            snapshot: true,
+            marker: PhantomData,
+        })
+    }
+
+    /// Returns true while the initial snapshot is still incomplete. The
+    /// frontier received along with a partial snapshot is not reached yet.
+    pub fn in_snapshot(&self) -> bool {
+        self.snapshot
+    }
+
+    /// Decode published updates with the given decoder, used if the schema
+    /// of the publisher differs from `(D, T, R)`.
+    pub fn decode_with(&mut self, decoder: Decoder) {
+        self.decoder = Some(decoder);
+    }
+}
+
+impl<T, D, R> Stream for TimestampedSubscriber<T, D, R>
+    where T: DeserializeOwned,
+          D: DeserializeOwned,
+          R: DeserializeOwned
+{
+    type Item = (Vec<(D, T, R)>, Vec<T>);
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
         let data = if let Some(mut buf) = try_ready!(self.rx.poll()) {
-            let data = buf.pop::<Vec<D>>()?;
-            let time = buf.pop::<T>()?;
+            let updates = match self.decoder {
+                Some(ref decoder) => decoder.pop::<(D, T, R)>(&mut buf)?,
+                None => buf.pop::<Vec<(D, T, R)>>()?,
+            };
             let frontier = buf.pop::<Vec<T>>()?;
+            self.snapshot = buf.pop::<bool>()?;
 
-            Some((frontier, time, data))
+            Some((updates, frontier))
         } else {
             None
         };
@@ -9,10 +9,10 @@
 use std::io::Error as IoError;
 use std::net::ToSocketAddrs;
 
-use futures::Future;
+use futures::{future, Future};
 
 use coordinator::requests::*;
-use query::Coordinator;
+use query::{Coordinator, Pending};
 
 #[derive(Debug)]
 pub enum KeeperWorkerRegistrationError {
@@ -131,29 +131,51 @@ impl Coordinator {
          worker_num: usize,
          addr: A)
          -> Result<(), KeeperWorkerRegistrationError> {
-        let addr = match addr.to_socket_addrs()?.next() {
-            Some(addr) => addr,
-            None => return Err(KeeperWorkerRegistrationError::SocketAddrsNotValid),
+        self.add_keeper_worker_async(name, worker_num, addr).wait()
+    }
+
+    /// Non-blocking version of `add_keeper_worker`.
+    pub fn add_keeper_worker_async<A: ToSocketAddrs>
+        (&self,
+         name: &str,
+         worker_num: usize,
+         addr: A)
+         -> Pending<(), KeeperWorkerRegistrationError> {
+        let addr = match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            Ok(None) => {
+                return Box::new(future::err(KeeperWorkerRegistrationError::SocketAddrsNotValid))
+            }
+            Err(err) => return Box::new(future::err(err.into())),
         };
         let addr = (addr.ip().to_string(), addr.port());
-        self.tx
+        let response = self.tx
             .request(&AddKeeperWorker {
                           name: name.to_string(),
                           worker_num: worker_num,
                           addr: addr,
                       })
-            .map_err(KeeperWorkerRegistrationError::from)
-            .wait()
+            .map_err(KeeperWorkerRegistrationError::from);
+
+        Box::new(response)
     }
 
     /// Returns the address of the requested Keeper.
     pub fn get_keeper_address(&self,
                               name: &str)
                               -> Result<(String, u16), KeeperLookupError> {
-        self.tx
+        self.get_keeper_address_async(name).wait()
+    }
+
+    /// Non-blocking version of `get_keeper_address`.
+    pub fn get_keeper_address_async(&self,
+                                    name: &str)
+                                    -> Pending<(String, u16), KeeperLookupError> {
+        let response = self.tx
             .request(&GetKeeperAddress { name: name.to_string() })
-            .map_err(KeeperLookupError::from)
-            .wait()
+            .map_err(KeeperLookupError::from);
+
+        Box::new(response)
     }
 
     /// To be called when unregistering.
@@ -161,12 +183,21 @@ impl Coordinator {
                                 name: &str,
                                 worker_num: usize)
                                 -> Result<(), WorkerDeregistrationError> {
-        self.tx
+        self.remove_keeper_worker_async(name, worker_num).wait()
+    }
+
+    /// Non-blocking version of `remove_keeper_worker`.
+    pub fn remove_keeper_worker_async(&self,
+                                      name: &str,
+                                      worker_num: usize)
+                                      -> Pending<(), WorkerDeregistrationError> {
+        let response = self.tx
             .request(&RemoveKeeperWorker {
                           name: name.to_string(),
                           worker_num: worker_num,
                       })
-            .map_err(WorkerDeregistrationError::from)
-            .wait()
+            .map_err(WorkerDeregistrationError::from);
+
+        Box::new(response)
     }
 }
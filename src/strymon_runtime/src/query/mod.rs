@@ -7,7 +7,9 @@
 // except according to those terms.
 
 use std::io::{Error as IoError, ErrorKind};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use timely_communication::{Allocator, WorkerGuards};
 use timely::progress::Timestamp;
@@ -16,27 +18,54 @@ use timely::progress::nested::product::Product;
 use timely::{self, Configuration};
 use timely::dataflow::scopes::Root;
 
-use futures::Future;
+use futures::{Future, Stream};
 
 use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
 
 use strymon_communication::Network;
-use strymon_communication::rpc::Outgoing;
+use strymon_communication::rpc::{Incoming, Outgoing, Request, Response};
 
 use executor::executable::NativeExecutable;
-use model::QueryId;
-use coordinator::requests::{AddWorkerGroup, QueryToken};
+use model::{Acl, QueryId};
+use coordinator::requests::{AddWorkerGroup, QueryToken, WorkerGroupError};
 
 pub mod subscribe;
 pub mod publish;
 pub mod keepers;
+pub mod source;
+
+/// A request which has been sent to the coordinator, but not yet answered.
+/// Responses are received in the background, the future can thus be polled
+/// from within an operator without blocking the worker, or be waited on.
+pub type Pending<T, E> = Box<Future<Item = T, Error = E>>;
+
+/// Delay between two attempts to reattach to a lost coordinator
+const RECONNECT_INTERVAL_SECS: u64 = 5;
+
+/// The connection to the coordinator, shared by all workers of a process.
+/// It is replaced once the process has reattached to a restarted coordinator.
+#[derive(Clone)]
+struct Connection {
+    tx: Arc<Mutex<Outgoing>>,
+}
+
+impl Connection {
+    fn request<R: Request>(&self, r: &R) -> Response<R> {
+        self.tx.lock().expect("reattaching thread panicked").request(r)
+    }
+}
 
 #[derive(Clone)]
 pub struct Coordinator {
     token: QueryToken,
     network: Network,
-    tx: Outgoing,
+    tx: Connection,
+    // index of the local worker and total number of workers
+    index: usize,
+    peers: usize,
+    /// ACL of topics published through this handle
+    acl: Acl,
 }
 
 fn initialize(id: QueryId,
@@ -44,11 +73,12 @@ fn initialize(id: QueryId,
               coord: String)
               -> Result<Coordinator, IoError> {
     let network = Network::init()?;
-    let (tx, _) = network.client(&*coord)?;
+    let (tx, rx) = network.client(&*coord)?;
 
     let announce = tx.request(&AddWorkerGroup {
         query: id,
         group: process,
+        reattach: None,
     });
 
     let token = announce.wait()
@@ -60,13 +90,78 @@ fn initialize(id: QueryId,
         })
         .map_err(Result::unwrap_err)?;
 
+    let tx = Connection { tx: Arc::new(Mutex::new(tx)) };
+    {
+        let (network, conn) = (network.clone(), tx.clone());
+        thread::spawn(move || watch(rx, &network, &coord, process, token, &conn));
+    }
+
     Ok(Coordinator {
         tx: tx,
         network: network,
         token: token,
+        index: 0,
+        peers: 1,
+        acl: Acl::default(),
     })
 }
 
+/// Waits for the connection to the coordinator to be closed, e.g. because
+/// the coordinator has been restarted, and then reattaches this worker group
+/// using its query token. Returns once the coordinator refuses to take the
+/// worker group back.
+fn watch(rx: Incoming,
+         network: &Network,
+         coord: &str,
+         process: usize,
+         token: QueryToken,
+         conn: &Connection) {
+    let mut connected = Some(rx);
+    loop {
+        if let Some(rx) = connected.take() {
+            // the coordinator never sends requests to queries
+            for _ in rx.wait() {}
+            warn!("lost connection to coordinator at {:?}", coord);
+        }
+
+        thread::sleep(Duration::from_secs(RECONNECT_INTERVAL_SECS));
+        match reattach(network, coord, process, token, conn) {
+            Ok(rx) => connected = rx,
+            Err(err) => return error!("failed to reattach to coordinator: {:?}", err),
+        }
+    }
+}
+
+/// Tries to reattach to the coordinator, returns `None` if it is not
+/// reachable yet.
+fn reattach(network: &Network,
+            coord: &str,
+            process: usize,
+            token: QueryToken,
+            conn: &Connection)
+            -> Result<Option<Incoming>, WorkerGroupError> {
+    info!("reattaching to coordinator at {:?}", coord);
+    let (tx, rx) = match network.client(coord) {
+        Ok(client) => client,
+        Err(_) => return Ok(None),
+    };
+
+    let announce = tx.request(&AddWorkerGroup {
+        query: token.id,
+        group: process,
+        reattach: Some(token),
+    });
+
+    match announce.wait() {
+        Ok(_) => {
+            *conn.tx.lock().expect("worker thread panicked") = tx;
+            Ok(Some(rx))
+        }
+        Err(Ok(err)) => Err(err),
+        Err(Err(_)) => Ok(None),
+    }
+}
+
 pub fn execute<T, F>(func: F) -> Result<WorkerGuards<T>, String>
     where T: Send + 'static,
           F: Fn(&mut Root<Allocator>, Coordinator) -> T,
@@ -100,7 +195,9 @@ pub fn execute<T, F>(func: F) -> Result<WorkerGuards<T>, String>
     // wrap in mutex because timely requires `Sync` for some reason
     let coord = Mutex::new(coord);
     timely::execute(timely_conf, move |root| {
-        let coord = coord.lock().unwrap().clone();
+        let mut coord = coord.lock().unwrap().clone();
+        coord.index = root.index();
+        coord.peers = root.peers();
         func(root, coord)
     })
 }
@@ -108,7 +205,7 @@ pub fn execute<T, F>(func: F) -> Result<WorkerGuards<T>, String>
 /// This is a helper trait to workaround the fact that Rust does not allow
 /// us to implement Serde's traits for Timely's custom timestamp types.
 pub trait PubSubTimestamp: Timestamp {
-    type Converted: Serialize + DeserializeOwned;
+    type Converted: Serialize + DeserializeOwned + Clone + PartialEq;
 
     fn to_pubsub(&self) -> Self::Converted;
     fn from_pubsub(converted: Self::Converted) -> Self;
@@ -6,7 +6,13 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::io::Error as IoError;
+use std::fmt;
+use std::cell::Cell;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::ops::Add;
+use std::io::{Error as IoError, ErrorKind};
 
 use timely::ExchangeData;
 use timely::progress::Timestamp;
@@ -17,13 +23,22 @@ use timely::dataflow::operators::Unary;
 use timely::dataflow::channels::pact::{Exchange, ParallelizationContract, Pipeline};
 use timely_communication::{Allocate, Pull, Push};
 
+use differential_dataflow::{AsCollection, Collection};
+
 use serde::ser::Serialize;
-use futures::Future;
+use serde::de::DeserializeOwned;
+use futures::{future, Async, Future};
+use futures::executor::{self, Spawn};
+use futures::unsync::oneshot::{channel, Sender};
 
-use query::{Coordinator, PubSubTimestamp};
+use query::{Coordinator, Pending, PubSubTimestamp};
 use coordinator::requests::*;
-use model::{Topic, TopicId, TopicType, TopicSchema};
+use model::{Acl, Compatibility, Topic, TopicId, TopicSchema, Schema};
+use model::schema::SchemaError;
 use pubsub::publisher::timely::TimelyPublisher;
+use pubsub::publisher::{Backpressure, Nop};
+use pubsub::publisher::retention::Retention;
+use pubsub::publisher::timestamped::TimestampedPublisher;
 use pubsub::publisher::collection::CollectionPublisher;
 
 #[derive(Debug)]
@@ -32,6 +47,17 @@ pub enum PublicationError {
     TopicNotFound,
     AuthenticationFailure,
     TypeIdMismatch,
+    /// The coordinator rejected the schema of the published data
+    InvalidSchema,
+    /// No schema can be derived for the published data type
+    UnsupportedType(SchemaError),
+    /// Another worker published a different partitioning of the topic
+    PartitionMismatch,
+    /// Another partition of the topic could not be published
+    PartitionLost,
+    /// The ACL of a previous topic with the same name does not allow us
+    /// to publish it
+    PermissionDenied,
     IoError(IoError),
 }
 
@@ -39,11 +65,21 @@ impl From<PublishError> for PublicationError {
     fn from(err: PublishError) -> Self {
         match err {
             PublishError::TopicAlreadyExists => PublicationError::TopicAlreadyExists,
+            PublishError::InvalidSchema => PublicationError::InvalidSchema,
+            PublishError::PartitionMismatch => PublicationError::PartitionMismatch,
+            PublishError::PartitionLost => PublicationError::PartitionLost,
+            PublishError::PermissionDenied => PublicationError::PermissionDenied,
             err => panic!("failed to publish: {:?}", err),
         }
     }
 }
 
+impl From<SchemaError> for PublicationError {
+    fn from(err: SchemaError) -> Self {
+        PublicationError::UnsupportedType(err)
+    }
+}
+
 impl From<IoError> for PublicationError {
     fn from(err: IoError) -> Self {
         PublicationError::IoError(err)
@@ -73,15 +109,70 @@ impl<T, E> From<Result<T, E>> for PublicationError
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum Partition {
+pub enum Partition<D> {
     PerWorker,
     Merge,
+    /// Publishes a single topic with one partition per worker, records are
+    /// placed into partitions by the hash of their key
+    Hash(fn(&D) -> u64),
+}
+
+impl<D> Clone for Partition<D> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<D> Copy for Partition<D> {}
+
+impl<D> fmt::Debug for Partition<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Partition::PerWorker => write!(f, "PerWorker"),
+            Partition::Merge => write!(f, "Merge"),
+            Partition::Hash(_) => write!(f, "Hash"),
+        }
+    }
+}
+
+/// Options for publishing a stream with `publish_with_options`.
+#[derive(Clone, Debug)]
+pub struct PublishOptions {
+    /// Schema evolution rules for subscribers of the topic
+    pub compatibility: Compatibility,
+    /// Retains published batches for subscribers using `subscribe_from`
+    pub retention: Option<Retention>,
+    /// Bounds the send queue of every subscriber
+    pub backpressure: Option<Backpressure>,
+}
+
+impl Default for PublishOptions {
+    fn default() -> Self {
+        PublishOptions {
+            compatibility: Compatibility::None,
+            retention: None,
+            backpressure: None,
+        }
+    }
+}
+
+/// Resolves to the topic published by the local worker, if any.
+pub type PendingTopic = Pending<Option<Topic>, PublicationError>;
+
+/// Number of batches queued for the slowest subscriber of a publication,
+/// updated every time the publishing operator is scheduled.
+#[derive(Clone, Debug, Default)]
+pub struct QueueDepth(Rc<Cell<usize>>);
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.0.get()
+    }
 }
 
 const PUBLISH_WORKER_ID: u64 = 0;
 
-impl<T: Timestamp, D: ExchangeData> ParallelizationContract<T, D> for Partition {
+impl<T: Timestamp, D: ExchangeData> ParallelizationContract<T, D> for Partition<D> {
     type Pusher = Box<Push<(T, Content<D>)>>;
     type Puller = Box<Pull<(T, Content<D>)>>;
 
@@ -99,94 +190,317 @@ impl<T: Timestamp, D: ExchangeData> ParallelizationContract<T, D> for Partition
                 let (push, pull) = Exchange::new(|_| PUBLISH_WORKER_ID).connect(allocator, identifier);
                (push, Box::new(pull))
             }
+            Partition::Hash(key) => {
+                let (push, pull) = Exchange::new(move |d| key(d)).connect(allocator, identifier);
+                (push, Box::new(pull))
+            }
         }
     }
 }
 
-impl Partition {
+impl<D> Partition<D> {
     fn name(&self, name: &str, worker_id: u64) -> Option<String> {
         match *self {
             Partition::PerWorker => Some(format!("{}.{}", name, worker_id)),
             Partition::Merge if (worker_id == PUBLISH_WORKER_ID) => {
                 Some(String::from(name))
             }
+            Partition::Hash(_) => Some(String::from(name)),
             _ => None,
         }
     }
+
+    /// Returns the partition hosted by the local worker and the total
+    /// number of partitions of the topic.
+    fn partition(&self, worker_id: u64, peers: usize) -> (usize, usize) {
+        match *self {
+            Partition::Hash(_) => (worker_id as usize, peers),
+            _ => (0, 1),
+        }
+    }
+}
+
+/// Compares converted timestamps using the order of the original ones.
+fn less_equal<T: PubSubTimestamp>(a: &T::Converted, b: &T::Converted) -> bool {
+    T::from_pubsub(a.clone()).less_equal(&T::from_pubsub(b.clone()))
+}
+
+/// Updates of a timestamped collection happen at the time of their record.
+fn collection_update<D: Clone, T: Clone>(record: &(D, i32), time: &T) -> (D, T, i32) {
+    (record.0.clone(), time.clone(), record.1)
+}
+
+/// Updates of a differential collection may be at times later than their
+/// capability.
+fn differential_update<D: Clone, T: Clone, R: Copy>(record: &(D, T, R), _: &T) -> (D, T, R) {
+    (record.0.clone(), record.1.clone(), record.2)
 }
 
 struct Publication {
     topic: Topic,
     coord: Coordinator,
+    // only the first partition removes a partitioned topic
+    owner: bool,
 }
 
 impl Drop for Publication {
     fn drop(&mut self) {
-        if let Err(err) = self.coord.unpublish(self.topic.id) {
-            warn!("failed to unpublish: {:?}", err)
+        if self.owner {
+            self.coord.withdraw(self.topic.id);
+        }
+    }
+}
+
+/// The publication of a topic hosted by the publishing operator. It is
+/// either registered before the dataflow is built, or the operator drives
+/// the registration to completion without blocking the worker.
+struct Registration {
+    pending: Option<(Spawn<Pending<Publication, PublicationError>>,
+                     Sender<Result<Option<Topic>, PublicationError>>)>,
+    publication: Option<Publication>,
+    notify: Arc<Nop>,
+}
+
+impl Registration {
+    fn done(publication: Option<Publication>) -> Self {
+        Registration {
+            pending: None,
+            publication: publication,
+            notify: Arc::new(Nop),
         }
     }
+
+    /// Returns a registration driven by `poll`, along with a future
+    /// resolving to the published topic.
+    fn pending(pending: Pending<Publication, PublicationError>) -> (Self, PendingTopic) {
+        let (tx, rx) = channel();
+        let registration = Registration {
+            pending: Some((executor::spawn(pending), tx)),
+            publication: None,
+            notify: Arc::new(Nop),
+        };
+
+        let topic = rx.then(|res| match res {
+            Ok(res) => res,
+            Err(_) => {
+                let err = IoError::new(ErrorKind::Other, "publishing operator dropped");
+                Err(PublicationError::IoError(err))
+            }
+        });
+
+        (registration, Box::new(topic))
+    }
+
+    fn poll(&mut self) {
+        let result = match self.pending {
+            Some((ref mut pending, _)) => {
+                match pending.poll_future_notify(&self.notify, 0) {
+                    Ok(Async::NotReady) => return,
+                    Ok(Async::Ready(publication)) => Ok(publication),
+                    Err(err) => Err(err),
+                }
+            }
+            None => return,
+        };
+
+        let (_, tx) = self.pending.take().unwrap();
+        let result = result.map(|publication| {
+            let topic = publication.topic.clone();
+            self.publication = Some(publication);
+            Some(topic)
+        });
+
+        // the caller might not be interested in the outcome
+        drop(tx.send(result));
+    }
 }
 
 impl Coordinator {
+    /// Returns a handle whose publications are restricted by `acl`, e.g.
+    /// `coord.with_acl(acl).publish(...)`. The ACL is advisory, as the
+    /// coordinator cannot verify the identity of queries.
+    pub fn with_acl(&self, acl: Acl) -> Coordinator {
+        let mut coord = self.clone();
+        coord.acl = acl;
+        coord
+    }
+
     fn publish_request(&self,
                        name: String,
                        schema: TopicSchema,
-                       addr: (String, u16))
+                       addr: ((String, u16), Option<u64>),
+                       partition: (usize, usize),
+                       compatibility: Compatibility)
                        -> Result<Publication, PublicationError> {
-        let topic = self.tx
+        self.publish_request_async(name, schema, addr, partition, compatibility).wait()
+    }
+
+    fn publish_request_async(&self,
+                             name: String,
+                             schema: TopicSchema,
+                             addr: ((String, u16), Option<u64>),
+                             partition: (usize, usize),
+                             compatibility: Compatibility)
+                             -> Pending<Publication, PublicationError> {
+        let (addr, secret) = addr;
+        let coord = self.clone();
+        let publication = self.tx
             .request(&Publish {
                 name: name,
                 token: self.token,
                 schema: schema,
+                compatibility: compatibility,
+                acl: self.acl.clone(),
+                secret: secret.expect("publication without secret"),
                 addr: addr,
+                partition: partition,
             })
             .map_err(PublicationError::from)
-            .wait()?;
+            .map(move |topic| {
+                Publication {
+                    topic: topic,
+                    coord: coord,
+                    owner: partition.0 == 0,
+                }
+            });
 
-        Ok(Publication {
-            topic: topic,
-            coord: self.clone(),
-        })
+        Box::new(publication)
     }
 
     pub fn publish<S, D>(&self,
                          name: &str,
                          stream: &Stream<S, D>,
-                         partition: Partition)
+                         partition: Partition<D>)
                          -> Result<Stream<S, D>, PublicationError>
-        where D: ExchangeData + Serialize,
+        where D: ExchangeData + Serialize + DeserializeOwned,
+              S: Scope,
+              S::Timestamp: PubSubTimestamp
+    {
+        self.publish_with(name, stream, partition, Compatibility::None)
+    }
+
+    /// Publishes a stream whose subscribers may use a different, but
+    /// compatible schema according to `compatibility`.
+    pub fn publish_with<S, D>(&self,
+                              name: &str,
+                              stream: &Stream<S, D>,
+                              partition: Partition<D>,
+                              compatibility: Compatibility)
+                              -> Result<Stream<S, D>, PublicationError>
+        where D: ExchangeData + Serialize + DeserializeOwned,
+              S: Scope,
+              S::Timestamp: PubSubTimestamp
+    {
+        let options = PublishOptions { compatibility: compatibility, ..Default::default() };
+        self.publish_with_options(name, stream, partition, options).map(|(stream, _)| stream)
+    }
+
+    /// Publishes a stream according to `options`. With a retention policy,
+    /// subscribers connecting later on can replay retained batches using
+    /// `subscribe_from`. With backpressure, a subscriber falling more than
+    /// `backpressure.capacity` batches behind causes the publishing operator
+    /// to either block, drop the oldest batches or disconnect it. Blocking
+    /// stalls the whole worker thread until the subscriber catches up.
+    ///
+    /// The returned `QueueDepth` reports how far the slowest subscriber of
+    /// the local publication lags behind.
+    pub fn publish_with_options<S, D>(&self,
+                                      name: &str,
+                                      stream: &Stream<S, D>,
+                                      partition: Partition<D>,
+                                      options: PublishOptions)
+                                      -> Result<(Stream<S, D>, QueueDepth), PublicationError>
+        where D: ExchangeData + Serialize + DeserializeOwned,
+              S: Scope,
+              S::Timestamp: PubSubTimestamp
+    {
+        self.publish_stream(name, stream, partition, options, true)
+            .map(|(stream, depth, _)| (stream, depth))
+    }
+
+    /// Non-blocking version of `publish_with_options`. The topic is
+    /// registered by the publishing operator while the dataflow runs, the
+    /// returned future resolves to the published topic, or to `None` if
+    /// the local worker does not host a publication of the stream.
+    pub fn publish_async<S, D>(&self,
+                               name: &str,
+                               stream: &Stream<S, D>,
+                               partition: Partition<D>,
+                               options: PublishOptions)
+                               -> (Stream<S, D>, QueueDepth, PendingTopic)
+        where D: ExchangeData + Serialize + DeserializeOwned,
+              S: Scope,
+              S::Timestamp: PubSubTimestamp
+    {
+        match self.publish_stream(name, stream, partition, options, false) {
+            Ok(published) => published,
+            // nothing has been published, the data just passes through
+            Err(err) => (stream.clone(), QueueDepth::default(), Box::new(future::err(err))),
+        }
+    }
+
+    fn publish_stream<S, D>(&self,
+                            name: &str,
+                            stream: &Stream<S, D>,
+                            partition: Partition<D>,
+                            options: PublishOptions,
+                            blocking: bool)
+                            -> Result<(Stream<S, D>, QueueDepth, PendingTopic), PublicationError>
+        where D: ExchangeData + Serialize + DeserializeOwned,
               S: Scope,
               S::Timestamp: PubSubTimestamp
     {
         let worker_id = stream.scope().index() as u64;
         let name = partition.name(name, worker_id);
+        let hosted = partition.partition(worker_id, stream.scope().peers());
 
+        let PublishOptions { compatibility, retention, backpressure } = options;
         let (addr, mut publisher) = if name.is_some() {
-            let (addr, publisher) =
+            let (addr, mut publisher) =
                 TimelyPublisher::<<S::Timestamp as PubSubTimestamp>::Converted, D>::new(&self.network)?;
-            (Some(addr), Some(publisher))
+            if let Some(retention) = retention {
+                publisher.retain(retention, less_equal::<S::Timestamp>)?;
+            }
+            if let Some(backpressure) = backpressure {
+                publisher.bound(backpressure);
+            }
+            (Some((addr, publisher.secret())), Some(publisher))
         } else {
             (None, None)
         };
 
-        let publication = if name.is_some() {
+        let (mut registration, topic) = if let Some(name) = name {
             // local worker hosts a publication
-            let item = TopicType::of::<D>();
-            let time = TopicType::of::<S::Timestamp>();
+            let item = D::schema()?;
+            let time = <S::Timestamp as PubSubTimestamp>::Converted::schema()?;
             let schema = TopicSchema::Stream(item, time);
-
-            Some(self.publish_request(name.unwrap(), schema, addr.unwrap())?)
+            let addr = addr.unwrap();
+
+            if blocking {
+                let publication = self.publish_request(name, schema, addr, hosted, compatibility)?;
+                let topic: PendingTopic = Box::new(future::ok(Some(publication.topic.clone())));
+                (Registration::done(Some(publication)), topic)
+            } else {
+                let pending = self.publish_request_async(name, schema, addr, hosted, compatibility);
+                Registration::pending(pending)
+            }
         } else {
-            None
+            (Registration::done(None), Box::new(future::ok(None)) as PendingTopic)
         };
 
+        let depth = QueueDepth::default();
+        let queued = depth.clone();
         let output = stream.unary_notify(partition,
                                          "timelypublisher",
                                          Vec::new(),
                                          move |input, output, notif| {
-            // ensure publication handle is moved into the closure/operator
-            let ref _guard = publication;
+            // the registration owns the publication handle of this operator
+            registration.poll();
+
+            // accept subscribers even if there is no new data
+            if let Some(ref mut publisher) = publisher {
+                publisher.poll().unwrap();
+            }
 
             // publish data on input
             let frontier: Vec<_> = notif
@@ -200,35 +514,60 @@ impl Coordinator {
                 }
                 output.session(&time).give_content(data);
             });
+
+            // inform subscribers about progress without data
+            if let Some(ref mut publisher) = publisher {
+                if frontier.is_empty() {
+                    publisher.close().unwrap();
+                } else {
+                    publisher.progress(&frontier).unwrap();
+                }
+                queued.0.set(publisher.queue_depth());
+            }
         });
 
-        Ok(output)
+        Ok((output, depth, topic))
     }
 
     pub fn publish_collection<S, D>(&self,
                                     name: &str,
                                     stream: &Stream<S, (D, i32)>,
-                                    partition: Partition)
+                                    partition: Partition<(D, i32)>)
                                     -> Result<Stream<S, (D, i32)>, PublicationError>
-        where D: ExchangeData + Eq + Serialize,
+        where D: ExchangeData + Eq + Hash + Serialize + DeserializeOwned,
+              S: Scope
+    {
+        self.publish_collection_with(name, stream, partition, Compatibility::None)
+    }
+
+    /// Publishes a collection whose subscribers may use a different, but
+    /// compatible schema according to `compatibility`.
+    pub fn publish_collection_with<S, D>(&self,
+                                         name: &str,
+                                         stream: &Stream<S, (D, i32)>,
+                                         partition: Partition<(D, i32)>,
+                                         compatibility: Compatibility)
+                                         -> Result<Stream<S, (D, i32)>, PublicationError>
+        where D: ExchangeData + Eq + Hash + Serialize + DeserializeOwned,
               S: Scope
     {
         let worker_id = stream.scope().index() as u64;
         let name = partition.name(name, worker_id);
+        let hosted = partition.partition(worker_id, stream.scope().peers());
 
         let (addr, mut mutator, mut publisher) = if name.is_some() {
             let (addr, mutator, publisher) =
                 CollectionPublisher::<D>::new(&self.network)?;
-            (Some(addr), Some(mutator), Some(publisher.spawn()))
+            (Some((addr, publisher.secret())), Some(mutator), Some(publisher.spawn()))
         } else {
             (None, None, None)
         };
 
         let publication = if name.is_some() {
             // local worker hosts a publication
-            let item = TopicType::of::<D>();
+            let item = D::schema()?;
             let schema = TopicSchema::Collection(item);
-            Some(self.publish_request(name.unwrap(), schema, addr.unwrap())?)
+            Some(self.publish_request(name.unwrap(), schema, addr.unwrap(), hosted, compatibility)?)
         } else {
             None
         };
@@ -255,13 +594,113 @@ impl Coordinator {
         Ok(output)
     }
 
-    fn unpublish(&self, topic: TopicId) -> Result<(), PublicationError> {
-        self.tx
-            .request(&Unpublish {
-                topic: topic,
-                token: self.token,
-            })
-            .map_err(PublicationError::from)
-            .wait()
+    /// Publishes a collection whose updates keep their logical time. In
+    /// contrast to `publish_collection`, subscribers receive the frontier
+    /// of the collection and can thus feed it into a differential dataflow
+    /// with the same time semantics as the publisher.
+    pub fn publish_timestamped_collection<S, D>(&self,
+                                                name: &str,
+                                                stream: &Stream<S, (D, i32)>,
+                                                partition: Partition<(D, i32)>)
+                                                -> Result<Stream<S, (D, i32)>, PublicationError>
+        where D: ExchangeData + Eq + Hash + Serialize + DeserializeOwned,
+              S: Scope,
+              S::Timestamp: PubSubTimestamp
+    {
+        self.publish_updates(name, stream, partition, collection_update)
+    }
+
+    /// Publishes a differential dataflow collection. Updates keep their
+    /// timestamp and difference, and the frontier of the collection is
+    /// published along with them. Use `subscribe_differential` to rebuild
+    /// the collection in another query.
+    pub fn publish_differential<S, D, R>(&self,
+                                         name: &str,
+                                         collection: &Collection<S, D, R>,
+                                         partition: Partition<(D, S::Timestamp, R)>)
+                                         -> Result<Collection<S, D, R>, PublicationError>
+        where D: ::differential_dataflow::Data + Hash + Serialize + DeserializeOwned,
+              R: ::differential_dataflow::Diff + ExchangeData + Add<Output = R> + Default,
+              R: Serialize + DeserializeOwned,
+              S: Scope,
+              S::Timestamp: PubSubTimestamp
+    {
+        self.publish_updates(name, &collection.inner, partition, differential_update)
+            .map(|stream| stream.as_collection())
+    }
+
+    /// Publishes the `(item, time, diff)` updates extracted from each record
+    /// of `stream` by `update`, together with the frontier of the stream.
+    fn publish_updates<S, X, D, R>(&self,
+                                   name: &str,
+                                   stream: &Stream<S, X>,
+                                   partition: Partition<X>,
+                                   update: fn(&X, &S::Timestamp) -> (D, S::Timestamp, R))
+                                   -> Result<Stream<S, X>, PublicationError>
+        where X: ExchangeData,
+              D: Clone + Eq + Hash + Serialize + Schema,
+              R: Copy + Add<Output = R> + Default + PartialEq + Serialize + Schema,
+              S: Scope,
+              S::Timestamp: PubSubTimestamp
+    {
+        let worker_id = stream.scope().index() as u64;
+        let name = partition.name(name, worker_id);
+        let hosted = partition.partition(worker_id, stream.scope().peers());
+
+        let (addr, mut publisher) = if name.is_some() {
+            let (addr, publisher) =
+                TimestampedPublisher::<<S::Timestamp as PubSubTimestamp>::Converted, D, R>::new(
+                    &self.network, less_equal::<S::Timestamp>)?;
+            (Some((addr, publisher.secret())), Some(publisher))
+        } else {
+            (None, None)
+        };
+
+        let publication = if name.is_some() {
+            // local worker hosts a publication
+            let item = D::schema()?;
+            let time = <S::Timestamp as PubSubTimestamp>::Converted::schema()?;
+            let schema = TopicSchema::TimestampedCollection(item, time, R::schema()?);
+            Some(self.publish_request(name.unwrap(), schema, addr.unwrap(), hosted, Compatibility::None)?)
+        } else {
+            None
+        };
+
+        let output = stream.unary_notify(partition,
+                                         "timestampedpublisher",
+                                         Vec::new(),
+                                         move |input, output, notif| {
+            // ensure publication handle is moved into the closure/operator
+            let ref _guard = publication;
+
+            let mut updates = Vec::new();
+            input.for_each(|time, data| {
+                updates.extend(data.iter().map(|record| {
+                    let (item, t, diff) = update(record, time.time());
+                    (item, t.to_pubsub(), diff)
+                }));
+                output.session(&time).give_content(data);
+            });
+
+            // progress is published even if there is no new data
+            if let Some(ref mut publisher) = publisher {
+                let frontier: Vec<_> = notif
+                    .frontier(0).iter()
+                    .map(|t| t.to_pubsub())
+                    .collect();
+                publisher.publish(&frontier, &updates).unwrap();
+            }
+        });
+
+        Ok(output)
+    }
+
+    /// Removes a publication without waiting for the coordinator to
+    /// respond, dropping a publication thus never blocks.
+    fn withdraw(&self, topic: TopicId) {
+        let _ = self.tx.request(&Unpublish {
+            topic: topic,
+            token: self.token,
+        });
     }
 }
@@ -0,0 +1,288 @@
+// Copyright 2017 ETH Zurich. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Subscriptions as dataflow operators.
+//!
+//! The operators are backed by an unordered input, whose capability is
+//! handed to the subscription. The subscription is polled whenever the
+//! worker schedules the operator, it therefore never blocks the worker while
+//! waiting for the coordinator or the publishers. Errors cannot be sent
+//! through the output stream, they are reported through a `SourceHandle`.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use timely::Data;
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::{Capability, Map, Unary, UnorderedInput};
+
+use differential_dataflow::{AsCollection, Collection};
+
+use futures::Async;
+use futures::executor::{self, Spawn};
+use futures::stream::Stream as FuturesStream;
+
+use serde::de::DeserializeOwned;
+
+use pubsub::publisher::Nop;
+use query::{Coordinator, Pending, PubSubTimestamp};
+use query::subscribe::SubscriptionError;
+
+/// Reports on the subscription backing a source operator.
+#[derive(Clone)]
+pub struct SourceHandle {
+    error: Rc<RefCell<Option<SubscriptionError>>>,
+    late: Rc<Cell<u64>>,
+}
+
+impl SourceHandle {
+    fn new() -> Self {
+        SourceHandle {
+            error: Rc::new(RefCell::new(None)),
+            late: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Returns the error the subscription failed with, if it failed. A
+    /// failed subscription has released its capabilities, the operator does
+    /// not produce any more data. The error is only returned once.
+    pub fn take_error(&self) -> Option<SubscriptionError> {
+        self.error.borrow_mut().take()
+    }
+
+    /// Number of records dropped because they were published at times the
+    /// operator had already advanced past.
+    pub fn late(&self) -> u64 {
+        self.late.get()
+    }
+}
+
+/// A subscription which is polled from within an operator.
+struct Source<S> {
+    name: String,
+    pending: Option<Spawn<Pending<S, SubscriptionError>>>,
+    subscription: Option<Spawn<S>>,
+    notify: Arc<Nop>,
+    handle: SourceHandle,
+}
+
+impl<S: FuturesStream> Source<S>
+    where SubscriptionError: From<S::Error>
+{
+    fn new(name: &str, pending: Pending<S, SubscriptionError>) -> Self {
+        Source {
+            name: String::from(name),
+            pending: Some(executor::spawn(pending)),
+            subscription: None,
+            notify: Arc::new(Nop),
+            handle: SourceHandle::new(),
+        }
+    }
+
+    /// Returns all items which are available without blocking. A failed
+    /// subscription is dropped, and its error is reported to the handle.
+    fn poll(&mut self) -> Vec<S::Item> {
+        match self.try_poll() {
+            Ok(items) => items,
+            Err(err) => {
+                error!("subscription to {:?} failed: {:?}", self.name, err);
+                self.pending = None;
+                self.subscription = None;
+                *self.handle.error.borrow_mut() = Some(err);
+                Vec::new()
+            }
+        }
+    }
+
+    fn try_poll(&mut self) -> Result<Vec<S::Item>, SubscriptionError> {
+        let subscribed = match self.pending {
+            Some(ref mut pending) => {
+                match pending.poll_future_notify(&self.notify, 0)? {
+                    Async::Ready(subscription) => Some(subscription),
+                    Async::NotReady => None,
+                }
+            }
+            None => None,
+        };
+
+        if let Some(subscription) = subscribed {
+            self.pending = None;
+            self.subscription = Some(executor::spawn(subscription));
+        }
+
+        let mut items = Vec::new();
+        let mut finished = false;
+        if let Some(ref mut subscription) = self.subscription {
+            loop {
+                match subscription.poll_stream_notify(&self.notify, 0)? {
+                    Async::Ready(Some(item)) => items.push(item),
+                    Async::Ready(None) => {
+                        finished = true;
+                        break;
+                    }
+                    Async::NotReady => break,
+                }
+            }
+        }
+
+        // unsubscribes and releases the remaining capabilities
+        if finished {
+            self.subscription = None;
+        }
+
+        Ok(items)
+    }
+}
+
+/// Capabilities for the frontier announced by the publishers of a
+/// timestamped collection.
+struct Frontier<T: PubSubTimestamp> {
+    caps: Vec<Capability<T>>,
+}
+
+impl<T: PubSubTimestamp> Frontier<T> {
+    /// Returns a capability for an update at `time`, or `None` if the
+    /// frontier has already advanced past it.
+    fn capability(&self, time: &T) -> Option<Capability<T>> {
+        self.caps
+            .iter()
+            .find(|cap| cap.time().less_equal(time))
+            .map(|cap| cap.delayed(time))
+    }
+
+    fn advance(&mut self, frontier: &[T]) {
+        let mut caps = Vec::with_capacity(frontier.len());
+        for time in frontier {
+            if let Some(cap) = self.caps.iter().find(|cap| cap.time().less_equal(time)) {
+                caps.push(cap.delayed(time));
+            }
+        }
+        self.caps = caps;
+    }
+}
+
+impl Coordinator {
+    /// Subscribes to a stream topic from within a dataflow. The subscription
+    /// waits for the topic to be published without blocking the worker.
+    ///
+    /// If the subscription fails, e.g. because the topic is published with
+    /// an incompatible schema, the stream ends and the error is reported
+    /// through the returned handle.
+    pub fn subscribe_stream<S, D>(&self, scope: &mut S, name: &str) -> (Stream<S, D>, SourceHandle)
+        where S: Scope,
+              S::Timestamp: PubSubTimestamp,
+              D: Data + DeserializeOwned
+    {
+        let ((mut handle, root), stream) = scope.new_unordered_input::<D>();
+        let mut source = Source::new(name, self.subscribe_async::<S::Timestamp, D>(name, root));
+        let status = source.handle.clone();
+
+        let stream = stream.unary_stream(Pipeline, "subscriber", move |input, output| {
+            input.for_each(|time, data| {
+                output.session(&time).give_content(data);
+            });
+
+            for (cap, data) in source.poll() {
+                handle.session(cap).give_iterator(data.into_iter());
+            }
+
+            // late records are dropped by the subscription itself
+            if let Some(ref subscription) = source.subscription {
+                source.handle.late.set(subscription.get_ref().late());
+            }
+        });
+
+        (stream, status)
+    }
+
+    /// Subscribes to a collection published with
+    /// `publish_timestamped_collection` from within a dataflow. Updates are
+    /// inserted at the time they were published at, and the collection
+    /// advances along with the frontier of its publisher.
+    ///
+    /// Failures are reported through the returned handle, see
+    /// `subscribe_stream`.
+    pub fn subscribe_collection_stream<S, D>(&self,
+                                             scope: &mut S,
+                                             name: &str)
+                                             -> (Collection<S, D>, SourceHandle)
+        where S: Scope,
+              S::Timestamp: PubSubTimestamp,
+              D: ::differential_dataflow::Data + DeserializeOwned
+    {
+        let (updates, status) = self.subscribe_differential::<S, D, i32>(scope, name);
+        let collection = updates.inner
+            .map(|(item, time, diff)| (item, time, diff as isize))
+            .as_collection();
+        (collection, status)
+    }
+
+    /// Rebuilds a collection published with `publish_differential` from
+    /// within a dataflow, keeping the timestamps and differences of the
+    /// published updates. Updates at times the collection has already
+    /// advanced past are dropped and counted by the returned handle.
+    ///
+    /// Failures are reported through the returned handle, see
+    /// `subscribe_stream`.
+    pub fn subscribe_differential<S, D, R>(&self,
+                                           scope: &mut S,
+                                           name: &str)
+                                           -> (Collection<S, D, R>, SourceHandle)
+        where S: Scope,
+              S::Timestamp: PubSubTimestamp,
+              D: ::differential_dataflow::Data + DeserializeOwned,
+              R: ::differential_dataflow::Diff + Data + DeserializeOwned
+    {
+        let ((mut handle, root), stream) = scope.new_unordered_input::<(D, S::Timestamp, R)>();
+        let pending = self.timestamped::<S::Timestamp, D, R>(name.to_string(), true);
+        let mut source = Source::new(name, pending);
+        let mut frontier = Frontier { caps: vec![root] };
+        let status = source.handle.clone();
+
+        let stream = stream.unary_stream(Pipeline, "collectionsubscriber", move |input, output| {
+            input.for_each(|time, data| {
+                output.session(&time).give_content(data);
+            });
+
+            for (mut updates, progress) in source.poll() {
+                updates.sort_by(|a, b| a.1.cmp(&b.1));
+                let mut updates = updates.into_iter().peekable();
+                while let Some((item, time, diff)) = updates.next() {
+                    let mut batch = vec![(item, time.clone(), diff)];
+                    while updates.peek().map_or(false, |u| u.1 == time) {
+                        batch.push(updates.next().unwrap());
+                    }
+
+                    // relabelling late updates would corrupt their timestamps
+                    match frontier.capability(&time) {
+                        Some(cap) => handle.session(cap).give_iterator(batch.into_iter()),
+                        None => {
+                            warn!("dropping {} late updates of {:?} at {:?}",
+                                  batch.len(),
+                                  source.name,
+                                  time);
+                            let late = &source.handle.late;
+                            late.set(late.get() + batch.len() as u64);
+                        }
+                    }
+                }
+
+                frontier.advance(&progress);
+            }
+
+            // the publishers are gone, the collection will not change anymore
+            if source.pending.is_none() && source.subscription.is_none() {
+                frontier.caps.clear();
+            }
+        });
+
+        (stream.as_collection(), status)
+    }
+}
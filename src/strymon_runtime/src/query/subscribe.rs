@@ -7,33 +7,296 @@
 // except according to those terms.
 
 use std::io;
+use std::mem;
+use std::hash::Hash;
+use std::collections::{HashMap, HashSet};
 
 use timely::Data;
+use timely::progress::frontier::Antichain;
 use timely::dataflow::operators::Capability;
 
-use futures::{Future, Poll, Async};
+use futures::{future, task, Future, Poll, Async};
 use futures::stream::{Stream, Wait};
 
 use serde::de::DeserializeOwned;
 
 use coordinator::requests::*;
 
-use pubsub::subscriber::{Subscriber, TimelySubscriber};
-use model::{Topic, TopicId};
-use query::{Coordinator, PubSubTimestamp};
+use pubsub::subscriber::{Subscriber, CollectionSubscriber, TimelySubscriber, TimelyMessage};
+use pubsub::subscriber::TimestampedSubscriber;
+use pubsub::decoder::Decoder;
+use model::{Topic, TopicId, TopicSchema, TopicType, Schema};
+use model::schema::{Projection, SchemaError};
+use query::{Coordinator, Pending, PubSubTimestamp};
+
+/// Waits for a topic to be published again under the same name, e.g.
+/// after the query publishing it has been restarted.
+struct Resubscribe {
+    name: String,
+    schema: TopicSchema,
+    /// The topic which has been lost
+    lost: TopicId,
+    state: ResubscribeState,
+}
+
+enum ResubscribeState {
+    Lookup(Pending<Topic, SubscriptionError>),
+    Watch(CollectionSubscriber<Topic>),
+    Subscribe(Pending<Topic, SubscriptionError>),
+}
+
+impl Resubscribe {
+    fn new(coord: &Coordinator, topic: &Topic, schema: TopicSchema) -> Self {
+        let lookup = coord.tx
+            .request(&Lookup { name: String::from("$topics") })
+            .map_err(|err| match err {
+                Ok(()) => SubscriptionError::TopicNotFound,
+                Err(err) => SubscriptionError::IoError(err),
+            });
+
+        Resubscribe {
+            name: topic.name.clone(),
+            schema: schema,
+            lost: topic.id,
+            state: ResubscribeState::Lookup(Box::new(lookup)),
+        }
+    }
+
+    /// Resolves to the republished topic once we are subscribed to it.
+    fn poll(&mut self, coord: &Coordinator) -> Poll<Topic, SubscriptionError> {
+        loop {
+            let next = match self.state {
+                ResubscribeState::Lookup(ref mut lookup) => {
+                    let catalog = try_ready!(lookup.poll());
+                    let addr = &catalog.partitions[0];
+                    let topics = Subscriber::connect_collection(addr, &coord.network, None)?;
+                    ResubscribeState::Watch(topics)
+                }
+                ResubscribeState::Watch(ref mut topics) => {
+                    let updates = match try_ready!(topics.poll()) {
+                        Some(updates) => updates,
+                        None => return Err(SubscriptionError::PublisherLost),
+                    };
+
+                    // the catalog might still contain the lost topic
+                    let republished = updates.into_iter().find(|&(ref topic, diff)| {
+                        diff > 0 && topic.name == self.name && topic.id != self.lost
+                    });
+
+                    match republished {
+                        Some((topic, _)) => {
+                            let request = coord.tx
+                                .request(&Subscribe {
+                                    name: topic.name,
+                                    token: coord.token,
+                                    blocking: false,
+                                    schema: self.schema.clone(),
+                                })
+                                .map_err(SubscriptionError::from);
+                            ResubscribeState::Subscribe(Box::new(request))
+                        }
+                        None => continue,
+                    }
+                }
+                ResubscribeState::Subscribe(ref mut request) => return request.poll(),
+            };
+
+            self.state = next;
+        }
+    }
+}
 
 pub struct Subscription<D: Data + DeserializeOwned> {
-    sub: Subscriber<D>,
+    /// One subscriber for every partition of the topic
+    subs: Vec<Subscriber<D>>,
     topic: Topic,
+    /// Schema expected by the subscriber
+    schema: TopicSchema,
     coord: Coordinator,
+    next: usize,
+    reconnect: Option<Reconnect<D>>,
+}
+
+/// Contents of a collection subscription which follows restarts of its
+/// publisher, used to deliver the snapshot of the republished topic as
+/// the difference to what has been delivered so far.
+struct Reconnect<D> {
+    resubscribe: Option<Resubscribe>,
+    /// All updates delivered so far
+    state: Vec<D>,
+    /// Length of `state` after it was last consolidated
+    consolidated: usize,
+    /// Retraction of the previous contents, merged with the snapshot of
+    /// the republished topic until all of its chunks have arrived
+    retract: Option<Vec<D>>,
+    consolidate: fn(&mut Vec<D>),
+    negate: fn(D) -> D,
+}
+
+impl<D: Clone> Reconnect<D> {
+    /// Returns the batch to deliver, or `None` while the snapshot of a
+    /// republished topic is still `loading`.
+    fn deliver(&mut self, data: Vec<D>, loading: bool) -> Option<Vec<D>> {
+        let data = match self.retract.take() {
+            Some(mut diff) => {
+                diff.extend(data);
+                if loading {
+                    self.retract = Some(diff);
+                    return None;
+                }
+                (self.consolidate)(&mut diff);
+                diff
+            }
+            None => data,
+        };
+
+        // consolidate whenever the state has doubled in size
+        self.state.extend_from_slice(&data);
+        if self.state.len() > 2 * self.consolidated + 1024 {
+            (self.consolidate)(&mut self.state);
+            self.consolidated = self.state.len();
+        }
+
+        Some(data)
+    }
+
+    fn poll(&mut self, coord: &Coordinator) -> Result<Option<Topic>, SubscriptionError> {
+        let result = match self.resubscribe {
+            Some(ref mut resubscribe) => resubscribe.poll(coord),
+            None => return Ok(None),
+        };
+
+        match result {
+            Ok(Async::NotReady) => Ok(None),
+            Ok(Async::Ready(topic)) => {
+                self.resubscribe = None;
+                (self.consolidate)(&mut self.state);
+                let negate = self.negate;
+                self.retract = Some(self.state.iter().cloned().map(negate).collect());
+                Ok(Some(topic))
+            }
+            Err(err) => {
+                self.resubscribe = None;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Sums up the differences of equal items, dropping those which cancel out.
+fn consolidate<K: Eq + Hash>(updates: &mut Vec<(K, i32)>) {
+    let mut counts = HashMap::new();
+    for (item, diff) in updates.drain(..) {
+        *counts.entry(item).or_insert(0) += diff;
+    }
+    updates.extend(counts.into_iter().filter(|&(_, diff)| diff != 0));
+}
+
+fn negate<K>((item, diff): (K, i32)) -> (K, i32) {
+    (item, -diff)
+}
+
+impl<K> Subscription<(K, i32)>
+    where K: Data + DeserializeOwned + Eq + Hash
+{
+    /// Follows restarts of the publisher: if the publisher goes away, the
+    /// subscription waits for a topic with the same name to be published
+    /// again and subscribes to it. Once the complete snapshot of the
+    /// republished topic has arrived, it is delivered as the difference to
+    /// the contents received so far. The subscription no longer ends when
+    /// its publisher goes away.
+    pub fn reconnect(&mut self) {
+        self.reconnect = Some(Reconnect {
+            resubscribe: None,
+            state: Vec::new(),
+            consolidated: 0,
+            retract: None,
+            consolidate: consolidate::<K>,
+            negate: negate::<K>,
+        });
+    }
+}
+
+impl<D: Data + DeserializeOwned> Subscription<D> {
+    /// Replaces the partitions of the lost topic with those of the topic
+    /// republished under the same name.
+    fn resubscribed(&mut self, topic: Topic) -> Result<(), SubscriptionError> {
+        let subs = match connect_collection(&topic, &self.schema, &self.coord) {
+            Ok(subs) => subs,
+            Err(err) => {
+                self.coord.detach(topic.id);
+                return Err(err);
+            }
+        };
+
+        info!("resubscribed to republished topic {:?}", topic.name);
+        self.coord.detach(self.topic.id);
+        self.subs = subs;
+        self.topic = topic;
+        self.next = 0;
+        Ok(())
+    }
 }
 
 impl<D: Data + DeserializeOwned> Stream for Subscription<D> {
     type Item = Vec<D>;
-    type Error = io::Error;
+    type Error = SubscriptionError;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.sub.poll()
+        loop {
+            let mut i = 0;
+            while i < self.subs.len() {
+                let idx = (self.next + i) % self.subs.len();
+                match self.subs[idx].poll()? {
+                    Async::Ready(Some(data)) => {
+                        self.next = idx + 1;
+                        let data = match self.reconnect {
+                            Some(ref mut reconnect) => {
+                                let loading = self.subs.iter().any(|sub| sub.in_snapshot());
+                                match reconnect.deliver(data, loading) {
+                                    Some(data) => data,
+                                    None => continue,
+                                }
+                            }
+                            None => data,
+                        };
+                        return Ok(Async::Ready(Some(data)));
+                    }
+                    Async::Ready(None) => {
+                        self.subs.remove(idx);
+                        if let Some(ref mut reconnect) = self.reconnect {
+                            if reconnect.resubscribe.is_none() {
+                                warn!("lost publisher of topic {:?}, waiting for it to reappear",
+                                      self.topic.name);
+                                let schema = self.schema.clone();
+                                let resubscribe =
+                                    Resubscribe::new(&self.coord, &self.topic, schema);
+                                reconnect.resubscribe = Some(resubscribe);
+                            }
+                        }
+                    }
+                    Async::NotReady => i += 1,
+                }
+            }
+
+            let republished = match self.reconnect {
+                Some(ref mut reconnect) => reconnect.poll(&self.coord)?,
+                None => None,
+            };
+
+            // poll the partitions of the republished topic
+            match republished {
+                Some(topic) => self.resubscribed(topic)?,
+                None => break,
+            }
+        }
+
+        let resubscribing = self.reconnect.as_ref().map_or(false, |r| r.resubscribe.is_some());
+        if self.subs.is_empty() && !resubscribing {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
     }
 }
 
@@ -48,9 +311,7 @@ impl<D: Data + DeserializeOwned> IntoIterator for Subscription<D> {
 
 impl<D: Data + DeserializeOwned> Drop for Subscription<D> {
     fn drop(&mut self) {
-        if let Err(err) = self.coord.unsubscribe(self.topic.id) {
-            warn!("failed to unsubscribe: {:?}", err)
-        }
+        self.coord.detach(self.topic.id);
     }
 }
 
@@ -66,53 +327,581 @@ impl<S: Stream> Iterator for IntoIter<S> {
     }
 }
 
+/// Subscription to a collection published with its logical times. Every
+/// item is a batch of `(item, time, diff)` updates along with the frontier
+/// of the collection, i.e. the subscriber will not receive any further
+/// updates at times not greater or equal to an element of the frontier.
+/// The subscription ends once all partitions announced an empty frontier,
+/// a partition closing its connection before fails it with `PublisherLost`.
+pub struct TimestampedSubscription<T, D, R = i32>
+    where T: PubSubTimestamp,
+          D: Data + DeserializeOwned,
+          R: Data + DeserializeOwned
+{
+    /// Every partition of the topic and its last announced frontier
+    subs: Vec<(TimestampedSubscriber<T::Converted, D, R>, Vec<T>)>,
+    topic: Topic,
+    coord: Coordinator,
+    next: usize,
+}
 
-pub struct TimelySubscription<T: PubSubTimestamp, D: Data + DeserializeOwned> {
+impl<T, D, R> TimestampedSubscription<T, D, R>
+    where T: PubSubTimestamp,
+          D: Data + DeserializeOwned,
+          R: Data + DeserializeOwned
+{
+    /// The combined frontier of all partitions.
+    fn frontier(&self) -> Vec<T> {
+        let mut frontier = Antichain::new();
+        for &(_, ref partition) in self.subs.iter() {
+            for time in partition {
+                frontier.insert(time.clone());
+            }
+        }
+        frontier.elements().to_vec()
+    }
+}
+
+impl<T, D, R> Stream for TimestampedSubscription<T, D, R>
+    where T: PubSubTimestamp,
+          D: Data + DeserializeOwned,
+          R: Data + DeserializeOwned
+{
+    type Item = (Vec<(D, T, R)>, Vec<T>);
+    type Error = SubscriptionError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut i = 0;
+        while i < self.subs.len() {
+            let idx = (self.next + i) % self.subs.len();
+            match self.subs[idx].0.poll()? {
+                Async::Ready(Some((updates, frontier))) => {
+                    self.next = idx + 1;
+                    // the frontier is only reached once the snapshot is complete
+                    if !self.subs[idx].0.in_snapshot() {
+                        self.subs[idx].1 = frontier.into_iter().map(T::from_pubsub).collect();
+                    }
+                    let updates = updates.into_iter()
+                        .map(|(d, t, r)| (d, T::from_pubsub(t), r))
+                        .collect();
+                    return Ok(Async::Ready(Some((updates, self.frontier()))));
+                }
+                // an empty frontier marks the end of the partition
+                Async::Ready(None) if self.subs[idx].1.is_empty() => {
+                    self.subs.remove(idx);
+                }
+                Async::Ready(None) => {
+                    return Err(SubscriptionError::PublisherLost);
+                }
+                Async::NotReady => i += 1,
+            }
+        }
+
+        if self.subs.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<T, D, R> IntoIterator for TimestampedSubscription<T, D, R>
+    where T: PubSubTimestamp,
+          D: Data + DeserializeOwned,
+          R: Data + DeserializeOwned
+{
+    type Item = (Vec<(D, T, R)>, Vec<T>);
+    type IntoIter = IntoIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter { inner: self.wait() }
+    }
+}
+
+impl<T, D, R> Drop for TimestampedSubscription<T, D, R>
+    where T: PubSubTimestamp,
+          D: Data + DeserializeOwned,
+          R: Data + DeserializeOwned
+{
+    fn drop(&mut self) {
+        self.coord.detach(self.topic.id);
+    }
+}
+
+/// A stream topic merged into a `TimelySubscription`.
+struct Source<T: PubSubTimestamp, D: Data + DeserializeOwned> {
     sub: TimelySubscriber<T::Converted, D>,
-    topic: Topic,
+    topic: TopicId,
+    /// The last frontier announced by the publisher
+    frontier: Vec<T>,
+}
+
+/// Watches the catalog for stream topics whose name matches a pattern.
+struct Watch {
+    pattern: String,
+    schema: TopicSchema,
+    topics: CollectionSubscriber<Topic>,
+    /// Topics which have been attached or are about to be attached
+    seen: HashSet<TopicId>,
+    pending: Vec<Box<Future<Item = Topic, Error = SubscriptionError>>>,
+}
+
+/// A stream topic whose publisher was lost before closing its stream.
+struct Lost<T> {
+    topic: TopicId,
+    name: String,
+    /// Last frontier of the lost partitions, we cannot know if they would
+    /// have sent more data
+    frontier: Vec<T>,
+    resubscribe: Option<Resubscribe>,
+}
+
+/// Data of a stream topic which might have been missed while its
+/// publisher was restarted.
+#[derive(Clone, Debug)]
+pub struct Gap<T> {
+    pub topic: String,
+    /// Frontier of the lost publisher, data at later times published
+    /// before the restart has not been received
+    pub frontier: Vec<T>,
+}
+
+pub struct TimelySubscription<T: PubSubTimestamp, D: Data + DeserializeOwned> {
+    /// The partitions of all topics read by the local worker
+    sources: Vec<Source<T, D>>,
+    topics: Vec<Topic>,
+    watch: Option<Watch>,
     coord: Coordinator,
     frontier: Vec<Capability<T>>,
+    // source to poll first, to avoid starving the others
+    next: usize,
+    /// Time from which retained batches are replayed
+    replay: Option<T>,
+    /// Batches dropped by publishers of sources which have been removed
+    missed: u64,
+    /// Topics whose publishers were lost, they keep holding back the frontier
+    lost: Vec<Lost<T>>,
+    /// Whether to attach to lost topics once they are republished
+    reconnect: bool,
+    gaps: Vec<Gap<T>>,
+    /// Records dropped because they arrived at times already passed
+    late: u64,
 }
 
-impl<T: PubSubTimestamp, D: Data + DeserializeOwned> Stream for TimelySubscription<T, D> {
-    type Item = (Capability<T>, Vec<D>);
-    type Error = io::Error;
+impl<T: PubSubTimestamp, D: Data + DeserializeOwned> TimelySubscription<T, D> {
+    fn new(coord: Coordinator, root: Capability<T>, watch: Option<Watch>) -> Self {
+        TimelySubscription {
+            sources: Vec::new(),
+            topics: Vec::new(),
+            watch: watch,
+            coord: coord,
+            frontier: vec![root],
+            next: 0,
+            replay: None,
+            missed: 0,
+            lost: Vec::new(),
+            reconnect: false,
+            gaps: Vec::new(),
+            late: 0,
+        }
+    }
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let next = try_ready!(self.sub.poll());
+    /// Follows restarts of the publishers: if a publisher goes away without
+    /// closing its stream, the subscription waits for a topic with the same
+    /// name to be published again and attaches to it. The frontier of the
+    /// lost publisher is held back in the meantime. Subscriptions to a
+    /// pattern attach the republished topic as any other matching topic.
+    pub fn reconnect(&mut self) {
+        self.reconnect = true;
+    }
+
+    /// Returns the gaps in the received data caused by restarted publishers
+    /// since the last call.
+    pub fn take_gaps(&mut self) -> Vec<Gap<T>> {
+        mem::replace(&mut self.gaps, Vec::new())
+    }
+
+    /// Number of batches the publishers dropped because this subscription
+    /// did not keep up with them.
+    pub fn missed(&self) -> u64 {
+        self.missed + self.sources.iter().map(|s| s.sub.missed()).sum::<u64>()
+    }
 
-        let (frontier, time, data) = if next.is_some() {
-            next.unwrap()
+    /// Number of records dropped because they were published at times the
+    /// subscription had already advanced past. This happens if a topic
+    /// attached late still sends data at times before our frontier.
+    pub fn late(&self) -> u64 {
+        self.late
+    }
+
+    /// Connects to the publishers of the partitions assigned to the local
+    /// worker of a topic the coordinator has accepted our subscription for.
+    fn attach(&mut self, topic: Topic, schema: &TopicSchema) -> Result<(), SubscriptionError> {
+        let projection = topic.schema
+            .projection(schema, topic.compatibility)
+            .ok_or(SubscriptionError::TypeIdMismatch)?;
+
+        // until the publishers tell us otherwise, they hold back our frontier
+        let frontier: Vec<T> = self.frontier.iter().map(|cap| cap.time().clone()).collect();
+
+        let mut sources = Vec::new();
+        for partition in topic.assigned(self.coord.index, self.coord.peers) {
+            let addr = &topic.partitions[partition];
+            let secret = topic.secrets.get(partition).cloned();
+            let from = self.replay.as_ref().map(PubSubTimestamp::to_pubsub);
+            let mut sub = TimelySubscriber::<T::Converted, D>::connect_from(addr,
+                                                                           &self.coord.network,
+                                                                           secret,
+                                                                           from)?;
+            if !projection.is_identity() {
+                sub.decode_with(Decoder::new(projection.clone()));
+            }
+
+            sources.push(Source {
+                sub: sub,
+                topic: topic.id,
+                frontier: frontier.clone(),
+            });
+        }
+
+        if self.reconnect {
+            self.take_over(&topic.name);
+        }
+
+        self.sources.extend(sources);
+        self.topics.push(topic);
+
+        Ok(())
+    }
+
+    /// Attaches topics which have been published since the last call.
+    fn poll_watch(&mut self) -> io::Result<()> {
+        let mut accepted = Vec::new();
+        let ended = match self.watch {
+            Some(ref mut watch) => {
+                let ended = loop {
+                    match watch.topics.poll()? {
+                        Async::Ready(Some(updates)) => {
+                            for (topic, diff) in updates {
+                                if diff > 0 && topic.schema.is_stream() &&
+                                   glob(&watch.pattern, &topic.name) &&
+                                   watch.seen.insert(topic.id) {
+                                    let request = self.coord.tx
+                                        .request(&Subscribe {
+                                            name: topic.name,
+                                            token: self.coord.token,
+                                            blocking: false,
+                                            schema: watch.schema.clone(),
+                                        })
+                                        .map_err(SubscriptionError::from);
+                                    watch.pending.push(Box::new(request));
+                                }
+                            }
+                        }
+                        Async::Ready(None) => break true,
+                        Async::NotReady => break false,
+                    }
+                };
+
+                let mut i = 0;
+                while i < watch.pending.len() {
+                    match watch.pending[i].poll() {
+                        Ok(Async::NotReady) => i += 1,
+                        Ok(Async::Ready(topic)) => {
+                            watch.pending.swap_remove(i);
+                            accepted.push((topic, watch.schema.clone()));
+                        }
+                        Err(err) => {
+                            // the topic might have been unpublished in the meantime
+                            watch.pending.swap_remove(i);
+                            warn!("failed to subscribe to matching topic: {:?}", err);
+                        }
+                    }
+                }
+
+                ended
+            }
+            None => false,
+        };
+
+        for (topic, schema) in accepted {
+            let id = topic.id;
+            if let Err(err) = self.attach(topic, &schema) {
+                warn!("failed to attach to matching topic: {:?}", err);
+                self.coord.detach(id);
+            }
+        }
+
+        if ended {
+            warn!("lost connection to the catalog, no longer attaching new topics");
+            self.watch = None;
+        }
+
+        Ok(())
+    }
+
+    /// Holds back the frontier of a lost publisher, and waits for its topic
+    /// to be republished if requested.
+    fn lose(&mut self, topic: TopicId, frontier: Vec<T>) -> Result<(), SubscriptionError> {
+        if let Some(lost) = self.lost.iter_mut().find(|l| l.topic == topic) {
+            lost.frontier.extend(frontier);
+            return Ok(());
+        }
+
+        let topic = self.topics
+            .iter()
+            .find(|t| t.id == topic)
+            .cloned()
+            .expect("lost source of unknown topic");
+
+        // the watch of a pattern subscription attaches the topic anyway
+        let resubscribe = if self.reconnect && self.watch.is_none() {
+            warn!("lost publisher of topic {:?}, waiting for it to reappear", topic.name);
+            let (item, time) = timestamped_schema::<T, D>()?;
+            let schema = TopicSchema::Stream(item, time);
+            Some(Resubscribe::new(&self.coord, &topic, schema))
         } else {
-            return Ok(Async::Ready(None));
+            None
+        };
+
+        self.lost.push(Lost {
+            topic: topic.id,
+            name: topic.name,
+            frontier: frontier,
+            resubscribe: resubscribe,
+        });
+
+        Ok(())
+    }
+
+    /// Attaches lost topics which have been republished.
+    fn poll_lost(&mut self) -> Result<bool, SubscriptionError> {
+        let mut republished = Vec::new();
+        let mut error = None;
+        for lost in self.lost.iter_mut() {
+            let result = match lost.resubscribe {
+                Some(ref mut resubscribe) => resubscribe.poll(&self.coord),
+                None => continue,
+            };
+
+            match result {
+                Ok(Async::NotReady) => (),
+                Ok(Async::Ready(topic)) => {
+                    lost.resubscribe = None;
+                    republished.push(topic);
+                }
+                Err(err) => {
+                    lost.resubscribe = None;
+                    error = Some(err);
+                }
+            }
+        }
+
+        let attached = !republished.is_empty();
+        if attached {
+            let (item, time) = timestamped_schema::<T, D>()?;
+            let schema = TopicSchema::Stream(item, time);
+            for topic in republished {
+                let id = topic.id;
+                if let Err(err) = self.attach(topic, &schema) {
+                    self.coord.detach(id);
+                    return Err(err);
+                }
+            }
+        }
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(attached),
+        }
+    }
+
+    /// Replaces a lost topic by the topic republished under the same name.
+    /// The data published in between is reported as a gap.
+    fn take_over(&mut self, name: &str) {
+        let lost = match self.lost.iter().position(|l| l.name == name) {
+            Some(i) => self.lost.remove(i),
+            None => return,
         };
 
-        // convert frontier and timestamp
-        let time = PubSubTimestamp::from_pubsub(time);
-        let frontier: Vec<_> = frontier.into_iter()
-            .map(PubSubTimestamp::from_pubsub)
-            .collect();
-
-        let mut time_cap = None;
-        let mut new_frontier = vec![];
-        for cap in self.frontier.iter() {
-            // get capability for resulting tuple
-            if time_cap.is_none() && cap.time().less_equal(&time) {
-                time_cap = Some(cap.delayed(&time));
+        warn!("attached republished topic {:?}, data after {:?} might be missing",
+              lost.name,
+              lost.frontier);
+
+        // partitions of the lost topic which are still connected
+        let mut i = 0;
+        while i < self.sources.len() {
+            if self.sources[i].topic == lost.topic {
+                let source = self.sources.remove(i);
+                self.missed += source.sub.missed();
+            } else {
+                i += 1;
             }
+        }
+
+        if self.topics.iter().any(|t| t.id == lost.topic) {
+            self.topics.retain(|t| t.id != lost.topic);
+            self.coord.detach(lost.topic);
+        }
+
+        self.gaps.push(Gap {
+            topic: lost.name,
+            frontier: lost.frontier,
+        });
+    }
+
+    /// Returns a capability for data at `time`, unless the subscription
+    /// has already advanced past it.
+    fn capability(&self, time: &T) -> Option<Capability<T>> {
+        self.frontier
+            .iter()
+            .find(|cap| cap.time().less_equal(time))
+            .map(|cap| cap.delayed(time))
+    }
+
+    /// Downgrades our capabilities to the combined frontier of all sources.
+    fn advance(&mut self) {
+        let mut frontier = Antichain::new();
+        for source in self.sources.iter() {
+            for t in source.frontier.iter() {
+                frontier.insert(t.clone());
+            }
+        }
+        for lost in self.lost.iter() {
+            for t in lost.frontier.iter() {
+                frontier.insert(t.clone());
+            }
+        }
+
+        // keep our capabilities as long as more topics can be attached
+        if frontier.elements().is_empty() && self.watch.is_some() {
+            return;
+        }
 
-            // upgrade capability for new frontier
-            for t in frontier.iter() {
-                if cap.time().less_equal(t) {
-                    new_frontier.push(cap.delayed(t));
+        let mut new_frontier: Vec<Capability<T>> = vec![];
+        for t in frontier.elements() {
+            let caps = match self.frontier.iter().find(|cap| cap.time().less_equal(t)) {
+                Some(cap) => vec![cap.delayed(t)],
+                // frontier of a late topic, we cannot go back in time
+                None => {
+                    self.frontier.iter()
+                        .filter(|cap| t.less_equal(&cap.time()))
+                        .cloned()
+                        .collect()
+                }
+            };
+
+            for cap in caps {
+                if !new_frontier.iter().any(|c| c.time() == cap.time()) {
+                    new_frontier.push(cap);
                 }
             }
         }
 
         self.frontier = new_frontier;
-        let time = time_cap.expect("failed to get capability for tuple");
+    }
+}
+
+impl<T: PubSubTimestamp, D: Data + DeserializeOwned> Stream for TimelySubscription<T, D> {
+    type Item = (Capability<T>, Vec<D>);
+    type Error = SubscriptionError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.watch.is_some() {
+            self.poll_watch()?;
+        }
+
+        let len = self.sources.len();
+        let mut ready = None;
+        let mut lost = None;
+        let mut finished = Vec::new();
+        'sources: for offset in 0..len {
+            let i = (self.next + offset) % len;
+            loop {
+                match self.sources[i].sub.poll()? {
+                    Async::Ready(Some(TimelyMessage::Batch(frontier, time, data))) => {
+                        ready = Some((i, (frontier, time, data)));
+                        break 'sources;
+                    }
+                    Async::Ready(Some(TimelyMessage::Progress(frontier))) => {
+                        // idle publisher, only its frontier has advanced
+                        self.sources[i].frontier = frontier.into_iter()
+                            .map(PubSubTimestamp::from_pubsub)
+                            .collect();
+                    }
+                    Async::Ready(Some(TimelyMessage::Close)) => {
+                        finished.push(i);
+                        break;
+                    }
+                    Async::Ready(None) => {
+                        // connection closed before the stream was complete
+                        lost = Some(i);
+                        break 'sources;
+                    }
+                    Async::NotReady => break,
+                }
+            }
+        }
+
+        // convert frontier and timestamp, get capability for resulting tuple
+        let item = match ready {
+            Some((i, (frontier, time, data))) => {
+                let time = PubSubTimestamp::from_pubsub(time);
+                self.sources[i].frontier = frontier.into_iter()
+                    .map(PubSubTimestamp::from_pubsub)
+                    .collect();
+                self.next = i + 1;
+
+                // relabelling late data would corrupt its timestamps
+                let cap = self.capability(&time);
+                if cap.is_none() {
+                    warn!("dropping {} late records of topic {:?} at {:?}",
+                          data.len(),
+                          self.sources[i].topic,
+                          time);
+                    self.late += data.len() as u64;
+                    task::current().notify();
+                }
+                cap.map(|cap| (cap, data))
+            }
+            None => None,
+        };
+
+        // lost publishers keep holding back the frontier
+        if let Some(i) = lost {
+            let (topic, frontier) = (self.sources[i].topic, self.sources[i].frontier.clone());
+            self.lose(topic, frontier)?;
+            finished.push(i);
+        }
+
+        // publishers which closed their stream no longer hold back the frontier
+        finished.sort();
+        for i in finished.into_iter().rev() {
+            let source = self.sources.remove(i);
+            self.missed += source.sub.missed();
+            if !self.sources.iter().any(|s| s.topic == source.topic) {
+                self.topics.retain(|t| t.id != source.topic);
+                self.coord.detach(source.topic);
+            }
+        }
+
+        // poll again for republished topics and the sources skipped above
+        if self.poll_lost()? || lost.is_some() {
+            task::current().notify();
+        }
 
-        Ok(Async::Ready(Some((time, data))))
+        self.advance();
+
+        let resubscribing = self.lost.iter().any(|l| l.resubscribe.is_some());
+        if lost.is_some() && !self.reconnect {
+            Err(SubscriptionError::PublisherLost)
+        } else if item.is_some() {
+            Ok(Async::Ready(item))
+        } else if self.sources.is_empty() && self.watch.is_none() && !resubscribing {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
     }
 }
 
@@ -130,20 +919,61 @@ impl<T, D> IntoIterator for TimelySubscription<T, D>
 
 impl<T: PubSubTimestamp, D: Data + DeserializeOwned> Drop for TimelySubscription<T, D> {
     fn drop(&mut self) {
-        if let Err(err) = self.coord.unsubscribe(self.topic.id) {
-            warn!("failed to unsubscribe: {:?}", err)
+        for topic in self.topics.iter() {
+            self.coord.detach(topic.id);
         }
     }
 }
 
+/// Matches a topic name against a glob pattern, where `*` matches any
+/// sequence of characters and `?` matches a single character.
+fn glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // position after the last `*` and the name position it is matched up to
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if let Some((bp, bn)) = backtrack {
+            // let the last `*` consume one more character
+            p = bp;
+            n = bn + 1;
+            backtrack = Some((bp, bn + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 #[derive(Debug)]
 pub enum SubscriptionError {
     TopicNotFound,
     TypeIdMismatch,
     AuthenticationFailure,
+    /// The ACL of the topic does not allow us to subscribe
+    PermissionDenied,
+    /// No schema can be derived for the subscribed data type
+    UnsupportedType(SchemaError),
+    /// A publisher went away without closing its stream
+    PublisherLost,
     IoError(io::Error),
 }
 
+impl From<SchemaError> for SubscriptionError {
+    fn from(err: SchemaError) -> Self {
+        SubscriptionError::UnsupportedType(err)
+    }
+}
+
 impl From<SubscribeError> for SubscriptionError {
     fn from(err: SubscribeError) -> Self {
         match err {
@@ -151,6 +981,8 @@ impl From<SubscribeError> for SubscriptionError {
             SubscribeError::AuthenticationFailure => {
                 SubscriptionError::AuthenticationFailure
             }
+            SubscribeError::IncompatibleSchema => SubscriptionError::TypeIdMismatch,
+            SubscribeError::PermissionDenied => SubscriptionError::PermissionDenied,
         }
     }
 }
@@ -184,48 +1016,158 @@ impl<T, E> From<Result<T, E>> for SubscriptionError
     }
 }
 
+/// Returns the schema of the data and the timestamps of a topic.
+fn timestamped_schema<T, D>() -> Result<(TopicType, TopicType), SubscriptionError>
+    where T: PubSubTimestamp,
+          D: Data + DeserializeOwned
+{
+    Ok((D::schema()?, <T::Converted as Schema>::schema()?))
+}
+
+fn collection_schema<D>() -> Result<TopicSchema, SubscriptionError>
+    where D: Data + DeserializeOwned
+{
+    // collections are published as `(item, diff)` pairs
+    let pair = D::schema()?;
+    let diff = i32::schema()?;
+    match (pair.element(0), pair.element(1)) {
+        (Some(item), Some(ref d)) if d.is_compatible(&diff) => Ok(TopicSchema::Collection(item)),
+        _ => Err(SubscriptionError::TypeIdMismatch),
+    }
+}
+
+/// Returns the secret of every partition, `None` for public topics.
+fn partition_secrets(topic: &Topic) -> Vec<Option<u64>> {
+    (0..topic.partitions.len()).map(|p| topic.secrets.get(p).cloned()).collect()
+}
+
+/// Connects to all partitions of a collection topic.
+fn connect_collection<D>(topic: &Topic,
+                         schema: &TopicSchema,
+                         coord: &Coordinator)
+                         -> Result<Vec<Subscriber<D>>, SubscriptionError>
+    where D: Data + DeserializeOwned
+{
+    let projection = topic.schema
+        .projection(schema, topic.compatibility)
+        .ok_or(SubscriptionError::TypeIdMismatch)?;
+
+    let decoder = if projection.is_identity() {
+        None
+    } else {
+        let pair = Projection::Tuple(vec![projection, Projection::Identity]);
+        Some(Decoder::new(pair))
+    };
+
+    // collections are not divided among workers, read all partitions
+    let mut subs = Vec::with_capacity(topic.partitions.len());
+    for (addr, secret) in topic.partitions.iter().zip(partition_secrets(topic)) {
+        let mut sub = Subscriber::<D>::connect_collection(addr, &coord.network, secret)?;
+        if let Some(ref decoder) = decoder {
+            sub.decode_with(decoder.clone());
+        }
+        subs.push(sub);
+    }
+
+    Ok(subs)
+}
+
 impl Coordinator {
-    fn unsubscribe(&self, topic: TopicId) -> Result<(), SubscriptionError> {
-        self.tx
-            .request(&Unsubscribe {
-                topic: topic,
-                token: self.token,
-            })
-            .map_err(SubscriptionError::from)
-            .wait()
+    /// Releases the subscription of a topic without waiting for the
+    /// coordinator to respond, dropping a subscription thus never blocks.
+    fn detach(&self, topic: TopicId) {
+        let _ = self.tx.request(&Unsubscribe {
+            topic: topic,
+            token: self.token,
+        });
     }
 
     fn timely<T, D>(&self,
                     name: String,
                     root: Capability<T>,
-                    blocking: bool)
-                    -> Result<TimelySubscription<T, D>, SubscriptionError>
+                    blocking: bool,
+                    replay: Option<T>)
+                    -> Pending<TimelySubscription<T, D>, SubscriptionError>
         where T: PubSubTimestamp,
               D: Data + DeserializeOwned
     {
-        let name = name.to_string();
         let coord = self.clone();
-        self.tx
+        let schema = match timestamped_schema::<T, D>() {
+            Ok((item, time)) => TopicSchema::Stream(item, time),
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let subscription = self.tx
             .request(&Subscribe {
                 name: name,
                 token: self.token,
                 blocking: blocking,
+                schema: schema.clone(),
             })
             .map_err(SubscriptionError::from)
             .and_then(move |topic| {
-                if !topic.schema.is_stream() {
-                    return Err(SubscriptionError::TypeIdMismatch);
-                }
+                let mut subscription = TimelySubscription::new(coord, root, None);
+                subscription.replay = replay;
+                subscription.attach(topic, &schema)?;
+                Ok(subscription)
+            });
 
-                let sub = TimelySubscriber::<T::Converted, D>::connect(&topic, &coord.network)?;
-                Ok(TimelySubscription {
-                    sub: sub,
-                    topic: topic,
-                    coord: coord,
-                    frontier: vec![root],
-                })
+        Box::new(subscription)
+    }
+
+    /// Subscribes to all stream topics whose name matches the glob
+    /// `pattern`, e.g. `topology.*` for a topic published with
+    /// `Partition::PerWorker`. The topics are merged into a single
+    /// subscription, whose frontier is the combined frontier of all matching
+    /// topics. Topics published later on are attached as they appear, data
+    /// they send for times the subscription has already passed is dropped
+    /// and counted, see `TimelySubscription::late`.
+    pub fn subscribe_pattern<T, D>(&self,
+                                   pattern: &str,
+                                   root: Capability<T>)
+                                   -> Result<TimelySubscription<T, D>, SubscriptionError>
+        where T: PubSubTimestamp,
+              D: Data + DeserializeOwned
+    {
+        self.subscribe_pattern_async(pattern, root).wait()
+    }
+
+    /// Non-blocking version of `subscribe_pattern`.
+    pub fn subscribe_pattern_async<T, D>(&self,
+                                         pattern: &str,
+                                         root: Capability<T>)
+                                         -> Pending<TimelySubscription<T, D>, SubscriptionError>
+        where T: PubSubTimestamp,
+              D: Data + DeserializeOwned
+    {
+        let coord = self.clone();
+        let pattern = String::from(pattern);
+        let schema = match timestamped_schema::<T, D>() {
+            Ok((item, time)) => TopicSchema::Stream(item, time),
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        // the catalog announces all existing topics on connect
+        let subscription = self.tx
+            .request(&Lookup { name: String::from("$topics") })
+            .map_err(|err| match err {
+                Ok(()) => SubscriptionError::TopicNotFound,
+                Err(err) => SubscriptionError::IoError(err),
             })
-            .wait()
+            .and_then(move |catalog| {
+                let addr = &catalog.partitions[0];
+                let topics = Subscriber::connect_collection(addr, &coord.network, None)?;
+                let watch = Watch {
+                    pattern: pattern,
+                    schema: schema,
+                    topics: topics,
+                    seen: HashSet::new(),
+                    pending: Vec::new(),
+                };
+
+                Ok(TimelySubscription::new(coord, root, Some(watch)))
+            });
+
+        Box::new(subscription)
     }
 
     pub fn subscribe<T, D>(&self,
@@ -235,7 +1177,34 @@ impl Coordinator {
         where T: PubSubTimestamp,
               D: Data + DeserializeOwned
     {
-        self.timely(name.to_string(), root, true)
+        self.timely(name.to_string(), root, true, None).wait()
+    }
+
+    /// Subscribes to a stream topic without blocking the worker. The
+    /// returned future resolves once the topic has been published, it can
+    /// be polled from within an operator or be waited on.
+    pub fn subscribe_async<T, D>(&self,
+                                 name: &str,
+                                 root: Capability<T>)
+                                 -> Pending<TimelySubscription<T, D>, SubscriptionError>
+        where T: PubSubTimestamp,
+              D: Data + DeserializeOwned
+    {
+        self.timely(name.to_string(), root, true, None)
+    }
+
+    /// Subscribes to a stream topic, asking its publisher to first replay
+    /// the retained batches with a timestamp of at least `from`. Publishers
+    /// without retention only send live data.
+    pub fn subscribe_from<T, D>(&self,
+                                name: &str,
+                                root: Capability<T>,
+                                from: T)
+                                -> Result<TimelySubscription<T, D>, SubscriptionError>
+        where T: PubSubTimestamp,
+              D: Data + DeserializeOwned
+    {
+        self.timely(name.to_string(), root, true, Some(from)).wait()
     }
 
     pub fn subscribe_nonblocking<T, D>
@@ -246,43 +1215,151 @@ impl Coordinator {
         where T: PubSubTimestamp,
               D: Data + DeserializeOwned
     {
-        self.timely(name.to_string(), root, false)
+        self.timely(name.to_string(), root, false, None).wait()
     }
 
     fn collection<D>(&self,
                      name: String,
                      blocking: bool)
-                     -> Result<Subscription<D>, SubscriptionError>
+                     -> Pending<Subscription<D>, SubscriptionError>
         where D: Data + DeserializeOwned
     {
-
         let coord = self.clone();
-        self.tx
+        let schema = match collection_schema::<D>() {
+            Ok(schema) => schema,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let subscription = self.tx
             .request(&Subscribe {
                 name: name,
                 token: self.token,
                 blocking: blocking,
+                schema: schema.clone(),
             })
             .map_err(SubscriptionError::from)
             .and_then(move |topic| {
-                if !topic.schema.is_collection() {
-                    return Err(SubscriptionError::TypeIdMismatch);
-                }
-
-                let sub = Subscriber::<D>::connect(&topic, &coord.network)?;
+                let subs = connect_collection(&topic, &schema, &coord)?;
                 Ok(Subscription {
-                    sub: sub,
+                    subs: subs,
                     topic: topic,
+                    schema: schema,
                     coord: coord,
+                    next: 0,
+                    reconnect: None,
                 })
+            });
+
+        Box::new(subscription)
+    }
+
+    pub(crate) fn timestamped<T, D, R>
+        (&self,
+         name: String,
+         blocking: bool)
+         -> Pending<TimestampedSubscription<T, D, R>, SubscriptionError>
+        where T: PubSubTimestamp,
+              D: Data + DeserializeOwned,
+              R: Data + DeserializeOwned
+    {
+        let coord = self.clone();
+        let schema = match timestamped_schema::<T, D>() {
+            Ok((item, time)) => {
+                match R::schema() {
+                    Ok(diff) => TopicSchema::TimestampedCollection(item, time, diff),
+                    Err(err) => return Box::new(future::err(err.into())),
+                }
+            }
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let subscription = self.tx
+            .request(&Subscribe {
+                name: name,
+                token: self.token,
+                blocking: blocking,
+                schema: schema.clone(),
             })
-            .wait()
+            .map_err(SubscriptionError::from)
+            .and_then(move |topic| {
+                let projection = topic.schema
+                    .projection(&schema, topic.compatibility)
+                    .ok_or(SubscriptionError::TypeIdMismatch)?;
+
+                let decoder = if projection.is_identity() {
+                    None
+                } else {
+                    let update = Projection::Tuple(vec![projection,
+                                                        Projection::Identity,
+                                                        Projection::Identity]);
+                    Some(Decoder::new(update))
+                };
+
+                let mut subs = Vec::with_capacity(topic.partitions.len());
+                for (addr, secret) in topic.partitions.iter().zip(partition_secrets(&topic)) {
+                    let mut sub = TimestampedSubscriber::connect(addr, &coord.network, secret)?;
+                    if let Some(ref decoder) = decoder {
+                        sub.decode_with(decoder.clone());
+                    }
+                    // nothing is known about the partition until its snapshot arrives
+                    subs.push((sub, vec![Default::default()]));
+                }
+                Ok(TimestampedSubscription {
+                    subs: subs,
+                    topic: topic,
+                    coord: coord,
+                    next: 0,
+                })
+            });
+
+        Box::new(subscription)
+    }
+
+    /// Subscribes to a collection published with
+    /// `publish_timestamped_collection`. The first batch of every partition
+    /// is a snapshot of the collection up to the published frontier.
+    pub fn subscribe_timestamped_collection<T, D>
+        (&self,
+         name: &str)
+         -> Result<TimestampedSubscription<T, D>, SubscriptionError>
+        where T: PubSubTimestamp,
+              D: Data + DeserializeOwned
+    {
+        self.timestamped(name.to_string(), true).wait()
+    }
+
+    /// Non-blocking version of `subscribe_timestamped_collection`.
+    pub fn subscribe_timestamped_collection_async<T, D>
+        (&self,
+         name: &str)
+         -> Pending<TimestampedSubscription<T, D>, SubscriptionError>
+        where T: PubSubTimestamp,
+              D: Data + DeserializeOwned
+    {
+        self.timestamped(name.to_string(), true)
+    }
+
+    pub fn subscribe_timestamped_collection_nonblocking<T, D>
+        (&self,
+         name: &str)
+         -> Result<TimestampedSubscription<T, D>, SubscriptionError>
+        where T: PubSubTimestamp,
+              D: Data + DeserializeOwned
+    {
+        self.timestamped(name.to_string(), false).wait()
     }
 
     pub fn subscribe_collection<D>(&self,
                                    name: &str)
                                    -> Result<Subscription<D>, SubscriptionError>
         where D: Data + DeserializeOwned
+    {
+        self.collection(name.to_string(), true).wait()
+    }
+
+    /// Non-blocking version of `subscribe_collection`.
+    pub fn subscribe_collection_async<D>(&self,
+                                         name: &str)
+                                         -> Pending<Subscription<D>, SubscriptionError>
+        where D: Data + DeserializeOwned
     {
         self.collection(name.to_string(), true)
     }
@@ -293,6 +1370,6 @@ impl Coordinator {
          -> Result<Subscription<D>, SubscriptionError>
         where D: Data + DeserializeOwned
     {
-        self.collection(name.to_string(), false)
+        self.collection(name.to_string(), false).wait()
     }
 }
@@ -9,6 +9,7 @@
 use std::io::{Error, Result, ErrorKind};
 use std::net::ToSocketAddrs;
 use std::iter::repeat;
+use std::env;
 
 use futures::Future;
 use futures::stream::Stream;
@@ -47,12 +48,27 @@ impl Submitter {
         let submission = Submission {
             query: query,
             name: name.into(),
+            // not authenticated, see `model::Acl`
+            user: env::var("USER").ok(),
             placement: placement,
         };
 
         self.tx.request(&submission)
     }
 
+    pub fn terminate(&self, id: QueryId) -> Response<TerminateQuery> {
+        self.tx.request(&TerminateQuery { query: id })
+    }
+
+    /// Fetches the output of all processes of a query, starting at the
+    /// given per-process offsets.
+    pub fn logs(&self, id: QueryId, offsets: Vec<u64>) -> Response<FetchLogs> {
+        self.tx.request(&FetchLogs {
+            query: id,
+            offsets: offsets,
+        })
+    }
+
     fn lookup(&self, name: &str) -> Result<Topic> {
         self.tx
             .request(&Lookup { name: name.into() })
@@ -67,20 +83,31 @@ impl Submitter {
         where D: DeserializeOwned + Clone
     {
         let topic = self.lookup(name)?;
-        assert_eq!(topic.schema, TopicSchema::Collection(TopicType::of::<D>()));
-
-        let sub = CollectionSubscriber::<D>::connect(&topic, &self.network)?;
+        let schema = D::schema().map_err(|err| Error::new(ErrorKind::Other, err))?;
+        if !topic.schema.is_compatible(&TopicSchema::Collection(schema)) {
+            return Err(Error::new(ErrorKind::InvalidData, "topic schema mismatch"));
+        }
 
-        match sub.into_future().wait() {
-            Ok((Some(vec), _)) => {
-                Ok(vec.into_iter()
-                    .flat_map(|(item, n)| repeat(item).take(n as usize))
-                    .collect())
-            }
-            Ok((None, _)) => {
-                Err(Error::new(ErrorKind::Other, "subscriber stopped unexpectedly"))
+        let addr = &topic.partitions[0];
+        let mut sub = CollectionSubscriber::<D>::connect_collection(addr, &self.network, None)?;
+
+        // the snapshot of the collection may be split into several batches
+        let mut collection = Vec::new();
+        loop {
+            match sub.into_future().wait() {
+                Ok((Some(vec), rest)) => {
+                    collection.extend(vec.into_iter()
+                        .flat_map(|(item, n)| repeat(item).take(n as usize)));
+                    if !rest.in_snapshot() {
+                        return Ok(collection);
+                    }
+                    sub = rest;
+                }
+                Ok((None, _)) => {
+                    return Err(Error::new(ErrorKind::Other, "subscriber stopped unexpectedly"))
+                }
+                Err((err, _)) => return Err(err),
             }
-            Err((err, _)) => Err(err),
         }
     }
 